@@ -0,0 +1,33 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use rustnes::cpu::CPU;
+use rustnes::mock_bus::MockBus;
+
+// NTSC CPU cycles in one frame
+const CYCLES_PER_FRAME: u64 = 29780;
+
+// a loop mixing loads, stores, arithmetic and branches, so dispatch dominates
+const PROGRAM: [u8; 16] = [
+    0xa2, 0x00, // LDX #$00
+    0xa9, 0x01, // LDA #$01
+    0x69, 0x02, // ADC #$02
+    0x9d, 0x00, 0x02, // STA $0200,X
+    0xe8, // INX
+    0xd0, 0xf6, // BNE $0002
+    0x4c, 0x00, 0x00, // JMP $0000
+    0xea, // NOP padding
+];
+
+fn run_frame(c: &mut Criterion) {
+    let mut ram = [0; 65536];
+    ram[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+    let mut cpu = CPU::new(MockBus::new(ram));
+    cpu.program_counter = 0x0000;
+
+    c.bench_function("cpu frame", |b| {
+        b.iter(|| black_box(cpu.run_for_cycles(CYCLES_PER_FRAME)))
+    });
+}
+
+criterion_group!(benches, run_frame);
+criterion_main!(benches);
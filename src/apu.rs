@@ -0,0 +1,1401 @@
+use serde::{Deserialize, Serialize};
+
+// APU frame counter is clocked about every quarter frame (7457 CPU cycles).
+// https://www.nesdev.org/wiki/APU_Frame_Counter
+const FRAME_COUNTER_PERIOD: usize = 7457;
+const CPU_CLOCK_RATE: f64 = 1_789_773.0;
+// the default output rate
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// the 8 step waveforms selected by the top 2 bits of $4000
+// https://www.nesdev.org/wiki/APU_Pulse
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DutyCycle {
+    Eighth,
+    Quarter,
+    Half,
+    QuarterNegated,
+}
+
+impl DutyCycle {
+    pub fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => DutyCycle::Eighth,
+            1 => DutyCycle::Quarter,
+            2 => DutyCycle::Half,
+            _ => DutyCycle::QuarterNegated,
+        }
+    }
+
+    pub fn waveform(&self) -> [u8; 8] {
+        match self {
+            DutyCycle::Eighth => [0, 1, 0, 0, 0, 0, 0, 0],
+            DutyCycle::Quarter => [0, 1, 1, 0, 0, 0, 0, 0],
+            DutyCycle::Half => [0, 1, 1, 1, 1, 0, 0, 0],
+            DutyCycle::QuarterNegated => [1, 0, 0, 1, 1, 1, 1, 1],
+        }
+    }
+}
+
+// length counter values indexed by the top 5 bits of the 4th channel register
+// https://www.nesdev.org/wiki/APU_Length_Counter
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// volume control shared by the pulse and noise channels, clocked every quarter frame
+// https://www.nesdev.org/wiki/APU_Envelope
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Envelope {
+    pub constant_volume: bool,
+    // the constant volume, or the divider period
+    pub volume: u8,
+    start: bool,
+    divider: u8,
+    pub decay: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the low 5 bits of $4000/$4004/$400C, ---C VVVV
+    pub fn write(&mut self, data: u8) {
+        self.constant_volume = data & 0b0001_0000 != 0;
+        self.volume = data & 0b0000_1111;
+    }
+
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    pub fn tick(&mut self, looping: bool) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+// how the sweep unit negates the period change. Pulse 1 adds the ones' complement (-c - 1),
+// pulse 2 the two's complement (-c), so the same sweep bends them to slightly different pitches.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum NegateMode {
+    OnesComplement,
+    TwosComplement,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PulseChannel {
+    pub duty: DutyCycle,
+    duty_step: usize,
+    // also the envelope loop flag
+    pub length_counter_halt: bool,
+    pub envelope: Envelope,
+    pub sweep_enabled: bool,
+    pub sweep_period: u8,
+    pub sweep_negate: bool,
+    pub negate_mode: NegateMode,
+    pub sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+    // the 11 bit period, the timer reloads from it
+    pub timer: u16,
+    timer_counter: u16,
+    pub length_counter: u8,
+    // set through $4015, the length counter stays 0 while disabled
+    pub enabled: bool,
+}
+
+impl PulseChannel {
+    pub fn new(negate_mode: NegateMode) -> Self {
+        PulseChannel {
+            duty: DutyCycle::Eighth,
+            duty_step: 0,
+            length_counter_halt: false,
+            envelope: Envelope::new(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            negate_mode,
+            sweep_shift: 0,
+            sweep_reload: false,
+            sweep_divider: 0,
+            timer: 0,
+            timer_counter: 0,
+            length_counter: 0,
+            enabled: false,
+        }
+    }
+
+    // reg is the offset inside the channel, 0..=3
+    pub fn write(&mut self, reg: u8, data: u8) {
+        match reg {
+            0 => self.write_to_duty_env(data),
+            1 => self.write_to_sweep(data),
+            2 => self.write_to_timer_lo(data),
+            3 => self.write_to_timer_hi(data),
+            _ => panic!("pulse channel has no register {}", reg),
+        }
+    }
+
+    // DDLC VVVV
+    pub fn write_to_duty_env(&mut self, data: u8) {
+        self.duty = DutyCycle::from_bits(data >> 6);
+        self.length_counter_halt = data & 0b0010_0000 != 0;
+        self.envelope.write(data);
+    }
+
+    // EPPP NSSS
+    pub fn write_to_sweep(&mut self, data: u8) {
+        self.sweep_enabled = data & 0b1000_0000 != 0;
+        self.sweep_period = (data >> 4) & 0b111;
+        self.sweep_negate = data & 0b0000_1000 != 0;
+        self.sweep_shift = data & 0b111;
+        self.sweep_reload = true;
+    }
+
+    pub fn write_to_timer_lo(&mut self, data: u8) {
+        self.timer = (self.timer & 0xff00) | data as u16;
+    }
+
+    // LLLL LTTT, also restarts the waveform and the envelope
+    pub fn write_to_timer_hi(&mut self, data: u8) {
+        self.timer = (self.timer & 0x00ff) | ((data as u16 & 0b111) << 8);
+        if self.enabled {
+            self.length_counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+        self.duty_step = 0;
+        self.envelope.restart();
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    // clocked every other CPU cycle
+    pub fn tick_timer(&mut self) {
+        if self.timer_counter == 0 {
+            self.timer_counter = self.timer;
+            self.duty_step = (self.duty_step + 1) % 8;
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    // clocked every quarter frame
+    pub fn tick_envelope(&mut self) {
+        self.envelope.tick(self.length_counter_halt);
+    }
+
+    // clocked every half frame
+    pub fn tick_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.is_muted()
+        {
+            self.timer = self.sweep_target_period();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    // clocked every half frame
+    pub fn tick_length_counter(&mut self) {
+        if !self.length_counter_halt && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    fn sweep_target_period(&self) -> u16 {
+        let change = self.timer >> self.sweep_shift;
+        if self.sweep_negate {
+            let change = match self.negate_mode {
+                NegateMode::OnesComplement => change + 1,
+                NegateMode::TwosComplement => change,
+            };
+            self.timer.saturating_sub(change)
+        } else {
+            self.timer + change
+        }
+    }
+
+    // the sweep unit silences periods below 8 and targets above $7FF even while disabled
+    fn is_muted(&self) -> bool {
+        self.timer < 8 || self.sweep_target_period() > 0x7ff
+    }
+
+    // the 4 bit DAC level, 0..=15
+    pub fn output(&self) -> f32 {
+        if self.length_counter == 0 || self.is_muted() || self.duty.waveform()[self.duty_step] == 0
+        {
+            return 0.0;
+        }
+        self.envelope.output() as f32
+    }
+}
+
+// 15, 14, .., 0, 0, 1, .., 15
+// https://www.nesdev.org/wiki/APU_Triangle
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct TriangleChannel {
+    // also the length counter halt flag
+    pub control: bool,
+    pub linear_counter: u8,
+    pub linear_counter_reload: u8,
+    linear_counter_reload_flag: bool,
+    pub timer: u16,
+    pub timer_period: u16,
+    pub length_counter: u8,
+    pub step: u8,
+    pub enabled: bool,
+}
+
+impl TriangleChannel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // reg is the offset inside the channel, 0..=3, $4009 is unused
+    pub fn write(&mut self, reg: u8, data: u8) {
+        match reg {
+            0 => self.write_to_linear_counter(data),
+            1 => {}
+            2 => self.write_to_timer_lo(data),
+            3 => self.write_to_timer_hi(data),
+            _ => panic!("triangle channel has no register {}", reg),
+        }
+    }
+
+    // CRRR RRRR
+    pub fn write_to_linear_counter(&mut self, data: u8) {
+        self.control = data & 0b1000_0000 != 0;
+        self.linear_counter_reload = data & 0b0111_1111;
+    }
+
+    pub fn write_to_timer_lo(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0xff00) | data as u16;
+    }
+
+    // LLLL LTTT
+    pub fn write_to_timer_hi(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0x00ff) | ((data as u16 & 0b111) << 8);
+        if self.enabled {
+            self.length_counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+        self.linear_counter_reload_flag = true;
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    // clocked every CPU cycle, the sequence only moves while both counters are nonzero
+    pub fn tick_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.linear_counter > 0 && self.length_counter > 0 {
+                self.step = (self.step + 1) % TRIANGLE_SEQUENCE.len() as u8;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // clocked every quarter frame
+    pub fn tick_linear_counter(&mut self) {
+        if self.linear_counter_reload_flag {
+            self.linear_counter = self.linear_counter_reload;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_counter_reload_flag = false;
+        }
+    }
+
+    // clocked every half frame
+    pub fn tick_length_counter(&mut self) {
+        if !self.control && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    // a silenced triangle holds its last step instead of dropping to 0, 0.0..=1.0
+    pub fn output(&self) -> f32 {
+        TRIANGLE_SEQUENCE[self.step as usize] as f32 / 15.0
+    }
+}
+
+// NTSC timer periods in CPU cycles, indexed by the low 4 bits of $400E
+// https://www.nesdev.org/wiki/APU_Noise
+const NOISE_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NoiseChannel {
+    // also the envelope loop flag
+    pub length_counter_halt: bool,
+    pub envelope: Envelope,
+    // 15 bit shift register, never 0
+    pub lfsr: u16,
+    // feedback from bit 6 instead of bit 1, a 93 step loop instead of 32767
+    pub mode: bool,
+    timer: u16,
+    pub timer_period: u16,
+    pub length_counter: u8,
+    pub enabled: bool,
+}
+
+impl Default for NoiseChannel {
+    fn default() -> Self {
+        NoiseChannel {
+            length_counter_halt: false,
+            envelope: Envelope::new(),
+            lfsr: 1,
+            mode: false,
+            timer: 0,
+            timer_period: NOISE_PERIOD_TABLE[0],
+            length_counter: 0,
+            enabled: false,
+        }
+    }
+}
+
+impl NoiseChannel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // reg is the offset inside the channel, 0..=3, $400D is unused
+    pub fn write(&mut self, reg: u8, data: u8) {
+        match reg {
+            // --LC VVVV
+            0 => {
+                self.length_counter_halt = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            1 => {}
+            // M--- PPPP
+            2 => {
+                self.mode = data & 0b1000_0000 != 0;
+                self.timer_period = NOISE_PERIOD_TABLE[(data & 0b1111) as usize];
+            }
+            // LLLL L---
+            3 => {
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.envelope.restart();
+            }
+            _ => panic!("noise channel has no register {}", reg),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    // clocked every CPU cycle
+    pub fn tick_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_lfsr();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_lfsr(&mut self) {
+        let tap = if self.mode { 6 } else { 1 };
+        let feedback = (self.lfsr ^ (self.lfsr >> tap)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+    }
+
+    // clocked every quarter frame
+    pub fn tick_envelope(&mut self) {
+        self.envelope.tick(self.length_counter_halt);
+    }
+
+    // clocked every half frame
+    pub fn tick_length_counter(&mut self) {
+        if !self.length_counter_halt && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    // the 4 bit DAC level, 0..=15
+    pub fn output(&self) -> f32 {
+        if self.length_counter == 0 || self.lfsr & 1 != 0 {
+            return 0.0;
+        }
+        self.envelope.output() as f32
+    }
+}
+
+// NTSC output rates in CPU cycles, indexed by the low 4 bits of $4010
+// https://www.nesdev.org/wiki/APU_DMC
+const DMC_RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DmcChannel {
+    pub irq_enabled: bool,
+    pub loop_flag: bool,
+    // timer period in CPU cycles
+    pub rate: u16,
+    timer: u16,
+    // the 7 bit DAC level, 0..=127
+    pub output_level: u8,
+    pub sample_addr: u16,
+    pub sample_length: u16,
+    pub current_addr: u16,
+    pub bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    pub irq_flag: bool,
+}
+
+impl Default for DmcChannel {
+    fn default() -> Self {
+        DmcChannel {
+            irq_enabled: false,
+            loop_flag: false,
+            rate: DMC_RATE_TABLE[0],
+            timer: 0,
+            output_level: 0,
+            sample_addr: 0xc000,
+            sample_length: 1,
+            current_addr: 0xc000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq_flag: false,
+        }
+    }
+}
+
+impl DmcChannel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // reg is the offset inside the channel, 0..=3
+    pub fn write(&mut self, reg: u8, data: u8) {
+        match reg {
+            // IL-- RRRR
+            0 => {
+                self.irq_enabled = data & 0b1000_0000 != 0;
+                self.loop_flag = data & 0b0100_0000 != 0;
+                self.rate = DMC_RATE_TABLE[(data & 0b1111) as usize];
+                if !self.irq_enabled {
+                    self.irq_flag = false;
+                }
+            }
+            // -DDD DDDD
+            1 => self.output_level = data & 0b0111_1111,
+            // the sample starts at $C000 + A * 64
+            2 => self.sample_addr = 0xc000 + data as u16 * 64,
+            // and is L * 16 + 1 bytes long
+            3 => self.sample_length = data as u16 * 16 + 1,
+            _ => panic!("dmc channel has no register {}", reg),
+        }
+    }
+
+    // $4015 bit 4 restarts a finished sample or stops the current one, either way clearing the IRQ
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // the memory reader fetches sample bytes, CPU addresses $8000-$FFFF
+    fn fill_sample_buffer<R: FnMut(u16) -> u8>(&mut self, memory_reader: &mut R) {
+        if self.sample_buffer.is_some() || self.bytes_remaining == 0 {
+            return;
+        }
+        self.sample_buffer = Some(memory_reader(self.current_addr));
+        // the address wraps from $FFFF around to $8000
+        self.current_addr = self.current_addr.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    // clocked every CPU cycle
+    pub fn tick_timer<R: FnMut(u16) -> u8>(&mut self, memory_reader: &mut R) {
+        self.fill_sample_buffer(memory_reader);
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate - 1;
+
+        // each bit moves the level up or down by 2, staying inside 0..=127
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_register = sample;
+                }
+                None => self.silence = true,
+            }
+            self.fill_sample_buffer(memory_reader);
+        }
+    }
+
+    pub fn output(&self) -> f32 {
+        self.output_level as f32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FrameCounterMode {
+    FourStep,
+    FiveStep,
+}
+
+// which units a frame sequencer step clocks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameClock {
+    // envelopes and the triangle linear counter
+    pub quarter_frame: bool,
+    // length counters and sweeps
+    pub half_frame: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FrameCounter {
+    pub mode: FrameCounterMode,
+    pub inhibit_irq: bool,
+    pub step: u8,
+    cycle: usize,
+    pub irq_flag: bool,
+}
+
+impl Default for FrameCounter {
+    fn default() -> Self {
+        FrameCounter {
+            mode: FrameCounterMode::FourStep,
+            inhibit_irq: false,
+            step: 0,
+            cycle: 0,
+            irq_flag: false,
+        }
+    }
+}
+
+impl FrameCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // MI-- ----, also restarts the sequence
+    pub fn write(&mut self, data: u8) {
+        self.mode = if data & 0b1000_0000 != 0 {
+            FrameCounterMode::FiveStep
+        } else {
+            FrameCounterMode::FourStep
+        };
+        self.inhibit_irq = data & 0b0100_0000 != 0;
+        if self.inhibit_irq {
+            self.irq_flag = false;
+        }
+        self.cycle = 0;
+        self.step = 0;
+    }
+
+    // returns how many sequencer steps the cycles completed
+    pub fn tick(&mut self, cycles: usize) -> usize {
+        self.cycle += cycles;
+        let steps = self.cycle / FRAME_COUNTER_PERIOD;
+        self.cycle %= FRAME_COUNTER_PERIOD;
+        steps
+    }
+
+    pub fn clock_step(&mut self) -> FrameClock {
+        let step_count = match self.mode {
+            FrameCounterMode::FourStep => 4,
+            FrameCounterMode::FiveStep => 5,
+        };
+        let last_step = self.step == step_count - 1;
+        let clock = FrameClock {
+            // the 5 step sequence clocks nothing on its 4th step
+            quarter_frame: !(self.mode == FrameCounterMode::FiveStep && self.step == 3),
+            half_frame: self.step == 1 || last_step,
+        };
+        if last_step && self.mode == FrameCounterMode::FourStep && !self.inhibit_irq {
+            self.irq_flag = true;
+        }
+        self.step = (self.step + 1) % step_count;
+        clock
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Apu {
+    pub pulse1: PulseChannel,
+    pub pulse2: PulseChannel,
+    pub triangle: TriangleChannel,
+    pub noise: NoiseChannel,
+    pub dmc: DmcChannel,
+    pub frame_counter: FrameCounter,
+    // handed to the audio callback every frame, not part of the saved state
+    #[serde(skip)]
+    pub samples: Vec<f32>,
+    // CPU cycles since the last sample, one is mixed every cycles_per_sample
+    sample_clock: f64,
+    cycles_per_sample: f64,
+    // the pulse timers run at half the CPU clock
+    odd_cycle: bool,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu {
+            pulse1: PulseChannel::new(NegateMode::OnesComplement),
+            pulse2: PulseChannel::new(NegateMode::TwosComplement),
+            triangle: TriangleChannel::new(),
+            noise: NoiseChannel::new(),
+            dmc: DmcChannel::new(),
+            frame_counter: FrameCounter::new(),
+            samples: Vec::new(),
+            sample_clock: 0.0,
+            cycles_per_sample: CPU_CLOCK_RATE / SAMPLE_RATE as f64,
+            odd_cycle: false,
+        }
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.cycles_per_sample = CPU_CLOCK_RATE / sample_rate as f64;
+    }
+
+    // reg is the offset from $4000, 0x00..=0x17
+    pub fn write_register(&mut self, reg: u8, data: u8) {
+        match reg {
+            0x00..=0x03 => self.pulse1.write(reg, data),
+            0x04..=0x07 => self.pulse2.write(reg - 0x04, data),
+            0x08..=0x0b => self.triangle.write(reg - 0x08, data),
+            0x0c..=0x0f => self.noise.write(reg - 0x0c, data),
+            0x10..=0x13 => self.dmc.write(reg - 0x10, data),
+            0x15 => {
+                self.pulse1.set_enabled(data & 0b0000_0001 != 0);
+                self.pulse2.set_enabled(data & 0b0000_0010 != 0);
+                self.triangle.set_enabled(data & 0b0000_0100 != 0);
+                self.noise.set_enabled(data & 0b0000_1000 != 0);
+                self.dmc.set_enabled(data & 0b0001_0000 != 0);
+            }
+            0x17 => {
+                self.frame_counter.write(data);
+                // the 5 step mode clocks every unit right away
+                if self.frame_counter.mode == FrameCounterMode::FiveStep {
+                    self.clock_frame(FrameClock {
+                        quarter_frame: true,
+                        half_frame: true,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn read_register(&mut self, reg: u8) -> u8 {
+        match reg {
+            // only $4015 (status) is readable
+            0x15 => self.read_status(),
+            _ => 0,
+        }
+    }
+
+    // IF-D NT21, reading clears the frame IRQ
+    fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        for (bit, active) in [
+            self.pulse1.length_counter > 0,
+            self.pulse2.length_counter > 0,
+            self.triangle.length_counter > 0,
+            self.noise.length_counter > 0,
+            self.dmc.bytes_remaining > 0,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            status |= (active as u8) << bit;
+        }
+        status |= (self.frame_counter.irq_flag as u8) << 6;
+        status |= (self.dmc.irq_flag as u8) << 7;
+        self.frame_counter.irq_flag = false;
+        status
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        self.tick_with_memory(cycles, |_| 0);
+    }
+
+    // the DMC reads its samples through memory_reader
+    pub fn tick_with_memory<R: FnMut(u16) -> u8>(&mut self, cycles: usize, mut memory_reader: R) {
+        for _ in 0..cycles {
+            self.dmc.tick_timer(&mut memory_reader);
+            self.triangle.tick_timer();
+            self.noise.tick_timer();
+            if self.odd_cycle {
+                self.pulse1.tick_timer();
+                self.pulse2.tick_timer();
+            }
+            self.odd_cycle = !self.odd_cycle;
+        }
+
+        for _ in 0..self.frame_counter.tick(cycles) {
+            let clock = self.frame_counter.clock_step();
+            self.clock_frame(clock);
+        }
+
+        self.sample_clock += cycles as f64;
+        while self.sample_clock >= self.cycles_per_sample {
+            self.sample_clock -= self.cycles_per_sample;
+            let sample = self.mix_output();
+            self.samples.push(sample);
+        }
+    }
+
+    // https://www.nesdev.org/wiki/APU_Mixer
+    pub fn mix_output(&self) -> f32 {
+        let pulse = self.pulse1.output() + self.pulse2.output();
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+        let tnd = self.triangle.output() * 15.0 / 8227.0
+            + self.noise.output() / 12241.0
+            + self.dmc.output() / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+
+    fn clock_frame(&mut self, clock: FrameClock) {
+        if clock.quarter_frame {
+            self.pulse1.tick_envelope();
+            self.pulse2.tick_envelope();
+            self.triangle.tick_linear_counter();
+            self.noise.tick_envelope();
+        }
+        if clock.half_frame {
+            self.pulse1.tick_sweep();
+            self.pulse1.tick_length_counter();
+            self.pulse2.tick_sweep();
+            self.pulse2.tick_length_counter();
+            self.triangle.tick_length_counter();
+            self.noise.tick_length_counter();
+        }
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.frame_counter.irq_flag || self.dmc.irq_flag
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_pulse1_volume() {
+        let mut apu = Apu::new();
+        apu.write_register(0x00, 0x0f);
+
+        assert_eq!(apu.pulse1.envelope.volume, 0x0f);
+        assert!(!apu.pulse1.envelope.constant_volume);
+    }
+
+    #[test]
+    fn test_write_pulse1_timer() {
+        let mut apu = Apu::new();
+        apu.write_register(0x15, 0b0000_0001);
+        apu.write_register(0x02, 0xab);
+        apu.write_register(0x03, 0b1111_1101);
+
+        assert_eq!(apu.pulse1.timer, 0x05ab);
+        assert_eq!(apu.pulse1.length_counter, 30);
+    }
+
+    #[test]
+    fn test_write_pulse1_duty_env() {
+        let mut pulse = PulseChannel::new(NegateMode::OnesComplement);
+        pulse.write_to_duty_env(0b1011_0101);
+
+        assert_eq!(pulse.duty, DutyCycle::Half);
+        assert!(pulse.length_counter_halt);
+        assert!(pulse.envelope.constant_volume);
+        assert_eq!(pulse.envelope.volume, 5);
+    }
+
+    #[test]
+    fn test_write_pulse1_sweep() {
+        let mut pulse = PulseChannel::new(NegateMode::OnesComplement);
+        pulse.write_to_sweep(0b1101_1010);
+
+        assert!(pulse.sweep_enabled);
+        assert_eq!(pulse.sweep_period, 5);
+        assert!(pulse.sweep_negate);
+        assert_eq!(pulse.sweep_shift, 2);
+    }
+
+    #[test]
+    fn test_length_counter_needs_enable() {
+        let mut pulse = PulseChannel::new(NegateMode::OnesComplement);
+        pulse.write_to_timer_hi(0b0000_1000);
+        assert_eq!(pulse.length_counter, 0);
+
+        pulse.set_enabled(true);
+        pulse.write_to_timer_hi(0b0000_1000);
+        assert_eq!(pulse.length_counter, 254);
+        pulse.tick_length_counter();
+        assert_eq!(pulse.length_counter, 253);
+
+        // halted counters keep their value
+        pulse.write_to_duty_env(0b0010_0000);
+        pulse.tick_length_counter();
+        assert_eq!(pulse.length_counter, 253);
+
+        pulse.set_enabled(false);
+        assert_eq!(pulse.length_counter, 0);
+    }
+
+    fn playing_pulse(duty_env: u8) -> PulseChannel {
+        let mut pulse = PulseChannel::new(NegateMode::OnesComplement);
+        pulse.set_enabled(true);
+        pulse.write_to_duty_env(duty_env);
+        pulse.write_to_timer_lo(0x08);
+        pulse.write_to_timer_hi(0x00);
+        pulse
+    }
+
+    #[test]
+    fn test_pulse_output_follows_duty() {
+        // 25% duty, constant volume 9
+        let mut pulse = playing_pulse(0b0101_1001);
+        let mut levels = Vec::new();
+        for _ in 0..8 {
+            levels.push(pulse.output());
+            // timer period 8, the step advances every 9 ticks
+            for _ in 0..9 {
+                pulse.tick_timer();
+            }
+        }
+
+        assert_eq!(levels, vec![0.0, 9.0, 9.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_pulse_muted() {
+        // 75% duty starts high
+        let mut pulse = playing_pulse(0b1101_1111);
+        assert_eq!(pulse.output(), 15.0);
+
+        pulse.write_to_timer_lo(0x07);
+        assert_eq!(pulse.output(), 0.0);
+
+        // the sweep target overflows $7FF
+        pulse.write_to_timer_lo(0xff);
+        pulse.write_to_timer_hi(0x07);
+        pulse.write_to_sweep(0b0000_0001);
+        assert_eq!(pulse.output(), 0.0);
+    }
+
+    #[test]
+    fn test_envelope_decay() {
+        let mut pulse = playing_pulse(0b1100_0001);
+        pulse.tick_envelope();
+        assert_eq!(pulse.output(), 15.0);
+
+        // divider period 1, the level drops every 2 clocks
+        pulse.tick_envelope();
+        pulse.tick_envelope();
+        assert_eq!(pulse.output(), 14.0);
+        for _ in 0..28 {
+            pulse.tick_envelope();
+        }
+        assert_eq!(pulse.output(), 0.0);
+
+        // without the loop flag it stays silent
+        pulse.tick_envelope();
+        pulse.tick_envelope();
+        assert_eq!(pulse.envelope.decay, 0);
+
+        pulse.write_to_duty_env(0b1110_0001);
+        pulse.tick_envelope();
+        pulse.tick_envelope();
+        assert_eq!(pulse.envelope.decay, 15);
+    }
+
+    #[test]
+    fn test_sweep_updates_period() {
+        let mut pulse = playing_pulse(0b1011_1111);
+        pulse.write_to_timer_lo(0x00);
+        pulse.write_to_timer_hi(0x01);
+        // period 0, shift 1
+        pulse.write_to_sweep(0b1000_0001);
+        pulse.tick_sweep();
+        assert_eq!(pulse.timer, 0x180);
+
+        // ones' complement: 0x180 - 0xc0 - 1
+        pulse.write_to_sweep(0b1000_1001);
+        pulse.tick_sweep();
+        assert_eq!(pulse.timer, 0x0bf);
+
+        pulse.write_to_sweep(0b0000_1001);
+        pulse.tick_sweep();
+        assert_eq!(pulse.timer, 0x0bf);
+    }
+
+    #[test]
+    fn test_frame_counter_step() {
+        let mut apu = Apu::new();
+        apu.tick(FRAME_COUNTER_PERIOD * 3);
+        assert_eq!(apu.frame_counter.step, 3);
+        apu.tick(FRAME_COUNTER_PERIOD);
+        assert_eq!(apu.frame_counter.step, 0);
+
+        apu.write_register(0x17, 0b1000_0000);
+        apu.tick(FRAME_COUNTER_PERIOD * 4);
+        assert_eq!(apu.frame_counter.step, 4);
+    }
+
+    #[test]
+    fn test_samples_at_sample_rate() {
+        let mut apu = Apu::new();
+        for _ in 0..(CPU_CLOCK_RATE as usize + 1) {
+            apu.tick(1);
+        }
+
+        assert_eq!(apu.samples.len(), SAMPLE_RATE as usize);
+    }
+
+    #[test]
+    fn test_configurable_sample_rate() {
+        let mut apu = Apu::new();
+        apu.set_sample_rate(48_000);
+        apu.tick(CPU_CLOCK_RATE as usize + 1);
+
+        assert_eq!(apu.samples.len(), 48_000);
+    }
+
+    #[test]
+    fn test_pulse2_registers() {
+        let mut apu = Apu::new();
+        apu.write_register(0x15, 0b0000_0010);
+        apu.write_register(0x04, 0b0111_0011);
+        apu.write_register(0x05, 0b1000_1001);
+        apu.write_register(0x06, 0x34);
+        apu.write_register(0x07, 0b0000_1010);
+
+        assert_eq!(apu.pulse2.duty, DutyCycle::Quarter);
+        assert_eq!(apu.pulse2.envelope.volume, 3);
+        assert!(apu.pulse2.sweep_negate);
+        assert_eq!(apu.pulse2.timer, 0x0234);
+        assert_eq!(apu.pulse2.length_counter, 254);
+        assert_eq!(apu.pulse1.timer, 0);
+        assert_eq!(apu.pulse1.length_counter, 0);
+    }
+
+    #[test]
+    fn test_pulse_negate_modes_differ() {
+        let mut apu = Apu::new();
+        apu.write_register(0x15, 0b0000_0011);
+        for channel in [0x00, 0x04] {
+            apu.write_register(channel + 0x01, 0b1000_1001);
+            apu.write_register(channel + 0x02, 0x00);
+            apu.write_register(channel + 0x03, 0x01);
+        }
+        apu.pulse1.tick_sweep();
+        apu.pulse2.tick_sweep();
+
+        // 0x100 - 0x80, minus one more on pulse 1
+        assert_eq!(apu.pulse1.timer, 0x7f);
+        assert_eq!(apu.pulse2.timer, 0x80);
+    }
+
+    fn playing_triangle() -> TriangleChannel {
+        let mut triangle = TriangleChannel::new();
+        triangle.set_enabled(true);
+        triangle.write_to_linear_counter(0b0000_0100);
+        triangle.write_to_timer_lo(0x02);
+        triangle.write_to_timer_hi(0b0000_1000);
+        triangle.tick_linear_counter();
+        triangle
+    }
+
+    #[test]
+    fn test_triangle_registers() {
+        let mut apu = Apu::new();
+        apu.write_register(0x15, 0b0000_0100);
+        apu.write_register(0x08, 0b1000_0101);
+        apu.write_register(0x0a, 0xcd);
+        apu.write_register(0x0b, 0b0001_0011);
+
+        assert!(apu.triangle.control);
+        assert_eq!(apu.triangle.linear_counter_reload, 5);
+        assert_eq!(apu.triangle.timer_period, 0x03cd);
+        assert_eq!(apu.triangle.length_counter, 20);
+    }
+
+    #[test]
+    fn test_triangle_32_step_cycle() {
+        let mut triangle = playing_triangle();
+        let mut levels = Vec::new();
+        for _ in 0..32 {
+            levels.push((triangle.output() * 15.0).round() as u8);
+            // timer period 2, the step advances every 3 ticks
+            for _ in 0..3 {
+                triangle.tick_timer();
+            }
+        }
+
+        assert_eq!(levels, TRIANGLE_SEQUENCE.to_vec());
+        assert_eq!(triangle.step, 0);
+        assert_eq!(triangle.output(), 1.0);
+    }
+
+    #[test]
+    fn test_triangle_linear_counter_gates_sequence() {
+        let mut triangle = playing_triangle();
+        assert_eq!(triangle.linear_counter, 4);
+        for _ in 0..4 {
+            triangle.tick_linear_counter();
+        }
+        assert_eq!(triangle.linear_counter, 0);
+
+        for _ in 0..10 {
+            triangle.tick_timer();
+        }
+        assert_eq!(triangle.step, 0);
+    }
+
+    #[test]
+    fn test_noise_registers() {
+        let mut apu = Apu::new();
+        apu.write_register(0x15, 0b0000_1000);
+        apu.write_register(0x0c, 0b0011_0111);
+        apu.write_register(0x0e, 0b1000_0011);
+        apu.write_register(0x0f, 0b0000_1000);
+
+        assert!(apu.noise.length_counter_halt);
+        assert!(apu.noise.envelope.constant_volume);
+        assert_eq!(apu.noise.envelope.volume, 7);
+        assert!(apu.noise.mode);
+        assert_eq!(apu.noise.timer_period, 32);
+        assert_eq!(apu.noise.length_counter, 254);
+    }
+
+    fn lfsr_loop_length(mode: bool) -> usize {
+        let mut noise = NoiseChannel::new();
+        noise.mode = mode;
+        for _ in 0..100 {
+            noise.clock_lfsr();
+        }
+        let start = noise.lfsr;
+        (1..=32767)
+            .find(|_| {
+                noise.clock_lfsr();
+                noise.lfsr == start
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_noise_lfsr_patterns() {
+        let mut noise = NoiseChannel::new();
+        let bits: Vec<u16> = (0..4)
+            .map(|_| {
+                noise.clock_lfsr();
+                noise.lfsr
+            })
+            .collect();
+        // 1 ^ 0 feeds into bit 14
+        assert_eq!(bits, vec![0x4000, 0x2000, 0x1000, 0x0800]);
+
+        assert_eq!(lfsr_loop_length(false), 32767);
+        assert_eq!(lfsr_loop_length(true), 93);
+    }
+
+    #[test]
+    fn test_noise_output() {
+        let mut noise = NoiseChannel::new();
+        noise.set_enabled(true);
+        noise.write(0, 0b0001_1010);
+        noise.write(3, 0);
+
+        // bit 0 of the LFSR set silences the channel
+        assert_eq!(noise.lfsr & 1, 1);
+        assert_eq!(noise.output(), 0.0);
+        noise.tick_timer();
+        assert_eq!(noise.output(), 10.0);
+    }
+
+    #[test]
+    fn test_dmc_registers() {
+        let mut apu = Apu::new();
+        apu.write_register(0x10, 0b1100_1111);
+        apu.write_register(0x11, 0xff);
+        apu.write_register(0x12, 0x02);
+        apu.write_register(0x13, 0x01);
+
+        assert!(apu.dmc.irq_enabled);
+        assert!(apu.dmc.loop_flag);
+        assert_eq!(apu.dmc.rate, 54);
+        assert_eq!(apu.dmc.output_level, 127);
+        assert_eq!(apu.dmc.sample_addr, 0xc080);
+        assert_eq!(apu.dmc.sample_length, 17);
+
+        apu.write_register(0x15, 0b0001_0000);
+        assert_eq!(apu.dmc.current_addr, 0xc080);
+        assert_eq!(apu.dmc.bytes_remaining, 17);
+    }
+
+    fn playing_dmc(control: u8, sample: &[u8]) -> (DmcChannel, Vec<u16>) {
+        let mut dmc = DmcChannel::new();
+        dmc.write(0, control);
+        dmc.write(1, 64);
+        dmc.write(3, 0);
+        dmc.set_enabled(true);
+        // the first sample byte is only shifted out after the silent 8 bits left from power on
+        let mut reads = Vec::new();
+        for _ in 0..8 * 54 {
+            dmc.tick_timer(&mut |addr| {
+                reads.push(addr);
+                sample[(addr - 0xc000) as usize % sample.len()]
+            });
+        }
+        assert_eq!(dmc.output_level, 64);
+        (dmc, reads)
+    }
+
+    #[test]
+    fn test_dmc_output_level() {
+        // rate 54
+        let (mut dmc, _) = playing_dmc(0b0000_1111, &[0b0000_1011]);
+        let mut levels = Vec::new();
+        for _ in 0..8 {
+            dmc.tick_timer(&mut |_| 0);
+            levels.push(dmc.output_level);
+            for _ in 0..53 {
+                dmc.tick_timer(&mut |_| 0);
+            }
+        }
+
+        // bits are played from bit 0 up
+        assert_eq!(levels, vec![66, 68, 66, 68, 66, 64, 62, 60]);
+    }
+
+    #[test]
+    fn test_dmc_output_level_clamps() {
+        let mut dmc = DmcChannel::new();
+        dmc.write(1, 126);
+        dmc.silence = false;
+        dmc.shift_register = 0xff;
+        dmc.tick_timer(&mut |_| 0);
+        assert_eq!(dmc.output_level, 126);
+
+        dmc.output_level = 1;
+        dmc.shift_register = 0x00;
+        dmc.timer = 0;
+        dmc.tick_timer(&mut |_| 0);
+        assert_eq!(dmc.output_level, 1);
+    }
+
+    #[test]
+    fn test_dmc_end_of_sample() {
+        let (dmc, reads) = playing_dmc(0b1000_1111, &[0x00]);
+        assert_eq!(reads, vec![0xc000]);
+        assert_eq!(dmc.bytes_remaining, 0);
+        assert!(dmc.irq_flag);
+
+        // looping restarts the sample instead of raising the IRQ
+        let (dmc, reads) = playing_dmc(0b1100_1111, &[0x00]);
+        assert_eq!(reads, vec![0xc000, 0xc000]);
+        assert_eq!(dmc.bytes_remaining, 1);
+        assert!(!dmc.irq_flag);
+    }
+
+    #[test]
+    fn test_dmc_irq_acknowledge() {
+        let mut apu = Apu::new();
+        apu.dmc.irq_enabled = true;
+        apu.dmc.irq_flag = true;
+        assert!(apu.irq_pending());
+
+        apu.write_register(0x15, 0);
+        assert!(!apu.irq_pending());
+    }
+
+    fn apu_with_length_counters(frame_counter: u8) -> Apu {
+        let mut apu = Apu::new();
+        apu.write_register(0x15, 0b0000_1111);
+        apu.write_register(0x17, frame_counter);
+        // length 254 on every channel
+        for reg in [0x03, 0x07, 0x0b, 0x0f] {
+            apu.write_register(reg, 0b0000_1000);
+        }
+        apu
+    }
+
+    fn length_counters(apu: &Apu) -> [u8; 4] {
+        [
+            apu.pulse1.length_counter,
+            apu.pulse2.length_counter,
+            apu.triangle.length_counter,
+            apu.noise.length_counter,
+        ]
+    }
+
+    #[test]
+    fn test_length_counters_four_step() {
+        let mut apu = apu_with_length_counters(0b0100_0000);
+        // twice per 4 step frame
+        apu.tick(FRAME_COUNTER_PERIOD * 4);
+        assert_eq!(length_counters(&apu), [252; 4]);
+        apu.tick(FRAME_COUNTER_PERIOD * 4 * 10);
+        assert_eq!(length_counters(&apu), [232; 4]);
+    }
+
+    #[test]
+    fn test_length_counters_five_step() {
+        // the $4017 write clocks once right away
+        let mut apu = apu_with_length_counters(0b1100_0000);
+        assert_eq!(length_counters(&apu), [254; 4]);
+        apu.write_register(0x17, 0b1100_0000);
+        assert_eq!(length_counters(&apu), [253; 4]);
+
+        // twice per 5 step frame
+        apu.tick(FRAME_COUNTER_PERIOD * 5);
+        assert_eq!(length_counters(&apu), [251; 4]);
+        apu.tick(FRAME_COUNTER_PERIOD * 4);
+        assert_eq!(length_counters(&apu), [250; 4]);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new();
+        apu.tick(FRAME_COUNTER_PERIOD * 3);
+        assert!(!apu.irq_pending());
+        apu.tick(FRAME_COUNTER_PERIOD);
+        assert!(apu.irq_pending());
+
+        assert_eq!(apu.read_register(0x15), 0b0100_0000);
+        assert!(!apu.irq_pending());
+
+        // inhibited, and never raised in 5 step mode
+        apu.write_register(0x17, 0b0100_0000);
+        apu.tick(FRAME_COUNTER_PERIOD * 4);
+        assert!(!apu.irq_pending());
+        apu.write_register(0x17, 0b1000_0000);
+        apu.tick(FRAME_COUNTER_PERIOD * 5);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn test_read_status() {
+        let mut apu = apu_with_length_counters(0b0100_0000);
+        apu.write_register(0x15, 0b0001_0101);
+        assert_eq!(apu.read_register(0x15), 0b0001_0101);
+    }
+
+    #[test]
+    fn test_mix_output() {
+        let mut apu = Apu::new();
+        // the triangle powers on at level 15
+        let triangle_only = apu.mix_output();
+        assert!((triangle_only - 159.79 / (8227.0 / 15.0 + 100.0)).abs() < 1e-6);
+        apu.triangle.step = 15;
+        assert_eq!(apu.mix_output(), 0.0);
+
+        apu.write_register(0x11, 127);
+        let dmc_only = apu.mix_output();
+        assert!((dmc_only - 159.79 / (22638.0 / 127.0 + 100.0)).abs() < 1e-6);
+
+        apu.pulse1.set_enabled(true);
+        apu.pulse1.write_to_duty_env(0b1101_1111);
+        apu.pulse1.write_to_timer_lo(0xff);
+        apu.pulse1.write_to_timer_hi(0x00);
+        let pulse = apu.mix_output() - dmc_only;
+        assert!((pulse - 95.88 / (8128.0 / 15.0 + 100.0)).abs() < 1e-6);
+    }
+}
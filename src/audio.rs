@@ -0,0 +1,247 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+#[cfg(feature = "sdl")]
+use sdl2::audio::AudioQueue;
+
+const CHANNELS: u16 = 1;
+const BITS_PER_SAMPLE: u16 = 16;
+const HEADER_SIZE: u32 = 44;
+
+// Where AudioOutput queues the mixed samples, the SDL audio queue or a recorder in tests.
+pub trait AudioSink {
+    fn queued_samples(&self) -> usize;
+    fn queue(&mut self, samples: &[f32]) -> Result<(), String>;
+}
+
+#[cfg(feature = "sdl")]
+impl AudioSink for AudioQueue<f32> {
+    fn queued_samples(&self) -> usize {
+        self.size() as usize / std::mem::size_of::<f32>()
+    }
+
+    fn queue(&mut self, samples: &[f32]) -> Result<(), String> {
+        self.queue_audio(samples)
+    }
+}
+
+// Plays the APU samples of each frame. When the device has drained the queue, silence is
+// queued ahead of the samples so playback restarts with some slack instead of crackling.
+pub struct AudioOutput<S: AudioSink> {
+    sink: S,
+    // 50ms of silence
+    underrun_padding: usize,
+}
+
+impl<S: AudioSink> AudioOutput<S> {
+    pub fn new(sink: S, sample_rate: u32) -> Self {
+        AudioOutput {
+            sink,
+            underrun_padding: sample_rate as usize / 20,
+        }
+    }
+
+    pub fn push_samples(&mut self, samples: &[f32]) {
+        if self.sink.queued_samples() == 0 {
+            self.queue(&vec![0.0; self.underrun_padding]);
+        }
+        self.queue(samples);
+    }
+
+    // a dropped buffer is only a glitch, so errors are reported and playback continues
+    fn queue(&mut self, samples: &[f32]) {
+        if let Err(e) = self.sink.queue(samples) {
+            println!("Failed to queue audio: {}", e);
+        }
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+}
+
+// Writes mono 16bit PCM WAV. The chunk sizes are unknown until recording stops,
+// so they are written as 0 and patched by finalize.
+// http://soundfile.sapp.org/doc/WaveFormat/
+pub struct WavWriter {
+    file: BufWriter<File>,
+    samples_written: u64,
+    sample_rate: u32,
+}
+
+impl WavWriter {
+    pub fn new(path: &Path, sample_rate: u32) -> io::Result<Self> {
+        let mut writer = WavWriter {
+            file: BufWriter::new(File::create(path)?),
+            samples_written: 0,
+            sample_rate,
+        };
+        writer.write_header(0)?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self, data_size: u32) -> io::Result<()> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+        let byte_rate = self.sample_rate * block_align as u32;
+
+        self.file.write_all(b"RIFF")?;
+        self.file
+            .write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.file.write_all(b"WAVE")?;
+
+        self.file.write_all(b"fmt ")?;
+        self.file.write_all(&16u32.to_le_bytes())?;
+        self.file.write_all(&1u16.to_le_bytes())?; // PCM
+        self.file.write_all(&CHANNELS.to_le_bytes())?;
+        self.file.write_all(&self.sample_rate.to_le_bytes())?;
+        self.file.write_all(&byte_rate.to_le_bytes())?;
+        self.file.write_all(&block_align.to_le_bytes())?;
+        self.file.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+        self.file.write_all(b"data")?;
+        self.file.write_all(&data_size.to_le_bytes())
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples_written += samples.len() as u64;
+        Ok(())
+    }
+
+    pub fn finalize(mut self) -> io::Result<()> {
+        let data_size = (self.samples_written * (BITS_PER_SAMPLE / 8) as u64) as u32;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header(data_size)?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // records what was queued, drained by the test like a playing device would
+    struct MockSink {
+        samples: Vec<f32>,
+        queued: usize,
+        fail: bool,
+    }
+
+    impl AudioSink for MockSink {
+        fn queued_samples(&self) -> usize {
+            self.queued
+        }
+
+        fn queue(&mut self, samples: &[f32]) -> Result<(), String> {
+            if self.fail {
+                return Err(String::from("device lost"));
+            }
+            self.samples.extend_from_slice(samples);
+            self.queued += samples.len();
+            Ok(())
+        }
+    }
+
+    fn mock_output() -> AudioOutput<MockSink> {
+        let sink = MockSink {
+            samples: Vec::new(),
+            queued: 0,
+            fail: false,
+        };
+        AudioOutput::new(sink, 100)
+    }
+
+    #[test]
+    fn test_underrun_inserts_silence() {
+        let mut output = mock_output();
+        output.push_samples(&[0.5, 0.25]);
+        output.push_samples(&[0.125]);
+
+        let mut expected = vec![0.0; 5];
+        expected.extend_from_slice(&[0.5, 0.25, 0.125]);
+        assert_eq!(output.sink().samples, expected);
+
+        // the device played everything
+        output.sink.queued = 0;
+        output.push_samples(&[1.0]);
+        assert_eq!(output.sink().samples.len(), 8 + 5 + 1);
+        assert_eq!(output.sink().samples[8..], [0.0, 0.0, 0.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_queue_errors_do_not_panic() {
+        let mut output = mock_output();
+        output.sink.fail = true;
+        output.push_samples(&[0.5]);
+        assert!(output.sink().samples.is_empty());
+    }
+
+    #[test]
+    fn test_apu_samples_reach_the_sink() {
+        let mut apu = crate::apu::Apu::new();
+        apu.set_sample_rate(1_000);
+        // the triangle powers on at full level
+        apu.tick(1_790);
+
+        let mut output = mock_output();
+        output.push_samples(&apu.samples);
+        assert_eq!(output.sink().samples.len(), 5 + 1);
+        assert_eq!(output.sink().samples[5], apu.mix_output());
+    }
+
+    fn u16_at(data: &[u8], pos: usize) -> u16 {
+        u16::from_le_bytes([data[pos], data[pos + 1]])
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+    }
+
+    #[test]
+    fn test_wav_header() {
+        let path = std::env::temp_dir().join("rustnes_test_wav_header.wav");
+        let mut writer = WavWriter::new(&path, 44100).unwrap();
+        writer.write_samples(&[0.0; 44100]).unwrap();
+        writer.finalize().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(data.len(), 44 + 44100 * 2);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32_at(&data, 4), 36 + 44100 * 2);
+        assert_eq!(&data[8..12], b"WAVE");
+        assert_eq!(&data[12..16], b"fmt ");
+        assert_eq!(u32_at(&data, 16), 16);
+        assert_eq!(u16_at(&data, 20), 1);
+        assert_eq!(u16_at(&data, 22), 1);
+        assert_eq!(u32_at(&data, 24), 44100);
+        assert_eq!(u32_at(&data, 28), 44100 * 2);
+        assert_eq!(u16_at(&data, 32), 2);
+        assert_eq!(u16_at(&data, 34), 16);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(u32_at(&data, 40), 44100 * 2);
+        assert!(data[44..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_write_samples_converts_to_i16() {
+        let path = std::env::temp_dir().join("rustnes_test_wav_samples.wav");
+        let mut writer = WavWriter::new(&path, 44100).unwrap();
+        writer.write_samples(&[1.0, -1.0, 0.5, 2.0]).unwrap();
+        writer.finalize().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let samples: Vec<i16> = data[44..]
+            .chunks(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(samples, vec![i16::MAX, -i16::MAX, i16::MAX / 2, i16::MAX]);
+    }
+}
@@ -1,31 +1,105 @@
+use std::fmt;
+
+use crate::apu::Apu;
 use crate::cartoridge::Rom;
-use crate::cpu::Memory;
+use crate::cpu::{CpuBus, Memory, CPU};
 use crate::joypad::Joypad;
+use crate::mapper::{Mapper, MapperStateError};
+use crate::mapper_registry;
 use crate::ppu::{NesPPU, PPU};
 
+use serde::{Deserialize, Serialize};
+
+pub type FrameCallback<'a> = Box<dyn FnMut(&NesPPU, &mut Joypad, &mut Joypad) + 'a>;
+pub type AudioCallback<'a> = Box<dyn FnMut(&[f32]) + 'a>;
+
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
-    prg_rom: Vec<u8>,
+    // cartridge work RAM, also where test ROMs report their results
+    prg_ram: [u8; 0x2000],
+    mapper: Box<dyn Mapper>,
     ppu: NesPPU,
+    apu: Apu,
     cycle: usize,
-    gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad) + 'call>,
+    // 3 PPU dots per CPU cycle on NTSC
+    pub ppu_cycle_count: u64,
+    gameloop_callback: FrameCallback<'call>,
+    audio_callback: AudioCallback<'call>,
     joypad1: Joypad,
+    joypad2: Joypad,
+    // the last value driven on the CPU data bus, what write-only and unmapped addresses read back
+    last_bus_value: u8,
+    // CPU cycles an OAM DMA halts the CPU for, run by the CPU once the writing instruction ends
+    dma_stall: usize,
+    // an NMI the PPU raised that the CPU hasn't taken yet
+    nmi_pending: bool,
+}
+
+// everything on the bus but the callbacks, for save states
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BusState {
+    pub cpu_vram: Vec<u8>,
+    pub prg_ram: Vec<u8>,
+    pub mapper: Vec<u8>,
+    pub ppu: NesPPU,
+    pub apu: Apu,
+    pub cycle: usize,
+    pub ppu_cycle_count: u64,
+    pub joypad1: Joypad,
+    pub joypad2: Joypad,
+    pub last_bus_value: u8,
+    pub nmi_pending: bool,
 }
 
 impl<'a> Bus<'a> {
     pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call>
     where
-        F: FnMut(&NesPPU, &mut Joypad) + 'call,
+        F: FnMut(&NesPPU, &mut Joypad, &mut Joypad) + 'call,
     {
+        Bus::new_with_audio(rom, gameloop_callback, |_: &[f32]| {})
+    }
+
+    pub fn new_with_audio<'call, F, A>(
+        rom: Rom,
+        gameloop_callback: F,
+        audio_callback: A,
+    ) -> Bus<'call>
+    where
+        F: FnMut(&NesPPU, &mut Joypad, &mut Joypad) + 'call,
+        A: FnMut(&[f32]) + 'call,
+    {
+        let mapper_number = if mapper_registry::supported_mappers().contains(&rom.mapper) {
+            rom.mapper
+        } else {
+            println!("Unsupported mapper {}, defaulting to mapper 0", rom.mapper);
+            0
+        };
+        let mapper =
+            mapper_registry::create_mapper(mapper_number, rom.prg_rom, &rom.chr_rom).unwrap();
         let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
-        Bus {
+        let mut bus = Bus {
             cpu_vram: [0; 0x0800], // 2048
-            prg_rom: rom.prg_rom,
-            ppu: ppu,
+            prg_ram: [0; 0x2000],
+            mapper,
+            ppu,
+            apu: Apu::new(),
             cycle: 0,
+            ppu_cycle_count: 0,
             gameloop_callback: Box::from(gameloop_callback),
+            audio_callback: Box::from(audio_callback),
             joypad1: Joypad::new(),
-        }
+            joypad2: Joypad::new(),
+            last_bus_value: 0,
+            dma_stall: 0,
+            nmi_pending: false,
+        };
+        bus.sync_ppu_mapping();
+        bus
+    }
+
+    // how often the audio callback gets samples, apu::SAMPLE_RATE unless set
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.apu.set_sample_rate(sample_rate);
     }
 
     pub fn show_ppu(&self) {
@@ -36,29 +110,159 @@ impl<'a> Bus<'a> {
         // println!("bus cycle: {}", self.cycle);
     }
 
-    pub fn tick(&mut self, cycles: usize) {
+    // OAM DMA copies the whole CPU page $XX00-$XXFF
+    pub fn read_oam_dma_source(&mut self, page: u8) -> [u8; 256] {
+        let mut buffer = [0; 256];
+        let base = (page as u16) << 8;
+        for (i, data) in buffer.iter_mut().enumerate() {
+            *data = self.mem_read(base + i as u16);
+        }
+        buffer
+    }
+
+    // the PPU keeps its own copy of the mapper's CHR banking and nametable mirroring
+    fn sync_ppu_mapping(&mut self) {
+        let mut banks = [0; 8];
+        for (i, bank) in banks.iter_mut().enumerate() {
+            *bank = self.mapper.chr_addr(i as u16 * 0x400);
+        }
+        self.ppu.set_chr_banks(banks);
+        if let Some(mirroring) = self.mapper.mirroring() {
+            self.ppu.mirroring = mirroring;
+        }
+    }
+
+    // battery-backed saves, only meaningful when the ROM has_battery
+    pub fn save_prg_ram(&self) -> Vec<u8> {
+        self.prg_ram.to_vec()
+    }
+
+    pub fn load_prg_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    pub fn save_mapper_state(&self) -> Vec<u8> {
+        self.mapper.save_state()
+    }
+
+    pub fn load_mapper_state(&mut self, data: &[u8]) -> Result<(), MapperStateError> {
+        self.mapper.load_state(data)?;
+        self.sync_ppu_mapping();
+        Ok(())
+    }
+
+    pub fn save_state(&self) -> BusState {
+        BusState {
+            cpu_vram: self.cpu_vram.to_vec(),
+            prg_ram: self.prg_ram.to_vec(),
+            mapper: self.mapper.save_state(),
+            ppu: self.ppu.clone(),
+            apu: self.apu.clone(),
+            cycle: self.cycle,
+            ppu_cycle_count: self.ppu_cycle_count,
+            joypad1: self.joypad1.clone(),
+            joypad2: self.joypad2.clone(),
+            last_bus_value: self.last_bus_value,
+            nmi_pending: self.nmi_pending,
+        }
+    }
+
+    // the callbacks stay attached, only the emulated state is replaced.
+    // A mapper state that doesn't fit the cartridge leaves the bus untouched.
+    pub fn load_state(&mut self, state: BusState) -> Result<(), MapperStateError> {
+        self.mapper.load_state(&state.mapper)?;
+        let len = state.cpu_vram.len().min(self.cpu_vram.len());
+        self.cpu_vram[..len].copy_from_slice(&state.cpu_vram[..len]);
+        self.load_prg_ram(&state.prg_ram);
+        self.ppu = state.ppu;
+        self.apu = state.apu;
+        self.cycle = state.cycle;
+        self.ppu_cycle_count = state.ppu_cycle_count;
+        self.joypad1 = state.joypad1;
+        self.joypad2 = state.joypad2;
+        self.last_bus_value = state.last_bus_value;
+        self.nmi_pending = state.nmi_pending;
+        self.sync_ppu_mapping();
+        Ok(())
+    }
+
+    // power cycling the cartridge, unlike the reset button which leaves mapper registers alone
+    pub fn reset_mapper(&mut self) {
+        self.mapper.reset();
+        self.sync_ppu_mapping();
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.mapper.irq_pending() || self.apu.irq_pending()
+    }
+}
+
+// callbacks can't be printed, so Debug is written by hand
+impl fmt::Debug for Bus<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bus")
+            .field("cpu_vram", &format_args!("[{} bytes]", self.cpu_vram.len()))
+            .field("ppu", &self.ppu)
+            .field("cycle", &self.cycle)
+            .field("ppu_cycle_count", &self.ppu_cycle_count)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CpuBus for Bus<'_> {
+    fn tick(&mut self, cycles: usize) {
         // println!("before: {}", self.cycle);
         // println!("tick mem read:{:04x}", self.mem_read(0x2002)); // TODO
         self.cycle += cycles;
         // println!("aaaaaaaaaaaaa:{}", cycles);
-        let new_frame = self.ppu.tick(cycles * 3);
+        let mapper = &self.mapper;
+        self.apu
+            .tick_with_memory(cycles, |addr| mapper.prg_read(addr));
+        self.ppu_cycle_count += cycles as u64 * 3;
+        let result = self.ppu.tick(cycles * 3);
+        if result.nmi_triggered {
+            self.nmi_pending = true;
+        }
+        // mappers count the visible and pre-render scanlines while the PPU is rendering
+        if let Some(scanline) = result.scanline_completed {
+            if (scanline < 240 || scanline == 261) && self.ppu.is_rendering_enabled() {
+                self.mapper.on_scanline();
+            }
+        }
         // println!("tick mem read:{:04x}", self.mem_read(0x2002)); // TODO
-        if new_frame {
-            (self.gameloop_callback)(&self.ppu, &mut self.joypad1);
+        if result.new_frame {
+            (self.gameloop_callback)(&self.ppu, &mut self.joypad1, &mut self.joypad2);
+            (self.audio_callback)(&self.apu.samples);
+            self.apu.samples.clear();
         }
         // println!("after: {}", self.cycle);
     }
 
-    pub fn poll_nmi_status(&mut self) -> Option<u8> {
-        self.ppu.nmi_interrupt.take()
+    fn take_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_pending)
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            addr = addr % 0x4000;
-        }
-        self.prg_rom[addr as usize]
+    fn irq_status(&self) -> bool {
+        self.irq_pending()
+    }
+
+    fn mapper_irq_acknowledge(&mut self) {
+        self.mapper.irq_acknowledge();
+    }
+
+    fn ppu_position(&self) -> (u16, usize) {
+        (self.ppu.scanline(), self.ppu.cycle())
+    }
+
+    fn take_dma_stall(&mut self) -> usize {
+        std::mem::take(&mut self.dma_stall)
+    }
+}
+
+impl CPU<Bus<'_>> {
+    pub fn ppu_cycles(&self) -> u64 {
+        self.bus.ppu_cycle_count
     }
 }
 
@@ -66,17 +270,17 @@ const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 // const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRROR_END: u16 = 0x3FFF;
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
 
 impl Memory for Bus<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
-        match addr {
+        let data = match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
                 self.cpu_vram[mirror_down_addr as usize]
             }
-            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => {
-                panic!("Attempt to read from write-only PPU address {:x}", addr)
-            }
+            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => self.last_bus_value,
             0x2002 => self.ppu.read_status(),
             0x2004 => self.ppu.read_oam_data(),
             0x2007 => self.ppu.read_data(),
@@ -84,34 +288,34 @@ impl Memory for Bus<'_> {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_read(mirror_down_addr)
             }
-            0x4000..=0x4015 => {
-                //ignore APU
-                0
-            }
+            0x4000..=0x4013 | 0x4015 => self.apu.read_register((addr - 0x4000) as u8),
 
             0x4016 => self.joypad1.read(),
-
-            0x4017 => {
-                // ignore joypad 2
-                0
-            }
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            0x4017 => self.joypad2.read(),
+            0x5000..=0x5FFF => self.mapper.expansion_read(addr),
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize],
+            0x8000..=0xFFFF => self.mapper.prg_read(addr),
 
             _ => {
                 println!("Ignoring mem access at {}", addr);
-                0
+                self.last_bus_value
             }
-        }
+        };
+        self.last_bus_value = data;
+        data
     }
     fn mem_write(&mut self, addr: u16, data: u8) {
         // println!("mem_write addr:0x{:04x}, data:0x{:02x}", addr, data); // TODO
+        self.last_bus_value = data;
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
                 self.cpu_vram[mirror_down_addr as usize] = data;
             }
             0x2000 => {
-                self.ppu.write_to_ctrl(data);
+                if self.ppu.write_to_ctrl(data) {
+                    self.nmi_pending = true;
+                }
             }
             0x2001 => {
                 self.ppu.write_to_mask(data);
@@ -138,25 +342,531 @@ impl Memory for Bus<'_> {
                 let mirror_down_addr = addr & 0b00100000_00000111;
                 self.mem_write(mirror_down_addr, data);
             }
-            0x4000..=0x4013 => {
-                // TODO APU
+            0x4000..=0x4013 | 0x4015 | 0x4017 => {
+                self.apu.write_register((addr - 0x4000) as u8, data);
             }
+            // the CPU is halted for the copy, one more cycle when it starts on an odd cycle
             0x4014 => {
-                // TODO OAMDMA
-            }
-            0x4015 => {
-                // TODO SND_CHN
+                let buffer = self.read_oam_dma_source(data);
+                self.ppu.write_oam_dma(&buffer);
+                self.dma_stall = 513 + self.cycle % 2;
             }
+            // the strobe goes to both controllers, $4017 writes belong to the APU
             0x4016 => {
                 self.joypad1.write(data);
+                self.joypad2.write(data);
+            }
+            0x5000..=0x5FFF => {
+                self.mapper.expansion_write(addr, data);
+                self.sync_ppu_mapping();
             }
-            0x4017 => {
-                // TODOjoypad2
+            PRG_RAM..=PRG_RAM_END => {
+                self.prg_ram[(addr - PRG_RAM) as usize] = data;
             }
             0x8000..=0xFFFF => {
-                panic!("Attempt to write to Cartridge ROM space")
+                self.mapper.prg_write(addr, data);
+                self.sync_ppu_mapping();
             }
             _ => println!("Ignoring mem write-access at {}", addr),
         }
     }
+
+    fn mem_peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b00000111_11111111;
+                self.cpu_vram[mirror_down_addr as usize]
+            }
+            PRG_RAM..=PRG_RAM_END => self.prg_ram[(addr - PRG_RAM) as usize],
+            0x8000..=0xFFFF => self.mapper.prg_read(addr),
+            // I/O registers change state on read, so they are not peeked
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartoridge::Mirroring;
+
+    fn test_rom() -> Rom {
+        let mut rom_data = vec![
+            0x4E, 0x45, 0x53, 0x1A, // NES^Z
+            0x02, // PRG ROM size (16KB)
+            0x01, // CHR ROM size (8KB)
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        rom_data.resize(rom_data.len() + 2 * 16 * 1024 + 8 * 1024, 0);
+        Rom::from_bytes(&rom_data).unwrap()
+    }
+
+    #[test]
+    fn test_take_nmi_clears_nmi() {
+        let mut bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        bus.ppu.status.set_start_vblank();
+        bus.mem_write(0x2000, 0b1000_0000);
+
+        assert!(bus.take_nmi());
+        assert!(!bus.take_nmi());
+    }
+
+    #[test]
+    fn test_tick_raises_nmi_at_vblank() {
+        let mut bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        bus.mem_write(0x2000, 0b1000_0000);
+
+        // 241 scanlines of 341 dots, 3 dots per CPU cycle, vblank starts on the last one
+        for _ in 1..(241 * 341_usize).div_ceil(3) {
+            bus.tick(1);
+            assert!(!bus.take_nmi());
+        }
+        bus.tick(1);
+        assert!(bus.take_nmi());
+        assert!(!bus.take_nmi());
+    }
+
+    #[test]
+    fn test_mem_peek_has_no_side_effects() {
+        let mut bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        bus.mem_write(0x0001, 0x55);
+        assert_eq!(bus.mem_peek(0x0801), 0x55);
+
+        bus.joypad1
+            .set_button(crate::joypad::JoypadButton::BUTTON_A, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        bus.mem_peek(0x4016);
+        assert_eq!(bus.mem_read(0x4016), 1);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        bus.mem_write(0x6000, 0x80);
+        bus.mem_write(0x7fff, 0x12);
+
+        assert_eq!(bus.mem_read(0x6000), 0x80);
+        assert_eq!(bus.mem_peek(0x7fff), 0x12);
+    }
+
+    #[test]
+    fn test_joypad_serial_read() {
+        use crate::joypad::JoypadButton;
+
+        let mut bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        bus.joypad1.set_button(JoypadButton::BUTTON_A, true);
+        bus.joypad1.set_button(JoypadButton::SELECT, true);
+        bus.joypad1.set_button(JoypadButton::UP, true);
+        bus.joypad1.set_button(JoypadButton::RIGHT, true);
+
+        // while the strobe is high every read reports A
+        bus.mem_write(0x4016, 1);
+        assert_eq!(bus.mem_read(0x4016), 1);
+        assert_eq!(bus.mem_read(0x4016), 1);
+
+        // A, B, Select, Start, Up, Down, Left, Right, then 1 after the 8th read
+        bus.mem_write(0x4016, 0);
+        let bits: Vec<u8> = (0..10).map(|_| bus.mem_read(0x4016)).collect();
+        assert_eq!(bits, vec![1, 0, 1, 0, 1, 0, 0, 1, 1, 1]);
+
+        // a new strobe restarts from A
+        bus.joypad1.set_button(JoypadButton::BUTTON_A, false);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        assert_eq!(bus.mem_read(0x4016), 0);
+        assert_eq!(bus.mem_read(0x4016), 0);
+        assert_eq!(bus.mem_read(0x4016), 1);
+    }
+
+    #[test]
+    fn test_joypad2_serial_read() {
+        use crate::joypad::JoypadButton;
+
+        let mut bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        bus.joypad1.set_button(JoypadButton::BUTTON_A, true);
+        bus.joypad1.set_button(JoypadButton::DOWN, true);
+        bus.joypad2.set_button(JoypadButton::START, true);
+        bus.joypad2.set_button(JoypadButton::LEFT, true);
+
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        let mut bits1 = Vec::new();
+        let mut bits2 = Vec::new();
+        // interleaved reads keep each controller's own position
+        for _ in 0..9 {
+            bits1.push(bus.mem_read(0x4016));
+            bits2.push(bus.mem_read(0x4017));
+        }
+        assert_eq!(bits1, vec![1, 0, 0, 0, 0, 1, 0, 0, 1]);
+        assert_eq!(bits2, vec![0, 0, 0, 1, 0, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_gameloop_callback_receives_joypad() {
+        let mut frames = 0;
+        {
+            let mut bus = Bus::new(
+                test_rom(),
+                |_: &NesPPU, joypad1: &mut Joypad, joypad2: &mut Joypad| {
+                    joypad1.set_button(crate::joypad::JoypadButton::BUTTON_A, true);
+                    joypad2.set_button(crate::joypad::JoypadButton::BUTTON_B, true);
+                    frames += 1;
+                },
+            );
+            assert_eq!(bus.mem_read(0x4016), 0);
+            assert_eq!(bus.mem_read(0x4017), 0);
+
+            // 262 scanlines of 114 CPU cycles complete one frame
+            for _ in 0..262 {
+                bus.tick(114);
+            }
+
+            bus.mem_write(0x4016, 1);
+            bus.mem_write(0x4016, 0);
+            assert_eq!(bus.mem_read(0x4016), 1);
+            assert_eq!(bus.mem_read(0x4016), 0);
+            assert_eq!(bus.mem_read(0x4017), 0);
+            assert_eq!(bus.mem_read(0x4017), 1);
+        }
+        assert_eq!(frames, 1);
+    }
+
+    #[test]
+    fn test_mmc3_scanline_irq() {
+        let mut rom_data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        rom_data.resize(rom_data.len() + 2 * 16 * 1024 + 8 * 1024, 0);
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        bus.mem_write(0x2001, 0b0001_1000);
+        bus.mem_write(0xc000, 4);
+        bus.mem_write(0xc001, 0);
+        bus.mem_write(0xe001, 0);
+
+        // 114 CPU cycles = 342 PPU dots, one scanline
+        for _ in 0..4 {
+            bus.tick(114);
+            assert!(!bus.irq_pending());
+        }
+        bus.tick(114);
+        assert!(bus.irq_pending());
+    }
+
+    #[test]
+    fn test_cnrom_chr_pages() {
+        let mut rom_data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x04, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        rom_data.resize(rom_data.len() + 2 * 16 * 1024, 0);
+        for bank in 0..4u8 {
+            rom_data.extend(vec![bank + 1; 8 * 1024]);
+        }
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        assert_eq!(bus.ppu.chr_page_0()[0], 1);
+
+        bus.mem_write(0x8000, 2);
+        assert_eq!(bus.ppu.chr_page_0()[0], 3);
+        assert_eq!(bus.ppu.chr_page_1()[0xfff], 3);
+        assert_eq!(bus.ppu.vram_peek(0x0000), 3);
+
+        for bank in [3, 0, 1, 2] {
+            bus.mem_write(0xffff, bank);
+            assert_eq!(bus.ppu.read_chr(0), bank + 1);
+            assert_eq!(bus.ppu.read_chr(0x1fff), bank + 1);
+        }
+    }
+
+    #[test]
+    fn test_axrom_single_screen_mirroring() {
+        let mut rom_data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x04, 0x00, 0x71, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        rom_data.resize(rom_data.len() + 4 * 16 * 1024, 0);
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        // the header's vertical mirroring is replaced by the mapper's
+        assert_eq!(bus.ppu.mirroring, Mirroring::SingleScreenLower);
+
+        bus.mem_write(0x8000, 0b1_0000);
+        assert_eq!(bus.ppu.mirroring, Mirroring::SingleScreenUpper);
+        bus.mem_write(0x2006, 0x2c);
+        bus.mem_write(0x2006, 0x00);
+        bus.mem_write(0x2007, 0x5a);
+        assert_eq!(bus.ppu.vram[0x400], 0x5a);
+    }
+
+    #[test]
+    fn test_mmc1_mirroring_reaches_ppu() {
+        let mut rom_data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        rom_data.resize(rom_data.len() + 2 * 16 * 1024 + 8 * 1024, 0);
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+
+        // control register 0b01110, vertical mirroring, written one bit at a time
+        for bit in 0..5 {
+            bus.mem_write(0x8000, (0b01110 >> bit) & 1);
+        }
+        assert_eq!(bus.ppu.mirroring, Mirroring::Vertical);
+        assert_eq!(bus.ppu.mirror_vram_addr(0x2800), 0x0000);
+
+        for bit in 0..5 {
+            bus.mem_write(0x9fff, (0b01111 >> bit) & 1);
+        }
+        assert_eq!(bus.ppu.mirroring, Mirroring::Horizontal);
+        assert_eq!(bus.ppu.mirror_vram_addr(0x2400), 0x0000);
+    }
+
+    #[test]
+    fn test_mmc3_mirroring_reaches_ppu() {
+        let mut rom_data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        rom_data.resize(rom_data.len() + 2 * 16 * 1024 + 8 * 1024, 0);
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        // the header's vertical mirroring until the game picks one
+        assert_eq!(bus.ppu.mirroring, Mirroring::Vertical);
+
+        bus.mem_write(0xa000, 1);
+        assert_eq!(bus.ppu.mirroring, Mirroring::Horizontal);
+        bus.mem_write(0xa000, 0);
+        assert_eq!(bus.ppu.mirroring, Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_uxrom_chr_ram() {
+        // UxROM with no CHR ROM
+        let mut rom_data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        rom_data.resize(rom_data.len() + 2 * 16 * 1024, 0);
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        assert!(bus.ppu.is_chr_ram);
+
+        // upload one tile to $1230 through PPUADDR/PPUDATA
+        bus.mem_write(0x2006, 0x12);
+        bus.mem_write(0x2006, 0x30);
+        for i in 0..16 {
+            bus.mem_write(0x2007, 0xa0 + i);
+        }
+
+        for i in 0..16 {
+            assert_eq!(bus.ppu.read_chr(0x1230 + i), 0xa0 + i as u8);
+        }
+        // switching the PRG bank leaves CHR RAM alone
+        bus.mem_write(0x8000, 1);
+        assert_eq!(bus.ppu.read_chr(0x1230), 0xa0);
+    }
+
+    #[test]
+    fn test_mapper_state_restores_chr_banks() {
+        let mut rom_data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x04, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        rom_data.resize(rom_data.len() + 2 * 16 * 1024, 0);
+        for bank in 0..4u8 {
+            rom_data.extend(vec![bank + 1; 8 * 1024]);
+        }
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        bus.mem_write(0x8000, 2);
+        let state = bus.save_mapper_state();
+
+        bus.mem_write(0x8000, 0);
+        assert_eq!(bus.ppu.vram_peek(0x0000), 1);
+
+        bus.load_mapper_state(&state).unwrap();
+        assert_eq!(bus.ppu.vram_peek(0x0000), 3);
+    }
+
+    #[test]
+    fn test_reset_mapper_restores_chr_banks() {
+        let mut rom_data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x04, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        rom_data.resize(rom_data.len() + 2 * 16 * 1024, 0);
+        for bank in 0..4u8 {
+            rom_data.extend(vec![bank + 1; 8 * 1024]);
+        }
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let mut bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        bus.mem_write(0x8000, 2);
+        assert_eq!(bus.ppu.vram_peek(0x0000), 3);
+
+        bus.reset_mapper();
+        assert_eq!(bus.ppu.vram_peek(0x0000), 1);
+    }
+
+    #[test]
+    fn test_ppu_cycles() {
+        let mut rom_data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        // NOPs everywhere, reset vector at $8000
+        rom_data.resize(rom_data.len() + 2 * 16 * 1024, 0xea);
+        let len = rom_data.len();
+        rom_data[len - 4] = 0x00;
+        rom_data[len - 3] = 0x80;
+        rom_data.resize(len + 8 * 1024, 0);
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let mut cpu = CPU::new(Bus::new(
+            rom,
+            |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {},
+        ));
+        cpu.reset();
+        let before = cpu.ppu_cycles();
+
+        cpu.step();
+
+        assert_eq!(cpu.ppu_cycles() - before, 6);
+        assert_eq!(cpu.bus.ppu.absolute_cycle_count, cpu.ppu_cycles());
+    }
+
+    #[test]
+    fn test_first_vblank_nmi_cycle() {
+        let mut rom_data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        // NOPs everywhere, NMI and reset vectors at $8000
+        rom_data.resize(rom_data.len() + 2 * 16 * 1024, 0xea);
+        let len = rom_data.len();
+        rom_data[len - 6..len - 2].copy_from_slice(&[0x00, 0x80, 0x00, 0x80]);
+        rom_data.resize(len + 8 * 1024, 0);
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let mut cpu = CPU::new(Bus::new(
+            rom,
+            |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {},
+        ));
+        cpu.reset();
+        cpu.bus.mem_write(0x2000, 0x80);
+
+        // NOP never touches the stack, only the NMI pushes to it
+        let stack_pointer = cpu.stack_pointer;
+        let mut nmi_cycle = None;
+        while nmi_cycle.is_none() {
+            let before = cpu.get_cycle_count();
+            cpu.step();
+            if cpu.stack_pointer != stack_pointer {
+                nmi_cycle = Some(before);
+            }
+        }
+        // vblank starts after 241 scanlines of 341 PPU dots, 3 dots per CPU cycle
+        assert_eq!(nmi_cycle, Some((241 * 341_u64).div_ceil(3)));
+
+        cpu.reset_cycle_count();
+        assert_eq!(cpu.get_cycle_count(), 0);
+    }
+
+    #[test]
+    fn test_prg_ram_save_and_load() {
+        let mut bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        bus.mem_write(0x6000, 0x12);
+        bus.mem_write(0x7fff, 0x34);
+        assert_eq!(bus.mem_read(0x6000), 0x12);
+        let save = bus.save_prg_ram();
+        assert_eq!(save.len(), 0x2000);
+
+        // a power cycle clears PRG RAM until the save is loaded back
+        let mut bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        assert_eq!(bus.mem_read(0x6000), 0x00);
+        bus.load_prg_ram(&save);
+        assert_eq!(bus.mem_read(0x6000), 0x12);
+        assert_eq!(bus.mem_read(0x7fff), 0x34);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        for addr in [0x2000, 0x2001, 0x2003, 0x2005, 0x2006, 0x4014, 0x4018] {
+            bus.mem_write(0x0000, addr as u8);
+            assert_eq!(bus.mem_read(addr), addr as u8);
+        }
+        // reads drive the bus too
+        bus.mem_write(0x0010, 0x5a);
+        bus.mem_write(0x0000, 0x00);
+        bus.mem_read(0x0010);
+        assert_eq!(bus.mem_read(0x2000), 0x5a);
+        // mirrors of write-only registers
+        assert_eq!(bus.mem_read(0x3ff8), 0x5a);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut bus = Bus::new(test_rom(), |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
+        bus.mem_write(0x0200, 0xab);
+        bus.mem_write(0x02ff, 0xcd);
+        bus.mem_write(0x4014, 0x02);
+
+        assert_eq!(bus.ppu.oam_data[0], 0xab);
+        assert_eq!(bus.ppu.oam_data[255], 0xcd);
+    }
+
+    #[test]
+    fn test_oam_dma_stalls_cpu() {
+        let mut cpu = CPU::new(Bus::new(
+            test_rom(),
+            |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {},
+        ));
+        for i in 0..=255u8 {
+            cpu.mem_write(0x0300 + i as u16, i);
+        }
+        cpu.program_counter = 0x0600;
+        // LDA #$03, STA $4014, STA $4014
+        for (i, byte) in [0xa9, 0x03, 0x8d, 0x14, 0x40, 0x8d, 0x14, 0x40]
+            .iter()
+            .enumerate()
+        {
+            cpu.mem_write(0x0600 + i as u16, *byte);
+        }
+        cpu.step();
+
+        assert_eq!(cpu.step(), 4 + 513);
+        assert_eq!(cpu.bus.ppu.oam_data[0x10], 0x10);
+        assert_eq!(cpu.bus.ppu.oam_data[0xff], 0xff);
+        assert_eq!(cpu.get_cycle_count(), 2 + 4 + 513);
+        assert_eq!(cpu.ppu_cycles(), (2 + 4 + 513) * 3);
+        // the stall crosses scanlines one at a time, 1557 dots = 4 scanlines + 193 dots
+        assert_eq!(cpu.bus.ppu_position(), (4, 193));
+
+        // now on an odd cycle
+        assert_eq!(cpu.step(), 4 + 514);
+        assert_eq!(cpu.get_cycle_count(), 2 + 4 + 513 + 4 + 514);
+    }
+
+    #[test]
+    fn test_trace_timing_after_instructions() {
+        let mut cpu = CPU::new(Bus::new(
+            test_rom(),
+            |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {},
+        ));
+        cpu.program_counter = 0x0600;
+        // LDA #$01, STA $0200, INX
+        for (i, byte) in [0xa9, 0x01, 0x8d, 0x00, 0x02, 0xe8].iter().enumerate() {
+            cpu.mem_write(0x0600 + i as u16, *byte);
+        }
+        for _ in 0..3 {
+            cpu.step();
+        }
+
+        // 2 + 4 + 2 cycles, 3 dots each
+        let (scanline, dot) = cpu.bus.ppu_position();
+        let line = crate::trace::trace(&cpu, cpu.get_cycle_count(), scanline, dot);
+        assert!(line.ends_with("PPU:  0, 24 CYC:8"), "{}", line);
+    }
 }
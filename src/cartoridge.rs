@@ -1,52 +1,395 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
 const PRG_ROM_PAGE_SIZE: usize = 16 * 1024;
 const CHR_ROM_PAGE_SIZE: usize = 8 * 1024;
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Mirroring {
     Vertical,
     Horizontal,
     FourScreen,
+    // all four nametables show the same 1KB of VRAM, switched by mappers like AxROM
+    SingleScreenLower,
+    SingleScreenUpper,
 }
 
+#[derive(Debug, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum RomFormat {
+    iNES1,
+    iNES2,
+    // old dumps with garbage (e.g. "DiskDude!") in header bytes 7-15
+    Archaic,
+}
+
+#[derive(Debug)]
+pub enum RomError {
+    // the first 4 bytes of the file, which should read "NES\x1a"
+    InvalidMagic([u8; 4]),
+    // shorter than the 16 byte header
+    TruncatedHeader,
+    // a header field this emulator can't represent
+    InvalidHeader(String),
+    // NES 2.0 mapper numbers above 255
+    UnsupportedMapper(u16),
+    // sizes in bytes, the header's size against what the file holds
+    TruncatedPrgRom { expected: usize, got: usize },
+    TruncatedChrRom { expected: usize, got: usize },
+    Io(io::Error),
+    // wraps any of the above with the file it came from
+    File(PathBuf, Box<RomError>),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::InvalidMagic(magic) => {
+                write!(
+                    f,
+                    "File is not in iNES file format (starts with {:02X?})",
+                    magic
+                )
+            }
+            RomError::TruncatedHeader => write!(f, "File is smaller than the 16 byte iNES header"),
+            RomError::InvalidHeader(message) => write!(f, "Invalid header: {}", message),
+            RomError::UnsupportedMapper(mapper) => write!(f, "Unsupported mapper {}", mapper),
+            RomError::TruncatedPrgRom { expected, got } => {
+                write!(
+                    f,
+                    "PRG ROM is truncated: header says {} bytes, file has {}",
+                    expected, got
+                )
+            }
+            RomError::TruncatedChrRom { expected, got } => {
+                write!(
+                    f,
+                    "CHR ROM is truncated: header says {} bytes, file has {}",
+                    expected, got
+                )
+            }
+            RomError::Io(err) => write!(f, "{}", err),
+            RomError::File(path, err) => write!(f, "{}: {}", path.display(), err),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
     pub mapper: u8,
     pub screen_mirroring: Mirroring,
+    // battery-backed PRG RAM at $6000-$7FFF that should be saved between sessions
+    pub has_battery: bool,
+    pub format: RomFormat,
+    // the fields below are only filled from NES 2.0 headers, iNES 1.0 leaves them 0
+    pub submapper: u8,
+    // the full 12-bit mapper number, equal to mapper for iNES 1.0
+    pub extended_mapper: u16,
+    // 0 NES/Famicom, 1 Vs. System, 2 PlayChoice-10, otherwise the extended console type
+    pub console_type: u8,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
 }
 
 impl Rom {
-    pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
-            return Err("File is not in iNES file format".to_string());
+    #[deprecated(note = "use Rom::from_bytes")]
+    pub fn new(raw: &[u8]) -> Result<Rom, RomError> {
+        Rom::from_bytes(raw)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Rom, RomError> {
+        let path = path.as_ref();
+        let in_file = |err| RomError::File(path.to_path_buf(), Box::new(err));
+        let raw = std::fs::read(path).map_err(|err| in_file(RomError::Io(err)))?;
+        Rom::from_bytes(&raw).map_err(in_file)
+    }
+
+    pub fn from_bytes(raw: &[u8]) -> Result<Rom, RomError> {
+        if raw.len() >= 4 && raw[0..4] != NES_TAG {
+            let mut magic = [0; 4];
+            magic.copy_from_slice(&raw[0..4]);
+            return Err(RomError::InvalidMagic(magic));
+        }
+        if raw.len() < 16 {
+            return Err(RomError::TruncatedHeader);
         }
 
-        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
-        let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES2.0 format is not supported".to_string());
+        let mut header = [0; 16];
+        header.copy_from_slice(&raw[0..16]);
+        let format = Rom::detect_format(&header);
+        if format == RomFormat::Archaic {
+            header[7..16].fill(0);
         }
-        let four_screen = raw[6] & 0b1000 != 0;
-        let vertical_mirroring = raw[6] & 0b1 != 0;
+
+        let mapper = (header[7] & 0b1111_0000) | (header[6] >> 4);
+        let nes2 = format == RomFormat::iNES2;
+        let extended_mapper = if nes2 {
+            (header[8] as u16 & 0x0f) << 8 | mapper as u16
+        } else {
+            mapper as u16
+        };
+        if extended_mapper > u8::MAX as u16 {
+            return Err(RomError::UnsupportedMapper(extended_mapper));
+        }
+        let four_screen = header[6] & 0b1000 != 0;
+        let vertical_mirroring = header[6] & 0b1 != 0;
         let screen_mirroring = match (four_screen, vertical_mirroring) {
             (true, _) => Mirroring::FourScreen,
             (false, true) => Mirroring::Vertical,
             (false, false) => Mirroring::Horizontal,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        // the exponent-multiplier size notation is only used by a handful of odd dumps
+        if nes2 && (header[9] & 0x0f == 0x0f || header[9] >> 4 == 0x0f) {
+            return Err(RomError::InvalidHeader(
+                "exponent-multiplier ROM sizes are not supported".to_string(),
+            ));
+        }
+        let (prg_rom_pages, chr_rom_pages) = match format {
+            // NES 2.0 keeps the upper 4 bits of the ROM sizes in byte 9
+            RomFormat::iNES2 => (
+                (header[9] as usize & 0x0f) << 8 | header[4] as usize,
+                (header[9] as usize >> 4) << 8 | header[5] as usize,
+            ),
+            RomFormat::iNES1 | RomFormat::Archaic => (header[4] as usize, header[5] as usize),
+        };
+        let prg_rom_size = prg_rom_pages * PRG_ROM_PAGE_SIZE;
+        let chr_rom_size = chr_rom_pages * CHR_ROM_PAGE_SIZE;
 
-        let skip_trainer = raw[6] & 0b100 != 0;
+        let skip_trainer = header[6] & 0b100 != 0;
         let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
+        let available = |start: usize| raw.len().saturating_sub(start);
+        if available(prg_rom_start) < prg_rom_size {
+            return Err(RomError::TruncatedPrgRom {
+                expected: prg_rom_size,
+                got: available(prg_rom_start),
+            });
+        }
+        if available(chr_rom_start) < chr_rom_size {
+            return Err(RomError::TruncatedChrRom {
+                expected: chr_rom_size,
+                got: available(chr_rom_start),
+            });
+        }
 
+        let console_type = match header[7] & 0b11 {
+            3 if nes2 => header[13] & 0x0f,
+            console_type => console_type,
+        };
+        let field = |value: u8| if nes2 { value } else { 0 };
         Ok(Rom {
             prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
             chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
-            mapper: mapper,
-            screen_mirroring: screen_mirroring,
+            mapper,
+            screen_mirroring,
+            has_battery: header[6] & 0b10 != 0,
+            format,
+            submapper: field(header[8] >> 4),
+            extended_mapper,
+            console_type,
+            prg_ram_size: Rom::ram_size(field(header[10] & 0x0f)),
+            prg_nvram_size: Rom::ram_size(field(header[10] >> 4)),
+            chr_ram_size: Rom::ram_size(field(header[11] & 0x0f)),
+            chr_nvram_size: Rom::ram_size(field(header[11] >> 4)),
         })
     }
+
+    // NES 2.0 RAM sizes are shift counts, 64 << n bytes with 0 meaning none
+    fn ram_size(shift: u8) -> usize {
+        match shift {
+            0 => 0,
+            shift => 64 << shift,
+        }
+    }
+
+    // https://www.nesdev.org/wiki/INES#Variant_comparison
+    pub fn detect_format(header: &[u8; 16]) -> RomFormat {
+        match header[7] & 0x0c {
+            0x08 => RomFormat::iNES2,
+            0x00 if header[12..16].iter().all(|b| *b == 0) => RomFormat::iNES1,
+            _ => RomFormat::Archaic,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(flags6: u8, rest: &[u8]) -> [u8; 16] {
+        let mut header = [0; 16];
+        header[0..4].copy_from_slice(&NES_TAG);
+        header[4] = 1;
+        header[5] = 1;
+        header[6] = flags6;
+        header[7..7 + rest.len()].copy_from_slice(rest);
+        header
+    }
+
+    fn rom_data(header: [u8; 16]) -> Vec<u8> {
+        let mut raw = header.to_vec();
+        raw.resize(16 + PRG_ROM_PAGE_SIZE + CHR_ROM_PAGE_SIZE, 0);
+        raw
+    }
+
+    #[test]
+    fn test_detect_ines1() {
+        assert_eq!(Rom::detect_format(&header(0x10, &[])), RomFormat::iNES1);
+        assert_eq!(Rom::detect_format(&header(0x10, &[0x40])), RomFormat::iNES1);
+    }
+
+    #[test]
+    fn test_detect_ines2() {
+        assert_eq!(Rom::detect_format(&header(0x10, &[0x08])), RomFormat::iNES2);
+        // NES 2.0 uses bytes 12-15 for extended fields
+        let nes2 = header(0x10, &[0x08, 0, 0, 0, 0, 0x02, 0x01, 0, 0]);
+        assert_eq!(Rom::detect_format(&nes2), RomFormat::iNES2);
+    }
+
+    #[test]
+    fn test_detect_archaic() {
+        let disk_dude = header(0x10, b"DiskDude!");
+        assert_eq!(Rom::detect_format(&disk_dude), RomFormat::Archaic);
+        // padding bytes left non-zero by early dumps
+        let padded = header(0x10, &[0x00, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(Rom::detect_format(&padded), RomFormat::Archaic);
+    }
+
+    #[test]
+    fn test_archaic_header_ignores_garbage() {
+        let rom = Rom::from_bytes(&rom_data(header(0x11, b"DiskDude!"))).unwrap();
+        assert_eq!(rom.mapper, 1);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_has_battery() {
+        assert!(
+            Rom::from_bytes(&rom_data(header(0x12, &[])))
+                .unwrap()
+                .has_battery
+        );
+        assert!(
+            !Rom::from_bytes(&rom_data(header(0x11, &[])))
+                .unwrap()
+                .has_battery
+        );
+    }
+
+    #[test]
+    fn test_ines2_fields() {
+        // mapper 4 submapper 3, Vs. System given as extended console type 1
+        let nes2 = header(0x40, &[0x0b, 0x30, 0x00, 0x07, 0x09, 0, 0x01]);
+        let rom = Rom::from_bytes(&rom_data(nes2)).unwrap();
+
+        assert_eq!(rom.format, RomFormat::iNES2);
+        assert_eq!(rom.mapper, 4);
+        assert_eq!(rom.extended_mapper, 4);
+        assert_eq!(rom.submapper, 3);
+        assert_eq!(rom.console_type, 1);
+        assert_eq!(rom.prg_ram_size, 8 * 1024);
+        assert_eq!(rom.prg_nvram_size, 0);
+        assert_eq!(rom.chr_ram_size, 32 * 1024);
+        assert_eq!(rom.chr_nvram_size, 0);
+
+        let ines1 = Rom::from_bytes(&rom_data(header(0x40, &[0, 0x30, 0, 0x07]))).unwrap();
+        assert_eq!(ines1.format, RomFormat::iNES1);
+        assert_eq!((ines1.submapper, ines1.prg_ram_size), (0, 0));
+    }
+
+    #[test]
+    fn test_ines2_errors() {
+        let err = Rom::from_bytes(&rom_data(header(0x40, &[0x08, 0x01]))).err();
+        assert!(matches!(err, Some(RomError::UnsupportedMapper(0x104))));
+
+        let err = Rom::from_bytes(&rom_data(header(0x00, &[0x08, 0, 0x0f]))).err();
+        assert!(matches!(err, Some(RomError::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn test_from_bytes_errors() {
+        let err = Rom::from_bytes(b"NES").err().unwrap();
+        assert!(matches!(err, RomError::TruncatedHeader));
+
+        let err = Rom::from_bytes(b"PK\x03\x04 not a rom").err().unwrap();
+        assert!(matches!(err, RomError::InvalidMagic(magic) if magic == *b"PK\x03\x04"));
+        assert_eq!(
+            err.to_string(),
+            "File is not in iNES file format (starts with [50, 4B, 03, 04])"
+        );
+
+        let mut truncated = rom_data(header(0x10, &[]));
+        truncated.pop();
+        let err = Rom::from_bytes(&truncated).err().unwrap();
+        assert!(matches!(
+            err,
+            RomError::TruncatedChrRom {
+                expected: CHR_ROM_PAGE_SIZE,
+                got
+            } if got == CHR_ROM_PAGE_SIZE - 1
+        ));
+        assert_eq!(
+            err.to_string(),
+            "CHR ROM is truncated: header says 8192 bytes, file has 8191"
+        );
+
+        truncated.truncate(16 + 100);
+        let err = Rom::from_bytes(&truncated).err().unwrap();
+        assert!(matches!(
+            err,
+            RomError::TruncatedPrgRom {
+                expected: PRG_ROM_PAGE_SIZE,
+                got: 100
+            }
+        ));
+    }
+
+    #[test]
+    fn test_from_file() {
+        let path = std::env::temp_dir().join("rustnes_test_from_file.nes");
+        std::fs::write(&path, rom_data(header(0x31, &[]))).unwrap();
+        let rom = Rom::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_from_file_errors_name_the_file() {
+        let path = std::env::temp_dir().join("rustnes_test_from_file_missing.nes");
+        let err = Rom::from_file(&path).err().unwrap();
+        assert!(matches!(err, RomError::File(_, ref inner) if matches!(**inner, RomError::Io(_))));
+        assert!(err.to_string().starts_with(&path.display().to_string()));
+
+        let path = std::env::temp_dir().join("rustnes_test_from_file_invalid.nes");
+        std::fs::write(&path, b"not a rom").unwrap();
+        let err = Rom::from_file(&path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{}: File is not in iNES file format (starts with [6E, 6F, 74, 20])",
+                path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_ines2_rom() {
+        let rom = Rom::from_bytes(&rom_data(header(0x20, &[0x38]))).unwrap();
+        assert_eq!(rom.mapper, 0x32);
+        assert_eq!(rom.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+    }
 }
@@ -1,6 +1,8 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 bitflags! {
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct ControlRegister: u8 {
         const NAMETABLE1 = 0b0000_0001;
         const NAMETABLE2 = 0b0000_0010;
@@ -13,6 +15,12 @@ bitflags! {
     }
 }
 
+impl Default for ControlRegister {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ControlRegister {
     pub fn new() -> Self {
         ControlRegister::from_bits_truncate(0b0000_0000)
@@ -30,11 +38,30 @@ impl ControlRegister {
         *self = ControlRegister::from_bits_truncate(data);
     }
 
-    pub fn generate_vblank_status(&mut self) -> bool {
-        let result = self.contains(ControlRegister::GENERATE_NMI);
-        self.set(ControlRegister::GENERATE_NMI, true);
-        // println!("generate_vbrank_status result:{}", result); // TODO
-        result
+    pub fn generate_vblank_status(&self) -> bool {
+        self.contains(ControlRegister::GENERATE_NMI)
+    }
+
+    pub fn base_nametable_addr(&self) -> u16 {
+        match self.nametable_index() {
+            0 => 0x2000,
+            1 => 0x2400,
+            2 => 0x2800,
+            3 => 0x2c00,
+            _ => panic!("not possible"),
+        }
+    }
+
+    pub fn nametable_index(&self) -> u8 {
+        self.bits() & 0b11
+    }
+
+    pub fn sprite_height(&self) -> u8 {
+        if self.contains(ControlRegister::STRIPE_SIZE) {
+            16
+        } else {
+            8
+        }
     }
 
     pub fn bknd_pattern_addr(&self) -> u16 {
@@ -45,11 +72,92 @@ impl ControlRegister {
         }
     }
 
+    // 8x8 sprites only, 8x16 sprites pick the pattern table with bit 0 of the tile index
     pub fn sprt_pattern_addr(&self) -> u16 {
-        if self.contains(ControlRegister::STRIPE_SIZE) {
+        if self.contains(ControlRegister::SPRITE_PATTERN_ADDR) {
             0x1000
         } else {
             0
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_base_nametable_addr() {
+        let mut ctrl = ControlRegister::new();
+        ctrl.update(0b0000_0000);
+        assert_eq!(ctrl.base_nametable_addr(), 0x2000);
+        ctrl.update(0b0000_0001);
+        assert_eq!(ctrl.base_nametable_addr(), 0x2400);
+        ctrl.update(0b0000_0010);
+        assert_eq!(ctrl.base_nametable_addr(), 0x2800);
+        ctrl.update(0b0000_0011);
+        assert_eq!(ctrl.base_nametable_addr(), 0x2c00);
+        ctrl.update(0b1111_1100);
+        assert_eq!(ctrl.base_nametable_addr(), 0x2000);
+    }
+
+    #[test]
+    fn test_nametable_index() {
+        let mut ctrl = ControlRegister::new();
+        for index in 0..4 {
+            ctrl.update(0b1010_1000 | index);
+            assert_eq!(ctrl.nametable_index(), index);
+            assert_eq!(ctrl.base_nametable_addr(), 0x2000 + index as u16 * 0x400);
+        }
+    }
+
+    #[test]
+    fn test_sprite_height() {
+        let mut ctrl = ControlRegister::new();
+        assert_eq!(ctrl.sprite_height(), 8);
+        ctrl.update(0b0010_0000);
+        assert_eq!(ctrl.sprite_height(), 16);
+        // the sprite pattern table bit doesn't change the size
+        ctrl.update(0b0000_1000);
+        assert_eq!(ctrl.sprite_height(), 8);
+    }
+
+    #[test]
+    fn test_vram_addr_increment() {
+        let mut ctrl = ControlRegister::new();
+        assert_eq!(ctrl.vram_addr_increment(), 1);
+        ctrl.update(0b0000_0100);
+        assert_eq!(ctrl.vram_addr_increment(), 32);
+    }
+
+    #[test]
+    fn test_bknd_pattern_addr() {
+        let mut ctrl = ControlRegister::new();
+        assert_eq!(ctrl.bknd_pattern_addr(), 0);
+        ctrl.update(0b0001_0000);
+        assert_eq!(ctrl.bknd_pattern_addr(), 0x1000);
+    }
+
+    #[test]
+    fn test_sprt_pattern_addr() {
+        let mut ctrl = ControlRegister::new();
+        assert_eq!(ctrl.sprt_pattern_addr(), 0);
+        ctrl.update(0b0000_1000);
+        assert_eq!(ctrl.sprt_pattern_addr(), 0x1000);
+        // sprite size doesn't choose the pattern table
+        ctrl.update(0b0010_0000);
+        assert_eq!(ctrl.sprt_pattern_addr(), 0);
+    }
+
+    #[test]
+    fn test_generate_vblank_status_does_not_mutate() {
+        let ctrl = ControlRegister::new();
+        assert!(!ctrl.generate_vblank_status());
+        assert!(!ctrl.generate_vblank_status());
+        assert_eq!(ctrl.bits(), 0);
+
+        let mut ctrl = ControlRegister::new();
+        ctrl.update(0b1000_0000);
+        assert!(ctrl.generate_vblank_status());
+    }
+}
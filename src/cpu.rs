@@ -1,9 +1,11 @@
-use crate::bus::Bus;
 use crate::opcode::{self, OpCode};
+use crate::trace::trace;
 use core::panic;
-use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
 
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug)]
 #[allow(non_camel_case_types)]
@@ -17,11 +19,12 @@ pub enum AddressingMode {
     Absolute_Y,
     Indirect_X,
     Indirect_Y,
+    Accumulator,
     NoneAddressing,
 }
 
 bitflags! {
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     pub struct CpuFlags:u8 {
         const CARRY = 0b0000_0001;
         const ZERO = 0b0000_0010;
@@ -41,11 +44,18 @@ const INTERRUP_VECTOR_ADDR: u16 = 0xfffe;
 pub trait Memory {
     fn mem_read(&mut self, addr: u16) -> u8;
     fn mem_write(&mut self, addr: u16, data: u8);
+    // read without side effects (PPU read buffer, joypad shift, ...) for debugging and tracing
+    fn mem_peek(&self, addr: u16) -> u8;
 
     fn mem_read_u16(&mut self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
         let hi = self.mem_read(pos + 1) as u16;
-        (hi << 8) | (lo as u16)
+        (hi << 8) | lo
+    }
+    fn mem_peek_u16(&self, pos: u16) -> u16 {
+        let lo = self.mem_peek(pos) as u16;
+        let hi = self.mem_peek(pos + 1) as u16;
+        (hi << 8) | lo
     }
     fn mem_write_u16(&mut self, pos: u16, data: u16) {
         let hi = (data >> 8) as u8;
@@ -54,32 +64,106 @@ pub trait Memory {
         self.mem_write(pos + 1, hi);
     }
 }
-pub struct CPU<'a> {
+pub trait CpuBus: Memory {
+    fn tick(&mut self, cycles: usize);
+    // true once per NMI the PPU raised, clearing the pending request
+    fn take_nmi(&mut self) -> bool;
+    // IRQ is level triggered, it stays set until the source is acknowledged
+    fn irq_status(&self) -> bool;
+    fn mapper_irq_acknowledge(&mut self);
+    // (scanline, dot) of the PPU, for the trace log
+    fn ppu_position(&self) -> (u16, usize);
+    // CPU cycles the last instruction halted the CPU for (OAM DMA), clearing them
+    fn take_dma_stall(&mut self) -> usize;
+}
+
+// registers only, for reporting CPU state once the bus is gone
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuSnapshot {
+    pub register_a: u8,
+    pub register_x: u8,
+    pub register_y: u8,
+    pub status: u8,
+    pub program_counter: u16,
+    pub stack_pointer: u8,
+}
+
+// everything but the bus, for save states
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CpuState {
+    pub register_a: u8,
+    pub register_x: u8,
+    pub register_y: u8,
+    pub status: CpuFlags,
+    pub program_counter: u16,
+    pub stack_pointer: u8,
+    pub total_cycles: u64,
+    pub irq_line: bool,
+}
+
+pub type BreakpointHandler<B> = Box<dyn FnMut(&mut CPU<B>)>;
+
+pub struct CPU<B: CpuBus> {
     pub register_a: u8,
     pub register_x: u8,
     pub register_y: u8,
     pub status: CpuFlags,
     pub program_counter: u16,
     pub stack_pointer: u8,
-    pub bus: Bus<'a>,
-    // pub extra_cycles: usize,
+    // CPU cycles since reset
+    total_cycles: u64,
+    // IRQ line driven from outside the bus, e.g. by a test or an expansion device
+    irq_line: bool,
+    // stop run_with_callback at BRK instead of executing it, for test programs
+    pub halt_on_brk: bool,
+    pub breakpoints: Vec<u16>,
+    // called by run_with_callback before an instruction at a breakpoint runs,
+    // without one a breakpoint pauses the run
+    pub on_breakpoint: Option<BreakpointHandler<B>>,
+    // set from on_breakpoint or the run callback to return from run_with_callback before the
+    // next instruction, the next run resumes from there
+    pub paused: bool,
+    pub bus: B,
 }
 
-impl Memory for CPU<'_> {
+impl<B: CpuBus + fmt::Debug> fmt::Debug for CPU<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CPU")
+            .field("register_a", &self.register_a)
+            .field("register_x", &self.register_x)
+            .field("register_y", &self.register_y)
+            .field("status", &self.status)
+            .field("program_counter", &self.program_counter)
+            .field("stack_pointer", &self.stack_pointer)
+            .field("total_cycles", &self.total_cycles)
+            .field("irq_line", &self.irq_line)
+            .field("halt_on_brk", &self.halt_on_brk)
+            .field("breakpoints", &self.breakpoints)
+            .field("paused", &self.paused)
+            .field("bus", &self.bus)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B: CpuBus> Memory for CPU<B> {
     fn mem_read(&mut self, addr: u16) -> u8 {
         let d = self.bus.mem_read(addr);
         // println!("mem_read: addr:0x{:04x}, data:0x{:02x}", addr, d); // TODO
-        return d;
+        d
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
         // println!("mem_write: addr:0x{:04x}, data:0x{:02x}", addr, data); // TODO
         self.bus.mem_write(addr, data);
     }
+
+    fn mem_peek(&self, addr: u16) -> u8 {
+        self.bus.mem_peek(addr)
+    }
 }
 
-impl<'a> CPU<'a> {
-    pub fn new<'b>(bus: Bus<'b>) -> CPU<'b> {
+impl<B: CpuBus> CPU<B> {
+    pub fn new(bus: B) -> CPU<B> {
         CPU {
             register_a: 0,
             register_x: 0,
@@ -87,7 +171,13 @@ impl<'a> CPU<'a> {
             status: CpuFlags::from_bits_truncate(INITIAL_STATUS),
             program_counter: 0x8000,
             stack_pointer: INITIAL_STACK,
-            bus: bus,
+            total_cycles: 0,
+            irq_line: false,
+            halt_on_brk: false,
+            breakpoints: Vec::new(),
+            on_breakpoint: None,
+            paused: false,
+            bus,
         }
     }
 
@@ -95,7 +185,7 @@ impl<'a> CPU<'a> {
         // self.memory[0x0600..(0x0600 + program.len())].copy_from_slice(&program[..]);
         // self.mem_write_u16(0xfffc, 0x0600);
         for i in 0..(program.len() as u16) {
-            self.mem_write(0x0000 + i, program[i as usize]);
+            self.mem_write(i, program[i as usize]);
         }
         self.mem_write_u16(0xfffc, 0x8600);
     }
@@ -108,6 +198,15 @@ impl<'a> CPU<'a> {
         self.status = CpuFlags::from_bits_truncate(INITIAL_STATUS);
 
         self.program_counter = self.mem_read_u16(0xFFFC);
+        self.reset_cycle_count();
+    }
+
+    pub fn get_cycle_count(&self) -> u64 {
+        self.total_cycles
+    }
+
+    pub fn reset_cycle_count(&mut self) {
+        self.total_cycles = 0;
     }
 
     pub fn load_and_run(&mut self, program: Vec<u8>) {
@@ -120,6 +219,26 @@ impl<'a> CPU<'a> {
         self.run_with_callback(|_| {});
     }
 
+    pub fn run_with_trace<W: Write>(&mut self, output: W) {
+        self.run_with_trace_and_callback(output, |_| {});
+    }
+
+    pub fn run_with_trace_and_callback<W: Write, F>(&mut self, mut output: W, mut callback: F)
+    where
+        F: FnMut(&mut CPU<B>),
+    {
+        self.run_with_callback(move |cpu| {
+            let (scanline, dot) = cpu.bus.ppu_position();
+            writeln!(
+                output,
+                "{}",
+                trace(cpu, cpu.get_cycle_count(), scanline, dot)
+            )
+            .unwrap();
+            callback(cpu);
+        });
+    }
+
     fn interrupt_nmi(&mut self) {
         // println!("interrupt nmi!!!!!!!!!!!!!!!!!!!"); // TODO
         self.stack_push_u16(self.program_counter);
@@ -132,121 +251,227 @@ impl<'a> CPU<'a> {
         self.stack_push(flag.bits());
         self.status.insert(CpuFlags::INTERRUPT_DISABLE);
         // println!("new status:{:04b}", self.status);
-        self.bus.tick(2);
+        self.tick(2);
         self.program_counter = self.mem_read_u16(0xfffa);
     }
 
+    fn interrupt_irq(&mut self) {
+        self.stack_push_u16(self.program_counter);
+        let mut flag = self.status.clone();
+        flag.set(CpuFlags::BREAK, false);
+        flag.set(CpuFlags::RESERVED, true);
+
+        self.stack_push(flag.bits());
+        self.status.insert(CpuFlags::INTERRUPT_DISABLE);
+        self.tick(2);
+        self.program_counter = self.mem_read_u16(0xfffe);
+        self.bus.mapper_irq_acknowledge();
+    }
+
+    // IRQ is level triggered, the line stays active until the caller clears it
+    pub fn set_irq(&mut self, active: bool) {
+        self.irq_line = active;
+    }
+
+    fn poll_interrupts(&mut self) {
+        if self.bus.take_nmi() {
+            self.interrupt_nmi();
+        } else if (self.irq_line || self.bus.irq_status())
+            && !self.status.contains(CpuFlags::INTERRUPT_DISABLE)
+        {
+            self.interrupt_irq();
+        }
+    }
+
     pub fn run_with_callback<F>(&mut self, mut callback: F)
     where
-        F: FnMut(&mut CPU),
+        F: FnMut(&mut CPU<B>),
     {
-        let ref opcodes: HashMap<u8, &'static OpCode> = *opcode::OPECODE_MAP;
+        // resuming from a pause must not stop at the same breakpoint again
+        let mut resuming = std::mem::take(&mut self.paused);
         loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt_nmi();
+            self.poll_interrupts();
+            if !resuming && self.breakpoints.contains(&self.program_counter) {
+                self.hit_breakpoint();
+                if self.paused {
+                    return;
+                }
             }
+            resuming = false;
             callback(self);
-
-            let code = self.mem_read(self.program_counter);
-            self.debug(code); // TODO
-                              // self.bus.show_ppu(); // TODO
-            self.program_counter += 1;
-            let before_program_counter = self.program_counter;
-
-            let opcode = opcodes
-                .get(&code)
-                .expect(&format!("OpCode {:x} is not recognized", code));
-            match code {
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
-                0x0a => self.asl_accumulator(),
-                0x06 | 0x16 | 0x0e | 0x1e => {
-                    self.asl(&opcode.mode);
-                }
-                0x90 => self.bcc(),
-                0xb0 => self.bcs(),
-                0xf0 => self.beq(),
-                0x24 | 0x2c => self.bit(&opcode.mode),
-                0x30 => self.bmi(),
-                0xd0 => self.bne(),
-                0x10 => self.bpl(),
-                0x00 => self.brk(),
-                0x50 => self.bvc(),
-                0x70 => self.bvs(),
-                0x18 => self.clc(),
-                0xd8 => self.cld(),
-                0x58 => self.cli(),
-                0xb8 => self.clv(),
-                0xd1 | 0xc1 | 0xd9 | 0xdd | 0xcd | 0xd5 | 0xc5 | 0xc9 => self.cmp(&opcode.mode),
-                0xe0 | 0xe4 | 0xec => self.cpx(&opcode.mode),
-                0xc0 | 0xc4 | 0xcc => self.cpy(&opcode.mode),
-                0xc6 | 0xd6 | 0xce | 0xde => self.dec(&opcode.mode),
-                0xca => self.dex(),
-                0x88 => self.dey(),
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode),
-                0xe6 | 0xf6 | 0xee | 0xfe => self.inc(&opcode.mode),
-                0xe8 => self.inx(),
-                0xc8 => self.iny(),
-                0x4c => self.jmp_absolute(),
-                0x6c => self.jmp(),
-                0x20 => self.jsr(),
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => self.lda(&opcode.mode),
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => self.ldx(&opcode.mode),
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => self.ldy(&opcode.mode),
-                0x4a => self.lsr_accumulator(),
-                0x46 | 0x56 | 0x4e | 0x5e => {
-                    self.lsr(&opcode.mode);
-                }
-                0xea => self.nop(),
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
-                0x48 => self.pha(),
-                0x08 => self.php(),
-                0x68 => self.pla(),
-                0x28 => self.plp(),
-                0x2a => self.rol_accumulate(),
-                0x26 | 0x36 | 0x2e | 0x3e => {
-                    self.rol(&opcode.mode);
-                }
-                0x6a => self.ror_accumulator(),
-                0x66 | 0x76 | 0x6e | 0x7e => {
-                    self.ror(&opcode.mode);
-                }
-                0x40 => self.rti(),
-                0x60 => self.rts(),
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => self.sbc(&opcode.mode),
-                0x38 => self.sec(),
-                0xf8 => self.sed(),
-                0x78 => self.sei(),
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode),
-                0x86 | 0x96 | 0x8e => self.stx(&opcode.mode),
-                0x84 | 0x94 | 0x8c => self.sty(&opcode.mode),
-                0xaa => self.tax(),
-                0xa8 => self.tay(),
-                0xba => self.tsx(),
-                0x8a => self.txa(),
-                0x9a => self.txs(),
-                0x98 => self.tya(),
-                0xa3 | 0xa7 | 0xaf | 0xb3 | 0xb7 | 0xbf => self.lax(&opcode.mode),
-                0x83 | 0x87 | 0x8f | 0x97 => self.sax(&opcode.mode),
-                0xeb => self.sbc(&opcode.mode),
-                0xc3 | 0xc7 | 0xcf | 0xd3 | 0xd7 | 0xdb | 0xdf => self.dcp(&opcode.mode),
-                0xe3 | 0xe7 | 0xef | 0xf3 | 0xf7 | 0xfb | 0xff => self.isc(&opcode.mode),
-                0x03 | 0x07 | 0x17 | 0x0f | 0x1f | 0x1b | 0x13 => self.slo(&opcode.mode),
-                0x27 | 0x37 | 0x2f | 0x3f | 0x3b | 0x23 | 0x33 => self.rla(&opcode.mode),
-                0x47 | 0x57 | 0x4f | 0x5f | 0x5b | 0x43 | 0x53 => self.sre(&opcode.mode),
-                0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => self.rra(&opcode.mode),
-                0x04 | 0x44 | 0x64 | 0x0c | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x1a
-                | 0x3a | 0x5a | 0x7a | 0xda | 0xfa | 0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 | 0x1c
-                | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => self.nop(),
-                _ => panic!("not arrowed operation code."),
+            if self.paused {
+                return;
+            }
+            if self.halt_on_brk && self.mem_peek(self.program_counter) == 0x00 {
+                return;
             }
+            self.execute_instruction();
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
 
-            self.bus.tick(opcode.cycle as usize);
-            self.bus.print_cycle();
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.retain(|&breakpoint| breakpoint != addr);
+    }
+
+    fn hit_breakpoint(&mut self) {
+        // taken out for the call since the handler gets the CPU mutably
+        if let Some(mut handler) = self.on_breakpoint.take() {
+            handler(self);
+            // keep a handler the callback installed in its place
+            self.on_breakpoint.get_or_insert(handler);
+        } else {
+            self.paused = true;
+        }
+    }
+
+    // service a pending NMI or IRQ, then execute one instruction, returning the cycles spent
+    pub fn step(&mut self) -> usize {
+        let start = self.total_cycles;
+        self.poll_interrupts();
+        self.execute_instruction();
+        (self.total_cycles - start) as usize
+    }
 
-            if before_program_counter == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
+    // Runs whole instructions until n cycles have passed. The last instruction can end a few
+    // cycles past the budget, the cycles actually run are returned.
+    pub fn run_for_cycles(&mut self, n: u64) -> u64 {
+        let start = self.total_cycles;
+        while self.total_cycles - start < n {
+            if self.halt_on_brk && self.mem_peek(self.program_counter) == 0x00 {
+                break;
             }
+            self.step();
+        }
+        self.total_cycles - start
+    }
+
+    // the opcode at the program counter, read without side effects
+    pub fn peek_instruction(&self) -> (u8, &'static OpCode) {
+        let code = self.mem_peek(self.program_counter);
+        let opcode = opcode::OPCODE_TABLE[code as usize]
+            .unwrap_or_else(|| panic!("OpCode {:x} is not recognized", code));
+        (code, opcode)
+    }
+
+    fn tick(&mut self, cycles: usize) {
+        self.total_cycles += cycles as u64;
+        self.bus.tick(cycles);
+    }
+
+    fn execute_instruction(&mut self) {
+        let code = self.mem_read(self.program_counter);
+        // self.debug(code); // TODO
+        // self.bus.show_ppu(); // TODO
+        self.program_counter += 1;
+        let before_program_counter = self.program_counter;
+
+        let opcode = opcode::OPCODE_TABLE[code as usize]
+            .unwrap_or_else(|| panic!("OpCode {:x} is not recognized", code));
+        // computed before the instruction changes the registers the address depends on
+        let page_crossed =
+            opcode.has_page_cross_penalty() && self.get_operand_address(&opcode.mode).1;
+        match code {
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
+            0x0a => self.asl_accumulator(),
+            0x06 | 0x16 | 0x0e | 0x1e => {
+                self.asl(&opcode.mode);
+            }
+            0x90 => self.bcc(),
+            0xb0 => self.bcs(),
+            0xf0 => self.beq(),
+            0x24 | 0x2c => self.bit(&opcode.mode),
+            0x30 => self.bmi(),
+            0xd0 => self.bne(),
+            0x10 => self.bpl(),
+            0x00 => self.brk(),
+            0x50 => self.bvc(),
+            0x70 => self.bvs(),
+            0x18 => self.clc(),
+            0xd8 => self.cld(),
+            0x58 => self.cli(),
+            0xb8 => self.clv(),
+            0xd1 | 0xc1 | 0xd9 | 0xdd | 0xcd | 0xd5 | 0xc5 | 0xc9 => self.cmp(&opcode.mode),
+            0xe0 | 0xe4 | 0xec => self.cpx(&opcode.mode),
+            0xc0 | 0xc4 | 0xcc => self.cpy(&opcode.mode),
+            0xc6 | 0xd6 | 0xce | 0xde => self.dec(&opcode.mode),
+            0xca => self.dex(),
+            0x88 => self.dey(),
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode),
+            0xe6 | 0xf6 | 0xee | 0xfe => self.inc(&opcode.mode),
+            0xe8 => self.inx(),
+            0xc8 => self.iny(),
+            0x4c => self.jmp_absolute(),
+            0x6c => self.jmp(),
+            0x20 => self.jsr(),
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => self.lda(&opcode.mode),
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => self.ldx(&opcode.mode),
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => self.ldy(&opcode.mode),
+            0x4a => self.lsr_accumulator(),
+            0x46 | 0x56 | 0x4e | 0x5e => {
+                self.lsr(&opcode.mode);
+            }
+            0xea => self.nop(),
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
+            0x48 => self.pha(),
+            0x08 => self.php(),
+            0x68 => self.pla(),
+            0x28 => self.plp(),
+            0x2a => self.rol_accumulate(),
+            0x26 | 0x36 | 0x2e | 0x3e => {
+                self.rol(&opcode.mode);
+            }
+            0x6a => self.ror_accumulator(),
+            0x66 | 0x76 | 0x6e | 0x7e => {
+                self.ror(&opcode.mode);
+            }
+            0x40 => self.rti(),
+            0x60 => self.rts(),
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => self.sbc(&opcode.mode),
+            0x38 => self.sec(),
+            0xf8 => self.sed(),
+            0x78 => self.sei(),
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode),
+            0x86 | 0x96 | 0x8e => self.stx(&opcode.mode),
+            0x84 | 0x94 | 0x8c => self.sty(&opcode.mode),
+            0xaa => self.tax(),
+            0xa8 => self.tay(),
+            0xba => self.tsx(),
+            0x8a => self.txa(),
+            0x9a => self.txs(),
+            0x98 => self.tya(),
+            0xa3 | 0xa7 | 0xaf | 0xb3 | 0xb7 | 0xbf => self.lax(&opcode.mode),
+            0x83 | 0x87 | 0x8f | 0x97 => self.sax(&opcode.mode),
+            0xeb => self.sbc(&opcode.mode),
+            0xc3 | 0xc7 | 0xcf | 0xd3 | 0xd7 | 0xdb | 0xdf => self.dcp(&opcode.mode),
+            0xe3 | 0xe7 | 0xef | 0xf3 | 0xf7 | 0xfb | 0xff => self.isc(&opcode.mode),
+            0x03 | 0x07 | 0x17 | 0x0f | 0x1f | 0x1b | 0x13 => self.slo(&opcode.mode),
+            0x27 | 0x37 | 0x2f | 0x3f | 0x3b | 0x23 | 0x33 => self.rla(&opcode.mode),
+            0x47 | 0x57 | 0x4f | 0x5f | 0x5b | 0x43 | 0x53 => self.sre(&opcode.mode),
+            0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => self.rra(&opcode.mode),
+            0x04 | 0x44 | 0x64 | 0x0c | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x1a | 0x3a
+            | 0x5a | 0x7a | 0xda | 0xfa | 0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 | 0x1c | 0x3c | 0x5c
+            | 0x7c | 0xdc | 0xfc => self.nop(),
+            _ => panic!("not arrowed operation code."),
+        }
+
+        if page_crossed {
+            self.tick(1);
+        }
+        self.tick(opcode.cycle as usize);
+        // one cycle at a time so the PPU sees every scanline of a long stall
+        for _ in 0..self.bus.take_dma_stall() {
+            self.tick(1);
+        }
+
+        if before_program_counter == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
         }
     }
 
@@ -265,7 +490,7 @@ impl<'a> CPU<'a> {
         // println!("0x{:02x} st:0b{:08b}", code, self.status) // TODO
     }
 
-    pub fn get_operand_address(&mut self, mode: &AddressingMode) -> (u16, bool) {
+    pub fn get_operand_address(&self, mode: &AddressingMode) -> (u16, bool) {
         match mode {
             AddressingMode::Immediate => (self.program_counter, false),
             _ => self.get_absolute_address(mode, self.program_counter),
@@ -273,51 +498,50 @@ impl<'a> CPU<'a> {
     }
 
     fn is_page_crossed(&self, addr1: u16, addr2: u16) -> bool {
-        let page_crossed = (addr1 & 0xFF00) != (addr2 & 0xFF);
-        page_crossed
+        (addr1 & 0xFF00) != (addr2 & 0xFF00)
     }
 
-    pub fn get_absolute_address(&mut self, mode: &AddressingMode, addr: u16) -> (u16, bool) {
+    pub fn get_absolute_address(&self, mode: &AddressingMode, addr: u16) -> (u16, bool) {
         match mode {
             // `page` is 256byte memory region.
             // for ex. 0page:0x0000 ~ 0x00ff, 1page:0x0100 ~ 0x01ff, ...
             // ZeroPage addressing uses only the first 256 bytes of memory, where the address is in the instruction
-            AddressingMode::ZeroPage => (self.mem_read(addr) as u16, false),
-            AddressingMode::Absolute => (self.mem_read_u16(addr), false),
+            AddressingMode::ZeroPage => (self.mem_peek(addr) as u16, false),
+            AddressingMode::Absolute => (self.mem_peek_u16(addr), false),
             AddressingMode::ZeroPage_X => {
-                let pos = self.mem_read(addr);
+                let pos = self.mem_peek(addr);
                 let addr = pos.wrapping_add(self.register_x) as u16;
                 (addr, false)
             }
             AddressingMode::ZeroPage_Y => {
-                let pos = self.mem_read(addr);
+                let pos = self.mem_peek(addr);
                 let addr = pos.wrapping_add(self.register_y) as u16;
                 (addr, false)
             }
             AddressingMode::Absolute_X => {
-                let base = self.mem_read_u16(addr);
+                let base = self.mem_peek_u16(addr);
                 let addr = base.wrapping_add(self.register_x as u16);
                 (addr, self.is_page_crossed(base, addr))
             }
             AddressingMode::Absolute_Y => {
-                let base = self.mem_read_u16(addr);
+                let base = self.mem_peek_u16(addr);
                 let addr = base.wrapping_add(self.register_y as u16);
                 (addr, self.is_page_crossed(base, addr))
             }
             AddressingMode::Indirect_X => {
-                let base = self.mem_read(addr);
+                let base = self.mem_peek(addr);
 
-                let ptr = (base as u8).wrapping_add(self.register_x);
-                let lo = self.mem_read(ptr as u16);
-                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
+                let ptr = base.wrapping_add(self.register_x);
+                let lo = self.mem_peek(ptr as u16);
+                let hi = self.mem_peek(ptr.wrapping_add(1) as u16);
                 let addr = (hi as u16) << 8 | lo as u16;
                 (addr, false)
             }
             AddressingMode::Indirect_Y => {
-                let base = self.mem_read(addr);
+                let base = self.mem_peek(addr);
 
-                let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
+                let lo = self.mem_peek(base as u16);
+                let hi = self.mem_peek(base.wrapping_add(1) as u16);
                 let deref_base = (hi as u16) << 8 | lo as u16;
                 let deref = deref_base.wrapping_add(self.register_y as u16);
                 (deref, self.is_page_crossed(deref_base, deref))
@@ -329,24 +553,16 @@ impl<'a> CPU<'a> {
     }
 
     fn adc(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let data = self.mem_read(addr);
 
         self.set_register_a_with_flags(data);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn and(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
-        self.register_a = self.register_a & self.mem_read(addr);
+        let (addr, _) = self.get_operand_address(mode);
+        self.register_a &= self.mem_read(addr);
         self.update_zero_and_negative_flags(self.register_a);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn asl(&mut self, mode: &AddressingMode) -> u8 {
@@ -372,7 +588,7 @@ impl<'a> CPU<'a> {
         } else {
             self.status.remove(CpuFlags::CARRY);
         }
-        self.register_a = self.register_a << 1;
+        self.register_a <<= 1;
         self.update_zero_and_negative_flags(self.register_a);
     }
 
@@ -418,11 +634,15 @@ impl<'a> CPU<'a> {
         self.branch(!self.status.contains(CpuFlags::NEGATIVE));
     }
 
+    // Like IRQ, but BREAK is set in the pushed status. The program counter is already past
+    // the opcode, and the return address skips one more padding byte.
     fn brk(&mut self) {
-        self.stack_push_u16(self.program_counter.wrapping_add(2));
-        self.status.set(CpuFlags::BREAK, true);
-        self.status.set(CpuFlags::INTERRUPT_DISABLE, true);
-        self.stack_push(self.status.bits());
+        self.stack_push_u16(self.program_counter.wrapping_add(1));
+        let mut flag = self.status.clone();
+        flag.set(CpuFlags::BREAK, true);
+        flag.set(CpuFlags::RESERVED, true);
+        self.stack_push(flag.bits());
+        self.status.insert(CpuFlags::INTERRUPT_DISABLE);
         self.program_counter = self.mem_read_u16(INTERRUP_VECTOR_ADDR);
     }
 
@@ -451,7 +671,7 @@ impl<'a> CPU<'a> {
     }
 
     fn cmp(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let data = self.mem_read(addr);
         if self.register_a >= data {
             self.status.insert(CpuFlags::CARRY);
@@ -459,10 +679,6 @@ impl<'a> CPU<'a> {
             self.status.remove(CpuFlags::CARRY);
         }
         self.update_zero_and_negative_flags(self.register_a.wrapping_sub(data));
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn cpx(&mut self, mode: &AddressingMode) {
@@ -506,14 +722,10 @@ impl<'a> CPU<'a> {
     }
 
     fn eor(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let data = self.mem_read(addr);
-        self.register_a = self.register_a ^ data;
+        self.register_a ^= data;
         self.update_zero_and_negative_flags(self.register_a); // [TODO] maybe need.
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn inc(&mut self, mode: &AddressingMode) {
@@ -570,36 +782,24 @@ impl<'a> CPU<'a> {
     }
 
     fn lda(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let value = self.mem_read(addr);
         self.register_a = value;
         self.update_zero_and_negative_flags(value);
 
         // println!("addr:{:02x}, val:{}, st:0b{:08b}", addr, value, self.status); // TODO
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn ldx(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         self.register_x = self.mem_read(addr);
         self.update_zero_and_negative_flags(self.register_x);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn ldy(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         self.register_y = self.mem_read(addr);
         self.update_zero_and_negative_flags(self.register_y);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn lsr(&mut self, mode: &AddressingMode) -> u8 {
@@ -622,7 +822,7 @@ impl<'a> CPU<'a> {
         } else {
             self.status.remove(CpuFlags::CARRY);
         }
-        self.register_a = self.register_a >> 1;
+        self.register_a >>= 1;
         self.update_zero_and_negative_flags(self.register_a);
     }
 
@@ -631,14 +831,10 @@ impl<'a> CPU<'a> {
     }
 
     fn ora(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let data = self.mem_read(addr);
-        self.register_a = data | self.register_a;
+        self.register_a |= data;
         self.update_zero_and_negative_flags(self.register_a);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn pha(&mut self) {
@@ -674,8 +870,8 @@ impl<'a> CPU<'a> {
             self.status.insert(CpuFlags::CARRY);
         }
 
-        data = data << 1;
-        data = data | (carry as u8);
+        data <<= 1;
+        data |= carry as u8;
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
         data
@@ -690,8 +886,8 @@ impl<'a> CPU<'a> {
         } else {
             self.status.insert(CpuFlags::CARRY);
         }
-        data = data << 1;
-        data = data | (carry as u8);
+        data <<= 1;
+        data |= carry as u8;
         self.register_a = data;
         self.update_zero_and_negative_flags(data);
     }
@@ -707,8 +903,8 @@ impl<'a> CPU<'a> {
             self.status.insert(CpuFlags::CARRY);
         }
 
-        data = data >> 1;
-        data = data | (carry as u8) << 7;
+        data >>= 1;
+        data |= (carry as u8) << 7;
         self.mem_write(addr, data);
         self.update_zero_and_negative_flags(data);
         data
@@ -724,8 +920,8 @@ impl<'a> CPU<'a> {
             self.status.insert(CpuFlags::CARRY);
         }
 
-        data = data >> 1;
-        data = data | (carry as u8) << 7;
+        data >>= 1;
+        data |= (carry as u8) << 7;
         self.register_a = data;
         self.update_zero_and_negative_flags(data);
     }
@@ -743,7 +939,7 @@ impl<'a> CPU<'a> {
     }
 
     fn sbc(&mut self, mode: &AddressingMode) {
-        let (addr, page_crossed) = self.get_operand_address(mode);
+        let (addr, _) = self.get_operand_address(mode);
         let data = self.mem_read(addr);
 
         // let sub_val = ((data as i8).wrapping_neg().wrapping_sub(1)) as u8;
@@ -751,10 +947,6 @@ impl<'a> CPU<'a> {
         // [TODO] maybe ok.
         let target_val = (-(data as i16) - 1) as u8;
         self.set_register_a_with_flags(target_val);
-
-        if page_crossed {
-            self.bus.tick(1);
-        }
     }
 
     fn sec(&mut self) {
@@ -884,7 +1076,7 @@ impl<'a> CPU<'a> {
 
     fn branch(&mut self, condition: bool) {
         if condition {
-            self.bus.tick(1);
+            self.tick(1);
 
             let jump: i8 = self.mem_read(self.program_counter) as i8;
             let jump_addr = self
@@ -893,7 +1085,7 @@ impl<'a> CPU<'a> {
                 .wrapping_add(jump as u16);
 
             if self.program_counter.wrapping_add(1) & 0xFF00 != jump_addr & 0xFF00 {
-                self.bus.tick(1);
+                self.tick(1);
             }
 
             self.program_counter = jump_addr;
@@ -960,18 +1152,18 @@ impl<'a> CPU<'a> {
 
     fn stack_pop(&mut self) -> u8 {
         self.stack_pointer = self.stack_pointer.wrapping_add(1);
-        let val = self.mem_read((STACK_TOP as u16) + self.stack_pointer as u16);
+        let val = self.mem_read(STACK_TOP + self.stack_pointer as u16);
         // println!(
         //     "pop: pointer:{}, val:{}, adddr:{}",
         //     self.stack_pointer,
         //     val,
         //     (STACK_TOP as u16) + self.stack_pointer as u16
         // );
-        return val;
+        val
     }
 
     fn stack_push(&mut self, data: u8) {
-        let addr = (STACK_TOP as u16) + self.stack_pointer as u16;
+        let addr = STACK_TOP + self.stack_pointer as u16;
         self.mem_write(addr, data);
         self.stack_pointer = self.stack_pointer.wrapping_sub(1);
         // println!(
@@ -992,11 +1184,63 @@ impl<'a> CPU<'a> {
         self.stack_push(hi);
         self.stack_push(lo);
     }
+
+    // the most recently pushed byte, without popping it
+    pub fn peek_stack_top(&self) -> Option<u8> {
+        self.peek_stack_n(0)
+    }
+
+    // the byte n pushes below the top, None past the bottom of the stack page
+    pub fn peek_stack_n(&self, n: u8) -> Option<u8> {
+        let offset = self.stack_pointer as u16 + 1 + n as u16;
+        if offset > 0xff {
+            return None;
+        }
+        Some(self.mem_peek(STACK_TOP + offset))
+    }
+
+    pub fn snapshot(&self) -> CpuSnapshot {
+        CpuSnapshot {
+            register_a: self.register_a,
+            register_x: self.register_x,
+            register_y: self.register_y,
+            status: self.status.bits(),
+            program_counter: self.program_counter,
+            stack_pointer: self.stack_pointer,
+        }
+    }
+
+    pub fn cpu_state(&self) -> CpuState {
+        CpuState {
+            register_a: self.register_a,
+            register_x: self.register_x,
+            register_y: self.register_y,
+            status: self.status.clone(),
+            program_counter: self.program_counter,
+            stack_pointer: self.stack_pointer,
+            total_cycles: self.total_cycles,
+            irq_line: self.irq_line,
+        }
+    }
+
+    pub fn load_cpu_state(&mut self, state: CpuState) {
+        self.register_a = state.register_a;
+        self.register_x = state.register_x;
+        self.register_y = state.register_y;
+        self.status = state.status;
+        self.program_counter = state.program_counter;
+        self.stack_pointer = state.stack_pointer;
+        self.total_cycles = state.total_cycles;
+        self.irq_line = state.irq_line;
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{cartoridge::Rom, joypad::Joypad, ppu::NesPPU};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::{bus::Bus, cartoridge::Rom, joypad::Joypad, mock_bus::MockBus, ppu::NesPPU};
 
     use super::*;
 
@@ -1019,11 +1263,12 @@ mod test {
         rom_data.extend_from_slice(&TEST_HEADER);
         rom_data.extend_from_slice(&testdata);
         rom_data.resize(rom_data.len() + 2 * 16 * 1024, 0);
-        rom_data.extend_from_slice(&[2; 1 * 8 * 1024]);
+        rom_data.extend_from_slice(&[2; 8 * 1024]);
 
-        let rom = Rom::new(&rom_data).unwrap();
-        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad| {});
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
         let mut cpu = CPU::new(bus);
+        cpu.halt_on_brk = true;
         cpu.run();
 
         assert_eq!(cpu.register_a, 0x05);
@@ -1039,11 +1284,12 @@ mod test {
         rom_data.extend_from_slice(&TEST_HEADER);
         rom_data.extend_from_slice(&testdata);
         rom_data.resize(rom_data.len() + 2 * 16 * 1024, 0);
-        rom_data.extend_from_slice(&[2; 1 * 8 * 1024]);
+        rom_data.extend_from_slice(&[2; 8 * 1024]);
 
-        let rom = Rom::new(&rom_data).unwrap();
-        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad| {});
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
         let mut cpu = CPU::new(bus);
+        cpu.halt_on_brk = true;
         cpu.run();
 
         assert!(cpu.status.contains(CpuFlags::ZERO))
@@ -1057,11 +1303,12 @@ mod test {
         rom_data.extend_from_slice(&TEST_HEADER);
         rom_data.extend_from_slice(&testdata);
         rom_data.resize(rom_data.len() + 2 * 16 * 1024, 0);
-        rom_data.extend_from_slice(&[2; 1 * 8 * 1024]);
+        rom_data.extend_from_slice(&[2; 8 * 1024]);
 
-        let rom = Rom::new(&rom_data).unwrap();
-        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad| {});
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
         let mut cpu = CPU::new(bus);
+        cpu.halt_on_brk = true;
         cpu.run();
 
         assert_eq!(cpu.register_x, 10)
@@ -1075,11 +1322,12 @@ mod test {
         rom_data.extend_from_slice(&TEST_HEADER);
         rom_data.extend_from_slice(&testdata);
         rom_data.resize(rom_data.len() + 2 * 16 * 1024, 0);
-        rom_data.extend_from_slice(&[2; 1 * 8 * 1024]);
+        rom_data.extend_from_slice(&[2; 8 * 1024]);
 
-        let rom = Rom::new(&rom_data).unwrap();
-        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad| {});
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
         let mut cpu = CPU::new(bus);
+        cpu.halt_on_brk = true;
         cpu.run();
 
         assert_eq!(cpu.register_x, 1)
@@ -1093,11 +1341,12 @@ mod test {
         rom_data.extend_from_slice(&TEST_HEADER);
         rom_data.extend_from_slice(&testdata);
         rom_data.resize(rom_data.len() + 2 * 16 * 1024, 0);
-        rom_data.extend_from_slice(&[2; 1 * 8 * 1024]);
+        rom_data.extend_from_slice(&[2; 8 * 1024]);
 
-        let rom = Rom::new(&rom_data).unwrap();
-        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad| {});
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
         let mut cpu = CPU::new(bus);
+        cpu.halt_on_brk = true;
         cpu.run();
 
         assert_eq!(cpu.register_x, 0xc1)
@@ -1111,15 +1360,527 @@ mod test {
         rom_data.extend_from_slice(&TEST_HEADER);
         rom_data.extend_from_slice(&testdata);
         rom_data.resize(rom_data.len() + 2 * 16 * 1024, 0);
-        rom_data.extend_from_slice(&[2; 1 * 8 * 1024]);
+        rom_data.extend_from_slice(&[2; 8 * 1024]);
 
-        let rom = Rom::new(&rom_data).unwrap();
-        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad| {});
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let bus = Bus::new(rom, |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {});
         let mut cpu = CPU::new(bus);
+        cpu.halt_on_brk = true;
 
         cpu.mem_write(0x10, 0x55); // set test data
         cpu.run();
 
         assert_eq!(cpu.register_a, 0x55)
     }
+
+    #[test]
+    fn test_debug_format() {
+        let mut rom_data = Vec::new();
+        rom_data.extend_from_slice(&TEST_HEADER);
+        rom_data.resize(rom_data.len() + 2 * 16 * 1024 + 8 * 1024, 0);
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let mut cpu = CPU::new(Bus::new(
+            rom,
+            |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {},
+        ));
+        cpu.register_a = 0x42;
+
+        let debug = format!("{:?}", cpu);
+        assert!(debug.contains("register_a: 66"));
+        assert!(debug.contains("cpu_vram: [2048 bytes]"));
+        assert!(debug.contains("NesPPU"));
+        assert!(!debug.contains("callback"));
+    }
+
+    #[test]
+    fn test_peek_stack_top() {
+        let cpu = crate::mock_bus::run_program(&[0xa9, 0x42, 0x48, 0x00]);
+        assert_eq!(cpu.stack_pointer, 0xfc);
+
+        assert_eq!(cpu.peek_stack_top(), Some(0x42));
+        assert_eq!(cpu.stack_pointer, 0xfc);
+    }
+
+    #[test]
+    fn test_peek_stack_n() {
+        // JSR $0004, then BRK in the subroutine
+        let mut cpu = crate::mock_bus::run_program(&[0x20, 0x04, 0x00, 0x00, 0x00]);
+        assert_eq!(cpu.peek_stack_top(), Some(0x02));
+        assert_eq!(cpu.peek_stack_n(1), Some(0x00));
+
+        cpu.stack_pointer = 0xfe;
+        assert!(cpu.peek_stack_n(0).is_some());
+        assert_eq!(cpu.peek_stack_n(1), None);
+        cpu.stack_pointer = 0xff;
+        assert_eq!(cpu.peek_stack_top(), None);
+    }
+
+    #[test]
+    fn test_page_cross_penalty_cycles() {
+        // LDX #$01; LDA $0010,X
+        let cpu = crate::mock_bus::run_program(&[0xa2, 0x01, 0xbd, 0x10, 0x00, 0x00]);
+        assert_eq!(cpu.bus.tick_count, 2 + 4);
+
+        // LDX #$01; LDA $00FF,X
+        let cpu = crate::mock_bus::run_program(&[0xa2, 0x01, 0xbd, 0xff, 0x00, 0x00]);
+        assert_eq!(cpu.bus.tick_count, 2 + 4 + 1);
+
+        // LDX #$01; STA $00FF,X has no penalty
+        let cpu = crate::mock_bus::run_program(&[0xa2, 0x01, 0x9d, 0xff, 0x00, 0x00]);
+        assert_eq!(cpu.bus.tick_count, 2 + 5);
+    }
+
+    #[test]
+    fn test_step_returns_cycles() {
+        // LDA #$05; TAX; INX; LDA $00FF,X; BRK
+        let mut ram = [0; 65536];
+        ram[..8].copy_from_slice(&[0xa9, 0x05, 0xaa, 0xe8, 0xbd, 0xff, 0x00, 0x00]);
+        let mut cpu = CPU::new_with_mock(ram);
+        cpu.reset();
+
+        for (code, cycles) in [(0xa9, 2), (0xaa, 2), (0xe8, 2)] {
+            let (peeked, opcode) = cpu.peek_instruction();
+            assert_eq!((peeked, opcode.cycle), (code, cycles));
+            assert_eq!(cpu.step(), cycles as usize);
+        }
+        // $00FF + 6 crosses into page 1
+        assert_eq!(cpu.peek_instruction().1.cycle, 4);
+        assert_eq!(cpu.step(), 5);
+        assert_eq!(cpu.peek_instruction().0, 0x00);
+        assert_eq!(cpu.step(), 7);
+
+        assert_eq!(cpu.get_cycle_count(), 2 + 2 + 2 + 5 + 7);
+        assert_eq!(cpu.bus.tick_count, 2 + 2 + 2 + 5 + 7);
+    }
+
+    #[test]
+    fn test_get_absolute_address_page_cross() {
+        let mut ram = [0; 65536];
+        ram[0x0200..0x0202].copy_from_slice(&[0xfe, 0x10]);
+        ram[0x0300..0x0302].copy_from_slice(&[0x00, 0x10]);
+        // ($40) points to $10FE
+        ram[0x0400] = 0x40;
+        ram[0x40..0x42].copy_from_slice(&[0xfe, 0x10]);
+        let mut cpu = CPU::new_with_mock(ram);
+
+        cpu.register_x = 0x03;
+        cpu.register_y = 0x03;
+        assert_eq!(
+            cpu.get_absolute_address(&AddressingMode::Absolute_X, 0x0200),
+            (0x1101, true)
+        );
+        assert_eq!(
+            cpu.get_absolute_address(&AddressingMode::Absolute_Y, 0x0200),
+            (0x1101, true)
+        );
+        assert_eq!(
+            cpu.get_absolute_address(&AddressingMode::Indirect_Y, 0x0400),
+            (0x1101, true)
+        );
+
+        cpu.register_x = 0x01;
+        cpu.register_y = 0x01;
+        assert_eq!(
+            cpu.get_absolute_address(&AddressingMode::Absolute_X, 0x0300),
+            (0x1001, false)
+        );
+        assert_eq!(
+            cpu.get_absolute_address(&AddressingMode::Absolute_Y, 0x0300),
+            (0x1001, false)
+        );
+        assert_eq!(
+            cpu.get_absolute_address(&AddressingMode::Indirect_Y, 0x0400),
+            (0x10ff, false)
+        );
+    }
+
+    #[test]
+    fn test_opcode_builder() {
+        let opcode = OpCode::builder()
+            .code(0xb9)
+            .mnemonic("LDA")
+            .len(3)
+            .cycles(4)
+            .mode(AddressingMode::Absolute_Y)
+            .page_cross_penalty()
+            .build();
+        assert_eq!(opcode.code, 0xb9);
+        assert_eq!(opcode.mnemonic, "LDA");
+        assert_eq!(opcode.len, 3);
+        assert_eq!(opcode.cycle, 4);
+        assert!(opcode.has_page_cross_penalty());
+        assert!(!opcode.is_branch());
+
+        assert!(!OpCode::new(0xad, "LDA", 3, 4, AddressingMode::Absolute).has_page_cross_penalty());
+        assert!(opcode::OPCODE_MAP[&0xd0].is_branch());
+        assert!(opcode::OPCODE_MAP[&0xb1].has_page_cross_penalty());
+        assert!(!opcode::OPCODE_MAP[&0x91].has_page_cross_penalty());
+    }
+
+    #[test]
+    #[should_panic(expected = "OpCode 2 is not recognized")]
+    fn test_unknown_opcode_panics() {
+        let mut ram = [0; 65536];
+        ram[0x8000] = 0x02;
+        CPU::new_with_mock(ram).step();
+    }
+
+    #[test]
+    fn test_opcode_table_matches_map() {
+        for code in 0..=255u8 {
+            let from_table = opcode::OPCODE_TABLE[code as usize].map(|ops| ops.code);
+            let from_map = opcode::OPCODE_MAP.get(&code).map(|ops| ops.code);
+            assert_eq!(from_table, from_map, "opcode {:02x}", code);
+        }
+        assert_eq!(opcode::OPCODE_TABLE[0xa9].unwrap().mnemonic, "LDA");
+        assert!(opcode::OPCODE_TABLE[0x02].is_none());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_opecode_map_alias() {
+        assert_eq!(opcode::OPECODE_MAP[&0xa9].mnemonic, "LDA");
+        assert_eq!(opcode::OPECODE_MAP.len(), opcode::OPCODE_MAP.len());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        // LDA #$42; LDX #$01; LDY #$02
+        let cpu = crate::mock_bus::run_program(&[0xa9, 0x42, 0xa2, 0x01, 0xa0, 0x02, 0x00]);
+        assert_eq!(
+            cpu.snapshot(),
+            CpuSnapshot {
+                register_a: 0x42,
+                register_x: 0x01,
+                register_y: 0x02,
+                status: cpu.status.bits(),
+                program_counter: 0x0006,
+                stack_pointer: cpu.stack_pointer,
+            }
+        );
+    }
+
+    #[test]
+    fn test_set_irq_runs_handler() {
+        let mut ram = [0; 65536];
+        // CLI; NOP; SEI; NOP; BRK
+        ram[..5].copy_from_slice(&[0x58, 0xea, 0x78, 0xea, 0x00]);
+        // handler: INC $10; RTI
+        ram[0x40..0x43].copy_from_slice(&[0xe6, 0x10, 0x40]);
+        ram[0xfffe] = 0x40;
+        let mut cpu = CPU::new_with_mock(ram);
+        cpu.reset();
+        cpu.set_irq(true);
+
+        // masked until CLI
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0001);
+        cpu.step();
+        assert_eq!(cpu.mem_peek(0x10), 1);
+        assert_eq!(cpu.peek_stack_n(1), Some(0x01));
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+
+        cpu.set_irq(false);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0001);
+        assert!(!cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+
+        // NOP; SEI, then the line is ignored
+        cpu.step();
+        cpu.step();
+        cpu.set_irq(true);
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0004);
+        assert_eq!(cpu.mem_peek(0x10), 1);
+    }
+
+    #[test]
+    fn test_run_for_cycles() {
+        // NOPs, 2 cycles each
+        let mut cpu = CPU::new_with_mock([0xea; 65536]);
+        cpu.program_counter = 0x0000;
+        assert_eq!(cpu.run_for_cycles(100), 100);
+        assert_eq!(cpu.get_cycle_count(), 100);
+        assert_eq!(cpu.program_counter, 50);
+
+        // LDA $A5, 3 cycles each, overshoots to the end of the 34th instruction
+        let mut cpu = CPU::new_with_mock([0xa5; 65536]);
+        cpu.program_counter = 0x0000;
+        assert_eq!(cpu.run_for_cycles(100), 102);
+        assert_eq!(cpu.bus.tick_count, 102);
+        assert_eq!(cpu.run_for_cycles(0), 0);
+    }
+
+    #[test]
+    fn test_breakpoint_handler_fires_once() {
+        let mut ram = [0; 65536];
+        // LDX #$03; DEX; BNE -3; LDA #$42; BRK
+        ram[..8].copy_from_slice(&[0xa2, 0x03, 0xca, 0xd0, 0xfd, 0xa9, 0x42, 0x00]);
+        let mut cpu = CPU::new_with_mock(ram);
+        cpu.program_counter = 0x0000;
+        cpu.halt_on_brk = true;
+
+        let hits = Rc::new(RefCell::new(Vec::new()));
+        let handler_hits = Rc::clone(&hits);
+        cpu.on_breakpoint = Some(Box::new(move |cpu: &mut CPU<MockBus>| {
+            handler_hits
+                .borrow_mut()
+                .push((cpu.program_counter, cpu.register_a))
+        }));
+        cpu.add_breakpoint(0x0005);
+        cpu.add_breakpoint(0x0005);
+        cpu.run();
+
+        // the handler runs before LDA executes
+        assert_eq!(*hits.borrow(), vec![(0x0005, 0x00)]);
+        assert_eq!(cpu.register_a, 0x42);
+
+        cpu.remove_breakpoint(0x0005);
+        assert!(cpu.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn test_breakpoint_pauses_run() {
+        let mut ram = [0; 65536];
+        // LDX #$03; DEX; BNE -3; BRK
+        ram[..6].copy_from_slice(&[0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x00]);
+        let mut cpu = CPU::new_with_mock(ram);
+        cpu.program_counter = 0x0000;
+        cpu.halt_on_brk = true;
+        cpu.add_breakpoint(0x0002);
+
+        // without a handler each visit to DEX stops the run before it executes
+        for x in [3, 2, 1] {
+            cpu.run();
+            assert!(cpu.paused);
+            assert_eq!((cpu.program_counter, cpu.register_x), (0x0002, x));
+        }
+        cpu.run();
+        assert!(!cpu.paused);
+        assert_eq!((cpu.program_counter, cpu.register_x), (0x0005, 0));
+    }
+
+    #[test]
+    fn test_callback_pauses_run() {
+        let mut ram = [0; 65536];
+        // INX forever
+        ram[..4].copy_from_slice(&[0xe8, 0x4c, 0x00, 0x00]);
+        let mut cpu = CPU::new_with_mock(ram);
+        cpu.program_counter = 0x0000;
+
+        cpu.run_with_callback(|cpu| {
+            if cpu.register_x == 5 {
+                cpu.paused = true;
+            }
+        });
+        assert!(cpu.paused);
+        assert_eq!((cpu.program_counter, cpu.register_x), (0x0001, 5));
+    }
+
+    #[test]
+    fn test_brk() {
+        let mut ram = [0; 65536];
+        // CLC; BRK; padding; LDA #$42; BRK
+        ram[..6].copy_from_slice(&[0x18, 0x00, 0xff, 0xa9, 0x42, 0x00]);
+        // handler: RTI
+        ram[0x40] = 0x40;
+        ram[0xfffe] = 0x40;
+        let mut cpu = CPU::new_with_mock(ram);
+        cpu.reset();
+        cpu.status.remove(CpuFlags::INTERRUPT_DISABLE);
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0040);
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+        assert!(!cpu.status.contains(CpuFlags::BREAK));
+        // return address is BRK + 2, pushed status has B and bit 5 set
+        assert_eq!(cpu.peek_stack_n(1), Some(0x03));
+        assert_eq!(cpu.peek_stack_n(2), Some(0x00));
+        assert_eq!(cpu.peek_stack_top(), Some(0b0011_0000));
+
+        // RTI resumes after the padding byte
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x0003);
+        assert!(!cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+
+        // halt_on_brk stops the run loop before the second BRK
+        cpu.halt_on_brk = true;
+        cpu.run();
+        assert_eq!(cpu.program_counter, 0x0005);
+        assert_eq!(cpu.register_a, 0x42);
+    }
+
+    #[test]
+    fn test_mmc3_irq_acknowledged() {
+        let mut rom_data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        let mut prg_rom = vec![0; 2 * 16 * 1024];
+        // $8000: CLI; JMP $8001, IRQ handler at $8010: JMP $8010
+        prg_rom[0x00..0x04].copy_from_slice(&[0x58, 0x4c, 0x01, 0x80]);
+        prg_rom[0x10..0x13].copy_from_slice(&[0x4c, 0x10, 0x80]);
+        prg_rom[0x7ffc..0x8000].copy_from_slice(&[0x00, 0x80, 0x10, 0x80]);
+        rom_data.extend_from_slice(&prg_rom);
+        rom_data.resize(rom_data.len() + 8 * 1024, 0);
+
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let mut cpu = CPU::new(Bus::new(
+            rom,
+            |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {},
+        ));
+        cpu.reset();
+        cpu.bus.mem_write(0x2001, 0b0001_1000);
+        cpu.bus.mem_write(0xc000, 2);
+        cpu.bus.mem_write(0xc001, 0);
+        cpu.bus.mem_write(0xe001, 0);
+
+        while !cpu.bus.irq_pending() {
+            cpu.step();
+        }
+        assert_ne!(cpu.program_counter, 0x8010);
+
+        cpu.step();
+        assert_eq!(cpu.program_counter, 0x8010);
+        assert!(!cpu.bus.irq_pending());
+        assert!(cpu.status.contains(CpuFlags::INTERRUPT_DISABLE));
+        assert!(cpu.peek_stack_top().unwrap() & CpuFlags::INTERRUPT_DISABLE.bits() == 0);
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_boundary_bug() {
+        let mut ram = [0; 65536];
+        ram[0x0200..0x0203].copy_from_slice(&[0x6c, 0xff, 0x30]); // JMP ($30FF)
+        ram[0x30ff] = 0x80;
+        ram[0x3100] = 0x50;
+        ram[0x3000] = 0x40;
+        let mut cpu = CPU::new_with_mock(ram);
+        cpu.program_counter = 0x0200;
+
+        cpu.step();
+
+        // the high byte wraps around within the page, $3000 instead of $3100
+        assert_eq!(cpu.program_counter, 0x4080);
+    }
+
+    #[test]
+    fn test_jmp_indirect() {
+        let mut ram = [0; 65536];
+        ram[0x0200..0x0203].copy_from_slice(&[0x6c, 0xfe, 0x30]); // JMP ($30FE)
+        ram[0x30fe] = 0x80;
+        ram[0x30ff] = 0x50;
+        ram[0x3000] = 0x40;
+        let mut cpu = CPU::new_with_mock(ram);
+        cpu.program_counter = 0x0200;
+
+        cpu.step();
+
+        assert_eq!(cpu.program_counter, 0x5080);
+    }
+
+    mod addressing_mode {
+        use proptest::prelude::*;
+
+        use crate::mock_bus::MockBus;
+
+        use super::*;
+
+        fn setup_cpu(pc: u16, operand: [u8; 2], a: u8, x: u8, y: u8) -> CPU<MockBus> {
+            let mut cpu = CPU::new_with_mock([0; 65536]);
+            cpu.program_counter = pc;
+            cpu.register_a = a;
+            cpu.register_x = x;
+            cpu.register_y = y;
+            cpu.mem_write(pc, operand[0]);
+            cpu.mem_write(pc + 1, operand[1]);
+            cpu
+        }
+
+        proptest! {
+            #[test]
+            fn zero_page(pc in 0x0200u16..0x8000, operand: [u8; 2], a: u8, x: u8, y: u8) {
+                let cpu = setup_cpu(pc, operand, a, x, y);
+                let expected = operand[0] as u16;
+                prop_assert_eq!(cpu.get_operand_address(&AddressingMode::ZeroPage), (expected, false));
+            }
+
+            #[test]
+            fn zero_page_x(pc in 0x0200u16..0x8000, operand: [u8; 2], a: u8, x: u8, y: u8) {
+                let cpu = setup_cpu(pc, operand, a, x, y);
+                let expected = (operand[0] as u16 + x as u16) % 256;
+                prop_assert_eq!(cpu.get_operand_address(&AddressingMode::ZeroPage_X), (expected, false));
+            }
+
+            #[test]
+            fn zero_page_y(pc in 0x0200u16..0x8000, operand: [u8; 2], a: u8, x: u8, y: u8) {
+                let cpu = setup_cpu(pc, operand, a, x, y);
+                let expected = (operand[0] as u16 + y as u16) % 256;
+                prop_assert_eq!(cpu.get_operand_address(&AddressingMode::ZeroPage_Y), (expected, false));
+            }
+
+            #[test]
+            fn absolute(pc in 0x0200u16..0x8000, operand: [u8; 2], a: u8, x: u8, y: u8) {
+                let cpu = setup_cpu(pc, operand, a, x, y);
+                let expected = operand[0] as u16 | (operand[1] as u16) << 8;
+                prop_assert_eq!(cpu.get_operand_address(&AddressingMode::Absolute), (expected, false));
+            }
+
+            #[test]
+            fn absolute_x(pc in 0x0200u16..0x8000, operand: [u8; 2], a: u8, x: u8, y: u8) {
+                let cpu = setup_cpu(pc, operand, a, x, y);
+                let base = operand[0] as u32 | (operand[1] as u32) << 8;
+                let addr = (base + x as u32) % 0x10000;
+                let expected = (addr as u16, base >> 8 != addr >> 8);
+                prop_assert_eq!(cpu.get_operand_address(&AddressingMode::Absolute_X), expected);
+            }
+
+            #[test]
+            fn absolute_y(pc in 0x0200u16..0x8000, operand: [u8; 2], a: u8, x: u8, y: u8) {
+                let cpu = setup_cpu(pc, operand, a, x, y);
+                let base = operand[0] as u32 | (operand[1] as u32) << 8;
+                let addr = (base + y as u32) % 0x10000;
+                let expected = (addr as u16, base >> 8 != addr >> 8);
+                prop_assert_eq!(cpu.get_operand_address(&AddressingMode::Absolute_Y), expected);
+            }
+
+            #[test]
+            fn indirect_x(
+                pc in 0x0200u16..0x8000,
+                operand: [u8; 2],
+                pointer: [u8; 2],
+                a: u8,
+                x: u8,
+                y: u8,
+            ) {
+                let mut cpu = setup_cpu(pc, operand, a, x, y);
+                let ptr = (operand[0] as u16 + x as u16) % 256;
+                cpu.mem_write(ptr, pointer[0]);
+                cpu.mem_write((ptr + 1) % 256, pointer[1]);
+
+                let expected = cpu.mem_read(ptr) as u16 | (cpu.mem_read((ptr + 1) % 256) as u16) << 8;
+                prop_assert_eq!(cpu.get_operand_address(&AddressingMode::Indirect_X), (expected, false));
+            }
+
+            #[test]
+            fn indirect_y(
+                pc in 0x0200u16..0x8000,
+                operand: [u8; 2],
+                pointer: [u8; 2],
+                a: u8,
+                x: u8,
+                y: u8,
+            ) {
+                let mut cpu = setup_cpu(pc, operand, a, x, y);
+                let ptr = operand[0] as u16;
+                cpu.mem_write(ptr, pointer[0]);
+                cpu.mem_write((ptr + 1) % 256, pointer[1]);
+
+                let base = cpu.mem_read(ptr) as u32 | (cpu.mem_read((ptr + 1) % 256) as u32) << 8;
+                let addr = (base + y as u32) % 0x10000;
+                let expected = (addr as u16, base >> 8 != addr >> 8);
+                prop_assert_eq!(cpu.get_operand_address(&AddressingMode::Indirect_Y), expected);
+            }
+        }
+    }
 }
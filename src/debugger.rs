@@ -0,0 +1,190 @@
+use std::io::{self, BufRead, Write};
+
+use crate::cpu::{CpuBus, Memory, CPU};
+use crate::trace::trace;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Step,
+    Continue,
+    Breakpoint(u16),
+    Peek(u16),
+    Registers,
+    Quit,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().ok_or("empty command")?;
+        let arg = words.next();
+
+        match (command, arg) {
+            ("s" | "step", None) => Ok(Command::Step),
+            ("c" | "continue", None) => Ok(Command::Continue),
+            ("b", Some(addr)) => Ok(Command::Breakpoint(parse_addr(addr)?)),
+            ("p", Some(addr)) => Ok(Command::Peek(parse_addr(addr)?)),
+            ("r", None) => Ok(Command::Registers),
+            ("q", None) => Ok(Command::Quit),
+            _ => Err(format!("unknown command: {}", line.trim())),
+        }
+    }
+}
+
+fn parse_addr(addr: &str) -> Result<u16, String> {
+    let hex = addr.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(hex, 16).map_err(|_| format!("invalid address: {}", addr))
+}
+
+// instructions `continue` runs before giving up on reaching a breakpoint
+const CONTINUE_LIMIT: usize = 10_000_000;
+
+// breakpoints live on the CPU, see CPU::add_breakpoint
+pub struct Debugger<B: CpuBus> {
+    pub cpu: CPU<B>,
+    pub continue_limit: usize,
+}
+
+impl<B: CpuBus> Debugger<B> {
+    pub fn new(cpu: CPU<B>) -> Self {
+        Debugger {
+            cpu,
+            continue_limit: CONTINUE_LIMIT,
+        }
+    }
+
+    pub fn run_repl(&mut self) {
+        let stdin = io::stdin();
+        self.run_repl_with(stdin.lock(), io::stdout());
+    }
+
+    pub fn run_repl_with<R: BufRead, W: Write>(&mut self, input: R, mut output: W) {
+        for line in input.lines() {
+            let line = line.unwrap();
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Command::parse(&line) {
+                Ok(Command::Quit) => break,
+                Ok(command) => self.execute(command, &mut output),
+                Err(e) => writeln!(output, "{}", e).unwrap(),
+            }
+        }
+    }
+
+    fn execute<W: Write>(&mut self, command: Command, output: &mut W) {
+        match command {
+            Command::Step => {
+                let (scanline, dot) = self.cpu.bus.ppu_position();
+                let cycles = self.cpu.get_cycle_count();
+                writeln!(output, "{}", trace(&self.cpu, cycles, scanline, dot)).unwrap();
+                self.cpu.step();
+            }
+            Command::Continue => {
+                let limit = self.continue_limit;
+                let mut executed = 0;
+                // run the current instruction even if it has a breakpoint
+                self.cpu.paused = true;
+                self.cpu.run_with_callback(|cpu| {
+                    if executed == limit {
+                        cpu.paused = true;
+                    }
+                    executed += 1;
+                });
+                if executed > limit {
+                    writeln!(
+                        output,
+                        "no breakpoint after {} instructions, stopped at ${:04X}",
+                        limit, self.cpu.program_counter
+                    )
+                    .unwrap();
+                } else {
+                    writeln!(output, "break at ${:04X}", self.cpu.program_counter).unwrap();
+                }
+            }
+            Command::Breakpoint(addr) => {
+                self.cpu.add_breakpoint(addr);
+                writeln!(output, "breakpoint at ${:04X}", addr).unwrap();
+            }
+            Command::Peek(addr) => {
+                let data = self.cpu.mem_peek(addr);
+                writeln!(output, "${:04X}: {:02X}", addr, data).unwrap();
+            }
+            Command::Registers => {
+                writeln!(
+                    output,
+                    "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}",
+                    self.cpu.register_a,
+                    self.cpu.register_x,
+                    self.cpu.register_y,
+                    self.cpu.status.bits(),
+                    self.cpu.stack_pointer,
+                    self.cpu.program_counter
+                )
+                .unwrap();
+            }
+            Command::Quit => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(Command::parse("s"), Ok(Command::Step));
+        assert_eq!(Command::parse("continue"), Ok(Command::Continue));
+        assert_eq!(Command::parse("b 8004"), Ok(Command::Breakpoint(0x8004)));
+        assert_eq!(Command::parse("p $00ff"), Ok(Command::Peek(0x00ff)));
+        assert_eq!(Command::parse("r"), Ok(Command::Registers));
+        assert_eq!(Command::parse("q"), Ok(Command::Quit));
+        assert!(Command::parse("p").is_err());
+        assert!(Command::parse("b zz").is_err());
+        assert!(Command::parse("x").is_err());
+    }
+
+    #[test]
+    fn test_repl_session() {
+        let mut ram = [0; 65536];
+        // LDA #$05, STA $10, INX, INX
+        ram[0x8000..0x8006].copy_from_slice(&[0xa9, 0x05, 0x85, 0x10, 0xe8, 0xe8]);
+        let mut debugger = Debugger::new(CPU::new_with_mock(ram));
+
+        let input = Cursor::new(&b"s\nb 8005\nc\np 10\nr\nq\ns\n"[..]);
+        let mut output = Vec::new();
+        debugger.run_repl_with(input, &mut output);
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[0].starts_with("8000  A9 05     LDA #$05"));
+        assert_eq!(lines[1], "breakpoint at $8005");
+        assert_eq!(lines[2], "break at $8005");
+        assert_eq!(lines[3], "$0010: 05");
+        assert_eq!(lines[4], "A:05 X:01 Y:00 P:24 SP:FD PC:8005");
+        assert_eq!(lines.len(), 5);
+        assert_eq!(debugger.cpu.breakpoints, vec![0x8005]);
+    }
+
+    #[test]
+    fn test_continue_without_breakpoint_stops() {
+        let mut ram = [0; 65536];
+        // INX; JMP $8000
+        ram[0x8000..0x8004].copy_from_slice(&[0xe8, 0x4c, 0x00, 0x80]);
+        let mut debugger = Debugger::new(CPU::new_with_mock(ram));
+        debugger.continue_limit = 10;
+
+        let mut output = Vec::new();
+        debugger.run_repl_with(Cursor::new(&b"c\nc\n"[..]), &mut output);
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        let stopped = "no breakpoint after 10 instructions, stopped at $8000";
+        assert_eq!(lines, vec![stopped, stopped]);
+        // 20 instructions, half of them INX
+        assert_eq!(debugger.cpu.register_x, 10);
+    }
+}
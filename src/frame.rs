@@ -1,7 +1,18 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone)]
 pub struct Frame {
     pub data: Vec<u8>,
 }
 
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Frame {
     const WUDTH: usize = 256;
     const HEIGHT: usize = 240;
@@ -12,6 +23,11 @@ impl Frame {
         }
     }
 
+    // (width, height) in pixels
+    pub fn dimensions() -> (usize, usize) {
+        (Frame::WUDTH, Frame::HEIGHT)
+    }
+
     pub fn set_pixcel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
         let base = y * 3 * Frame::WUDTH + x * 3;
         if base + 2 < self.data.len() {
@@ -20,4 +36,182 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
+
+    pub fn clear(&mut self, color: (u8, u8, u8)) {
+        for pixel in self.data.chunks_mut(3) {
+            pixel.copy_from_slice(&[color.0, color.1, color.2]);
+        }
+    }
+
+    // (x, y, rgb) in row-major order
+    pub fn iter_pixels(&self) -> impl Iterator<Item = (usize, usize, (u8, u8, u8))> + '_ {
+        self.data.chunks(3).enumerate().map(|(i, pixel)| {
+            (
+                i % Frame::WUDTH,
+                i / Frame::WUDTH,
+                (pixel[0], pixel[1], pixel[2]),
+            )
+        })
+    }
+
+    // self = self * (1 - alpha) + overlay * alpha, for every pixel
+    pub fn blend_alpha(&mut self, overlay: &Frame, alpha: f32) {
+        for (dst, src) in self.data.iter_mut().zip(&overlay.data) {
+            let blended = *dst as f32 * (1.0 - alpha) + *src as f32 * alpha;
+            *dst = blended.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+
+    // number of pixels that differ between the two frames
+    pub fn diff(&self, other: &Frame) -> usize {
+        self.data
+            .chunks(3)
+            .zip(other.data.chunks(3))
+            .filter(|(a, b)| a != b)
+            .count()
+    }
+
+    // 8bit RGB PNG of the whole frame, for screenshots
+    pub fn to_png(&self) -> Result<Vec<u8>, png::EncodingError> {
+        let mut png_data = Vec::new();
+        let mut encoder =
+            png::Encoder::new(&mut png_data, Frame::WUDTH as u32, Frame::HEIGHT as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.data)?;
+        writer.finish()?;
+        Ok(png_data)
+    }
+
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        let png_data = self.to_png().map_err(io::Error::other)?;
+        fs::write(path, png_data)
+    }
+
+    // 1 pixel outline, clipped to the screen
+    pub fn draw_rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: (u8, u8, u8)) {
+        if w == 0 || h == 0 {
+            return;
+        }
+        let right = (x + w - 1).min(Frame::WUDTH - 1);
+        let bottom = (y + h - 1).min(Frame::HEIGHT - 1);
+        for px in x..=right {
+            self.set_pixcel(px, y, color);
+            self.set_pixcel(px, bottom, color);
+        }
+        for py in y..=bottom {
+            self.set_pixcel(x, py, color);
+            self.set_pixcel(right, py, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_blend_alpha() {
+        let mut frame = Frame::new();
+        frame.data.fill(0xff);
+        let overlay = Frame::new();
+
+        frame.blend_alpha(&overlay, 0.5);
+
+        assert!(frame.data.iter().all(|c| *c == 127 || *c == 128));
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut frame = Frame::new();
+        let other = Frame::new();
+        assert_eq!(frame.diff(&other), 0);
+
+        frame.set_pixcel(10, 10, (1, 0, 0));
+        frame.set_pixcel(11, 10, (1, 1, 1));
+        assert_eq!(frame.diff(&other), 2);
+    }
+
+    #[test]
+    fn test_draw_rect() {
+        let mut frame = Frame::new();
+        frame.draw_rect(250, 2, 10, 3, (1, 2, 3));
+
+        let pixel = |x: usize, y: usize| {
+            let base = (y * 256 + x) * 3;
+            (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+        };
+        assert_eq!(pixel(250, 2), (1, 2, 3));
+        assert_eq!(pixel(255, 4), (1, 2, 3));
+        assert_eq!(pixel(252, 3), (0, 0, 0));
+        // clipped instead of wrapping onto the next row
+        assert_eq!(pixel(0, 3), (0, 0, 0));
+    }
+
+    fn decode_png(png_data: &[u8]) -> (png::OutputInfo, Vec<u8>) {
+        let decoder = png::Decoder::new(io::Cursor::new(png_data));
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut data).unwrap();
+        (info, data)
+    }
+
+    #[test]
+    fn test_to_png_round_trip() {
+        let mut frame = Frame::new();
+        for (i, c) in frame.data.iter_mut().enumerate() {
+            *c = (i % 251) as u8;
+        }
+
+        let (info, data) = decode_png(&frame.to_png().unwrap());
+
+        assert_eq!(
+            (info.width as usize, info.height as usize),
+            Frame::dimensions()
+        );
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(info.bit_depth, png::BitDepth::Eight);
+        assert_eq!(data, frame.data);
+    }
+
+    #[test]
+    fn test_save_png() {
+        let path = std::env::temp_dir().join("rustnes_test_save_png.png");
+        let mut frame = Frame::new();
+        frame.set_pixcel(3, 4, (10, 20, 30));
+        frame.save_png(&path).unwrap();
+
+        let png_data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (_, data) = decode_png(&png_data);
+        assert_eq!(data, frame.data);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut frame = Frame::new();
+        frame.set_pixcel(5, 5, (1, 2, 3));
+        frame.clear((255, 0, 0));
+
+        assert_eq!(frame.iter_pixels().count(), 256 * 240);
+        assert!(frame.iter_pixels().all(|(_, _, rgb)| rgb == (255, 0, 0)));
+    }
+
+    #[test]
+    fn test_iter_pixels_row_major() {
+        let mut frame = Frame::new();
+        frame.set_pixcel(255, 0, (1, 1, 1));
+        frame.set_pixcel(0, 1, (2, 2, 2));
+
+        let pixels: Vec<_> = frame.iter_pixels().skip(255).take(2).collect();
+        assert_eq!(pixels, vec![(255, 0, (1, 1, 1)), (0, 1, (2, 2, 2))]);
+        assert_eq!(frame.iter_pixels().last().unwrap().0, 255);
+        assert_eq!(frame.iter_pixels().last().unwrap().1, 239);
+    }
+
+    #[test]
+    fn test_dimensions() {
+        assert_eq!(Frame::dimensions(), (256, 240));
+    }
 }
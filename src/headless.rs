@@ -0,0 +1,48 @@
+use std::cell::Cell;
+
+use crate::bus::Bus;
+use crate::cartoridge::Rom;
+use crate::cpu::CPU;
+use crate::frame::Frame;
+use crate::joypad::Joypad;
+use crate::ppu::NesPPU;
+
+// Runs the ROM until it has rendered a number of frames without a window, handing every
+// frame to frame_callback. For screenshot tests and running test ROMs in CI.
+pub fn run_headless<F>(rom: Rom, frames: u32, mut frame_callback: F)
+where
+    F: FnMut(&Frame),
+{
+    let mut frame = Frame::new();
+    let frames_done = Cell::new(0);
+    let bus = Bus::new(rom, |ppu: &NesPPU, _: &mut Joypad, _: &mut Joypad| {
+        ppu.generate_frame(&mut frame);
+        frame_callback(&frame);
+        frames_done.set(frames_done.get() + 1);
+    });
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    while frames_done.get() < frames {
+        cpu.step();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_headless_nestest() {
+        let rom = Rom::from_file("test/sample/nestest.nes").unwrap();
+        let mut frames = 0;
+        let mut last_frame = Frame::new();
+        run_headless(rom, 10, |frame| {
+            frames += 1;
+            last_frame = frame.clone();
+        });
+
+        assert_eq!(frames, 10);
+        // the menu is on screen
+        assert!(last_frame.iter_pixels().any(|(_, _, rgb)| rgb != (0, 0, 0)));
+    }
+}
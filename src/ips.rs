@@ -0,0 +1,130 @@
+use std::path::Path;
+
+const MAGIC: &[u8] = b"PATCH";
+const EOF_MARKER: &[u8] = b"EOF";
+
+// One record, RLE records are expanded when parsed.
+#[derive(Debug, PartialEq)]
+pub struct IpsRecord {
+    pub offset: usize,
+    pub data: Vec<u8>,
+}
+
+// https://zerosoft.zophar.net/ips.php
+#[derive(Debug, PartialEq)]
+pub struct IpsPatch {
+    pub records: Vec<IpsRecord>,
+}
+
+impl IpsPatch {
+    pub fn load(path: &Path) -> Result<IpsPatch, String> {
+        let raw = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        IpsPatch::parse(&raw)
+    }
+
+    pub fn parse(raw: &[u8]) -> Result<IpsPatch, String> {
+        if !raw.starts_with(MAGIC) {
+            return Err("File is not an IPS patch".to_string());
+        }
+
+        let mut pos = MAGIC.len();
+        let mut take = |len: usize| -> Result<&[u8], String> {
+            let bytes = raw
+                .get(pos..pos + len)
+                .ok_or("IPS patch ends before EOF marker")?;
+            pos += len;
+            Ok(bytes)
+        };
+
+        let mut records = Vec::new();
+        loop {
+            let offset = take(3)?;
+            if offset == EOF_MARKER {
+                break;
+            }
+            let offset =
+                (offset[0] as usize) << 16 | (offset[1] as usize) << 8 | offset[2] as usize;
+            let size = take(2)?;
+            let size = (size[0] as usize) << 8 | size[1] as usize;
+
+            let data = if size == 0 {
+                // RLE: 16bit run length and the byte to repeat
+                let rle = take(3)?;
+                let length = (rle[0] as usize) << 8 | rle[1] as usize;
+                vec![rle[2]; length]
+            } else {
+                take(size)?.to_vec()
+            };
+            records.push(IpsRecord { offset, data });
+        }
+        Ok(IpsPatch { records })
+    }
+
+    // records past the end of the ROM grow it
+    pub fn apply(&self, rom: &mut Vec<u8>) {
+        for record in &self.records {
+            let end = record.offset + record.data.len();
+            if rom.len() < end {
+                rom.resize(end, 0);
+            }
+            rom[record.offset..end].copy_from_slice(&record.data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn synthetic_patch() -> Vec<u8> {
+        let mut raw = b"PATCH".to_vec();
+        // 2 bytes at $000010
+        raw.extend_from_slice(&[0x00, 0x00, 0x10, 0x00, 0x02, 0xaa, 0xbb]);
+        // RLE, 4 x $cc at $000020
+        raw.extend_from_slice(&[0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x04, 0xcc]);
+        // 1 byte at $000042, past the end of the ROM
+        raw.extend_from_slice(&[0x00, 0x00, 0x42, 0x00, 0x01, 0xdd]);
+        raw.extend_from_slice(b"EOF");
+        raw
+    }
+
+    #[test]
+    fn test_parse() {
+        let patch = IpsPatch::parse(&synthetic_patch()).unwrap();
+        assert_eq!(
+            patch.records,
+            vec![
+                IpsRecord {
+                    offset: 0x10,
+                    data: vec![0xaa, 0xbb],
+                },
+                IpsRecord {
+                    offset: 0x20,
+                    data: vec![0xcc; 4],
+                },
+                IpsRecord {
+                    offset: 0x42,
+                    data: vec![0xdd],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let patch = IpsPatch::parse(&synthetic_patch()).unwrap();
+        let mut rom = vec![0x11; 0x40];
+        patch.apply(&mut rom);
+
+        assert_eq!(rom[0x0f..0x12], [0x11, 0xaa, 0xbb]);
+        assert_eq!(rom[0x1f..0x25], [0x11, 0xcc, 0xcc, 0xcc, 0xcc, 0x11]);
+        assert_eq!(rom.len(), 0x43);
+        assert_eq!(rom[0x40..], [0x00, 0x00, 0xdd]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(IpsPatch::parse(b"NES\x1a").is_err());
+        assert!(IpsPatch::parse(b"PATCH\x00\x00\x10\x00\x02\xaa").is_err());
+    }
+}
@@ -1,7 +1,8 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 bitflags! {
-    #[derive(Clone, Copy)]
+    #[derive(Clone, Copy, Serialize, Deserialize)]
     pub struct JoypadButton: u8 {
         const RIGHT    = 0b1000_0000;
         const LEFT     = 0b0100_0000;
@@ -14,10 +15,21 @@ bitflags! {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Joypad {
     strobe: bool,
     button_index: u8,
     button_status: JoypadButton,
+    pub turbo_a: bool,
+    pub turbo_b: bool,
+    pub turbo_period: u8,
+    turbo_frame_counter: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Joypad {
@@ -26,6 +38,10 @@ impl Joypad {
             strobe: false,
             button_index: 0,
             button_status: JoypadButton::from_bits_truncate(0),
+            turbo_a: false,
+            turbo_b: false,
+            turbo_period: 2,
+            turbo_frame_counter: 0,
         }
     }
 
@@ -40,14 +56,134 @@ impl Joypad {
         if self.button_index > 7 {
             return 1;
         }
-        let response = (self.button_status.bits() & (1 << self.button_index)) >> self.button_index;
+        let status = self.effective_button_status();
+        let response = (status.bits() & (1 << self.button_index)) >> self.button_index;
         if !self.strobe && self.button_index <= 7 {
             self.button_index += 1;
         }
         response
     }
 
-    pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
+    pub fn set_button(&mut self, button: JoypadButton, pressed: bool) {
         self.button_status.set(button, pressed);
     }
+
+    #[deprecated(note = "use set_button")]
+    pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
+        self.set_button(button, pressed);
+    }
+
+    // replace the whole button state at once, e.g. for TAS playback
+    pub fn set_all_buttons(&mut self, buttons: JoypadButton) {
+        self.button_status = buttons;
+    }
+
+    pub fn button_pressed(&self, button: JoypadButton) -> bool {
+        self.button_status.contains(button)
+    }
+
+    pub fn active_buttons(&self) -> JoypadButton {
+        self.button_status
+    }
+
+    pub fn set_turbo_a(&mut self, enabled: bool) {
+        self.turbo_a = enabled;
+    }
+
+    pub fn set_turbo_b(&mut self, enabled: bool) {
+        self.turbo_b = enabled;
+    }
+
+    // called once per frame from the game loop to drive turbo buttons
+    pub fn advance_frame(&mut self) {
+        self.turbo_frame_counter = self.turbo_frame_counter.wrapping_add(1);
+    }
+
+    fn effective_button_status(&self) -> JoypadButton {
+        let mut status = self.button_status;
+        let turbo_pressed = self
+            .turbo_frame_counter
+            .is_multiple_of(self.turbo_period.max(1));
+        if self.turbo_a {
+            status.set(JoypadButton::BUTTON_A, turbo_pressed);
+        }
+        if self.turbo_b {
+            status.set(JoypadButton::BUTTON_B, turbo_pressed);
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_button_a(joypad: &mut Joypad) -> u8 {
+        joypad.write(1);
+        joypad.write(0);
+        joypad.read()
+    }
+
+    #[test]
+    fn test_turbo_a_alternates_every_frame() {
+        let mut joypad = Joypad::new();
+        joypad.set_turbo_a(true);
+
+        assert_eq!(read_button_a(&mut joypad), 1);
+        joypad.advance_frame();
+        assert_eq!(read_button_a(&mut joypad), 0);
+        joypad.advance_frame();
+        assert_eq!(read_button_a(&mut joypad), 1);
+        joypad.advance_frame();
+        assert_eq!(read_button_a(&mut joypad), 0);
+    }
+
+    #[test]
+    fn test_turbo_b_does_not_affect_button_a() {
+        let mut joypad = Joypad::new();
+        joypad.set_turbo_b(true);
+        joypad.set_button(JoypadButton::BUTTON_A, true);
+
+        for _ in 0..4 {
+            assert_eq!(read_button_a(&mut joypad), 1);
+            joypad.advance_frame();
+        }
+    }
+
+    #[test]
+    fn test_set_button() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(JoypadButton::UP, true);
+        joypad.set_button(JoypadButton::START, true);
+        assert!(joypad.button_pressed(JoypadButton::UP));
+        assert!(joypad.button_pressed(JoypadButton::START));
+        assert!(!joypad.button_pressed(JoypadButton::DOWN));
+
+        joypad.set_button(JoypadButton::UP, false);
+        assert!(!joypad.button_pressed(JoypadButton::UP));
+        assert_eq!(joypad.active_buttons().bits(), JoypadButton::START.bits());
+    }
+
+    #[test]
+    fn test_set_all_buttons() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(JoypadButton::UP, true);
+        joypad.set_all_buttons(JoypadButton::LEFT | JoypadButton::BUTTON_B);
+
+        assert!(!joypad.button_pressed(JoypadButton::UP));
+        assert!(joypad.button_pressed(JoypadButton::LEFT));
+        assert!(joypad.button_pressed(JoypadButton::BUTTON_B));
+        assert_eq!(
+            joypad.active_buttons().bits(),
+            (JoypadButton::LEFT | JoypadButton::BUTTON_B).bits()
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_set_button_pressed_status_alias() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::SELECT, true);
+        assert!(joypad.button_pressed(JoypadButton::SELECT));
+    }
 }
@@ -0,0 +1,28 @@
+pub mod apu;
+pub mod audio;
+pub mod bus;
+pub mod cartoridge;
+pub mod control;
+pub mod cpu;
+pub mod debugger;
+pub mod frame;
+#[cfg(feature = "headless")]
+pub mod headless;
+pub mod ips;
+pub mod joypad;
+pub mod mapper;
+pub mod mapper_registry;
+pub mod mask;
+pub mod metrics;
+pub mod mock_bus;
+pub mod opcode;
+pub mod palette;
+pub mod palette_animation;
+pub mod ppu;
+pub mod render;
+pub mod rom_db;
+pub mod save_state;
+pub mod scroll;
+pub mod status;
+pub mod test_harness;
+pub mod trace;
@@ -1,43 +1,79 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env;
+use std::error::Error;
+use std::path::Path;
+use std::rc::Rc;
 
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Texture, WindowCanvas};
+use sdl2::EventPump;
 
-use crate::bus::Bus;
-use crate::cartoridge::Rom;
-use crate::frame::Frame;
-use crate::joypad::Joypad;
-use crate::ppu::NesPPU;
-use cpu::CPU;
-
-pub mod bus;
-pub mod cartoridge;
-pub mod control;
-pub mod cpu;
-pub mod frame;
-pub mod joypad;
-pub mod mask;
-pub mod opcode;
-pub mod palette;
-pub mod ppu;
-pub mod render;
-pub mod scroll;
-pub mod status;
-pub mod trace;
-
-fn main() {
+use rustnes::apu;
+use rustnes::audio::{AudioOutput, WavWriter};
+use rustnes::bus::Bus;
+use rustnes::cartoridge::Rom;
+use rustnes::cpu::CPU;
+use rustnes::debugger::Debugger;
+use rustnes::frame::Frame;
+use rustnes::ips::IpsPatch;
+use rustnes::joypad::{self, Joypad};
+use rustnes::metrics::InputLatencyTracker;
+use rustnes::palette_animation::{self, PaletteAnimator};
+use rustnes::ppu::NesPPU;
+use rustnes::render::{self, DebugConfig};
+use rustnes::rom_db::{self, RomDatabase};
+
+fn main() -> Result<(), Box<dyn Error>> {
     const LOGICAL_WIDTH: u32 = 256;
     const LOGICAL_HEIGHT: u32 = 240;
     const WINDOW_SCALE: u32 = 3;
+    const LATENCY_PRESS_FRAME: u64 = 120;
+    const FLAGS_WITH_VALUE: [&str; 3] = ["--record-audio", "--patch", "--sample-rate"];
 
     let args: Vec<String> = env::args().collect();
-    let rom_path = if args.len() >= 2 {
-        &args[1]
-    } else {
-        "./test/sample/helloworld.nes"
+    let debug = args.iter().skip(1).any(|arg| arg == "--debug");
+    let demo = args.iter().skip(1).any(|arg| arg == "--demo");
+    let measure_latency = args.iter().skip(1).any(|arg| arg == "--measure-latency");
+    let debug_config = DebugConfig {
+        show_sprites: args.iter().skip(1).any(|arg| arg == "--show-sprites"),
+    };
+    let flag_value = |flag: &str| {
+        args.iter().position(|arg| arg == flag).map(|i| {
+            args.get(i + 1)
+                .unwrap_or_else(|| panic!("{} needs a value", flag))
+                .as_str()
+        })
     };
+    let record_audio_path = flag_value("--record-audio");
+    let patch_path = flag_value("--patch");
+    let sample_rate = flag_value("--sample-rate")
+        .map(|rate| rate.parse::<u32>())
+        .transpose()?
+        .unwrap_or(apu::SAMPLE_RATE);
+    let rom_path = args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find(|(i, arg)| {
+            !arg.starts_with("--") && !FLAGS_WITH_VALUE.contains(&args[i - 1].as_str())
+        })
+        .map(|(_, arg)| arg.as_str())
+        .unwrap_or("./test/sample/helloworld.nes");
+
+    if args.iter().skip(1).any(|arg| arg == "--identify") {
+        let bytes = std::fs::read(rom_path)?;
+        let hash = rom_db::sha256(&bytes);
+        println!("SHA-256: {}", rom_db::hash_to_hex(&hash));
+        match RomDatabase::new().lookup(&hash) {
+            Some(entry) => println!("{:?}", entry),
+            None => println!("Unknown ROM"),
+        }
+        return Ok(());
+    }
 
     let mut key_map = HashMap::new();
     key_map.insert(Keycode::Down, joypad::JoypadButton::DOWN);
@@ -49,13 +85,24 @@ fn main() {
     key_map.insert(Keycode::A, joypad::JoypadButton::BUTTON_A);
     key_map.insert(Keycode::S, joypad::JoypadButton::BUTTON_B);
 
+    // player 2 on the other side of the keyboard
+    let mut key_map2 = HashMap::new();
+    key_map2.insert(Keycode::K, joypad::JoypadButton::DOWN);
+    key_map2.insert(Keycode::I, joypad::JoypadButton::UP);
+    key_map2.insert(Keycode::L, joypad::JoypadButton::RIGHT);
+    key_map2.insert(Keycode::J, joypad::JoypadButton::LEFT);
+    key_map2.insert(Keycode::T, joypad::JoypadButton::SELECT);
+    key_map2.insert(Keycode::Y, joypad::JoypadButton::START);
+    key_map2.insert(Keycode::G, joypad::JoypadButton::BUTTON_A);
+    key_map2.insert(Keycode::H, joypad::JoypadButton::BUTTON_B);
+
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
         .window(
             "NES Emulator",
-            (LOGICAL_WIDTH * WINDOW_SCALE) as u32,
-            (LOGICAL_HEIGHT * WINDOW_SCALE) as u32,
+            LOGICAL_WIDTH * WINDOW_SCALE,
+            LOGICAL_HEIGHT * WINDOW_SCALE,
         )
         .position_centered()
         .build()
@@ -66,18 +113,66 @@ fn main() {
         .set_scale(WINDOW_SCALE as f32, WINDOW_SCALE as f32)
         .unwrap();
 
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let desired_spec = AudioSpecDesired {
+        freq: Some(sample_rate as i32),
+        channels: Some(1),
+        samples: None,
+    };
+    let audio_queue: AudioQueue<f32> = audio_subsystem.open_queue(None, &desired_spec).unwrap();
+    audio_queue.resume();
+    let mut audio_output = AudioOutput::new(audio_queue, sample_rate);
+
     let creator = canvas.texture_creator();
     let mut texture = creator
         .create_texture_target(PixelFormatEnum::RGB24, LOGICAL_WIDTH, LOGICAL_HEIGHT)
         .unwrap();
 
-    let bytes = std::fs::read(rom_path).unwrap();
-    let rom = Rom::new(&bytes).unwrap();
+    let rom = match patch_path {
+        Some(patch_path) => {
+            let mut bytes = std::fs::read(rom_path)?;
+            IpsPatch::load(Path::new(patch_path))?.apply(&mut bytes);
+            Rom::from_bytes(&bytes)
+        }
+        None => Rom::from_file(rom_path),
+    };
+    // returning the error from main would print its Debug form
+    let rom = rom.unwrap_or_else(|err| {
+        eprintln!("Failed to load ROM: {}", err);
+        std::process::exit(1);
+    });
+
+    if demo {
+        run_demo(rom, &mut canvas, &mut texture, &mut event_pump);
+        return Ok(());
+    }
 
     let mut frame = Frame::new();
+    let mut latency_tracker = measure_latency.then(InputLatencyTracker::new);
 
-    let bus = Bus::new(rom, move |ppu: &NesPPU, joypad: &mut Joypad| {
-        render::render(ppu, &mut frame);
+    // shared by the audio callback writing samples and the game loop finalizing on quit
+    let recorder =
+        Rc::new(RefCell::new(record_audio_path.map(|path| {
+            WavWriter::new(Path::new(path), sample_rate).unwrap()
+        })));
+    let quit_recorder = Rc::clone(&recorder);
+
+    let gameloop_callback = move |ppu: &NesPPU, joypad1: &mut Joypad, joypad2: &mut Joypad| {
+        joypad1.advance_frame();
+        joypad2.advance_frame();
+        ppu.generate_frame(&mut frame);
+        render::render_debug_overlay(ppu, &mut frame, &debug_config);
+        if let Some(tracker) = latency_tracker.as_mut() {
+            if let Some(latency) = tracker.on_frame_rendered(&frame) {
+                println!("Input latency: {} frames", latency);
+                std::process::exit(0);
+            }
+            // let the game boot before pressing START
+            if tracker.frame_count() == LATENCY_PRESS_FRAME {
+                joypad1.set_button(joypad::JoypadButton::START, true);
+                tracker.on_button_press(tracker.frame_count());
+            }
+        }
         texture.update(None, &frame.data, 256 * 3).unwrap();
 
         canvas.copy(&texture, None, None).unwrap();
@@ -88,23 +183,102 @@ fn main() {
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => std::process::exit(0),
+                } => {
+                    if let Some(writer) = quit_recorder.borrow_mut().take() {
+                        writer.finalize().unwrap();
+                    }
+                    std::process::exit(0)
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Z),
+                    ..
+                } => joypad1.set_turbo_a(true),
+                Event::KeyDown {
+                    keycode: Some(Keycode::X),
+                    ..
+                } => joypad1.set_turbo_b(true),
+                Event::KeyUp {
+                    keycode: Some(Keycode::Z),
+                    ..
+                } => joypad1.set_turbo_a(false),
+                Event::KeyUp {
+                    keycode: Some(Keycode::X),
+                    ..
+                } => joypad1.set_turbo_b(false),
                 Event::KeyDown { keycode, .. } => {
-                    if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, true);
+                    let keycode = keycode.unwrap_or(Keycode::Ampersand);
+                    if let Some(key) = key_map.get(&keycode) {
+                        joypad1.set_button(*key, true);
+                    }
+                    if let Some(key) = key_map2.get(&keycode) {
+                        joypad2.set_button(*key, true);
                     }
                 }
                 Event::KeyUp { keycode, .. } => {
-                    if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, false);
+                    let keycode = keycode.unwrap_or(Keycode::Ampersand);
+                    if let Some(key) = key_map.get(&keycode) {
+                        joypad1.set_button(*key, false);
+                    }
+                    if let Some(key) = key_map2.get(&keycode) {
+                        joypad2.set_button(*key, false);
                     }
                 }
                 _ => { /* nop */ }
             }
         }
-    });
+    };
+
+    let audio_callback = move |samples: &[f32]| {
+        audio_output.push_samples(samples);
+        if let Some(writer) = recorder.borrow_mut().as_mut() {
+            writer.write_samples(samples).unwrap();
+        }
+    };
+
+    let mut bus = Bus::new_with_audio(rom, gameloop_callback, audio_callback);
+    bus.set_sample_rate(sample_rate);
 
     let mut cpu = CPU::new(bus);
     cpu.reset();
-    cpu.run();
+    if debug {
+        Debugger::new(cpu).run_repl();
+    } else {
+        cpu.run();
+    }
+    Ok(())
+}
+
+// Shows the ROM's background tiles with the palette cycling through all 64 system colors.
+fn run_demo(
+    rom: Rom,
+    canvas: &mut WindowCanvas,
+    texture: &mut Texture,
+    event_pump: &mut EventPump,
+) {
+    let mut ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+    // show the background including the leftmost column
+    ppu.mask.update(0b0000_1010);
+    for (i, tile) in ppu.vram[..0x3c0].iter_mut().enumerate() {
+        *tile = i as u8;
+    }
+    let mut animator = PaletteAnimator::new(&mut ppu, palette_animation::all_colors_demo());
+    let mut frame = Frame::new();
+
+    loop {
+        animator.ppu().generate_frame(&mut frame);
+        texture.update(None, &frame.data, 256 * 3).unwrap();
+        canvas.copy(texture, None, None).unwrap();
+        canvas.present();
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => return,
+                _ => { /* nop */ }
+            }
+        }
+        animator.advance_frame();
+    }
 }
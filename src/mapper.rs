@@ -0,0 +1,1434 @@
+const PRG_BANK_SIZE_8K: usize = 8 * 1024;
+const PRG_BANK_SIZE_16K: usize = 16 * 1024;
+const PRG_BANK_SIZE_32K: usize = 32 * 1024;
+const CHR_BANK_SIZE_1K: usize = 1024;
+const CHR_BANK_SIZE_4K: usize = 4 * 1024;
+const CHR_BANK_SIZE_8K: usize = 8 * 1024;
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cartoridge::Mirroring;
+
+#[derive(Debug, PartialEq)]
+pub enum MapperStateError {
+    // not a bincode-encoded MapperState
+    Malformed,
+    // the state of another mapper
+    WrongMapper,
+    // a register the mapper can't hold, e.g. a bank past the end of the ROM
+    OutOfRange {
+        register: &'static str,
+        value: usize,
+    },
+}
+
+impl fmt::Display for MapperStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapperStateError::Malformed => write!(f, "Mapper state is malformed"),
+            MapperStateError::WrongMapper => write!(f, "Mapper state is for another mapper"),
+            MapperStateError::OutOfRange { register, value } => {
+                write!(f, "Mapper state has {} out of range: {}", register, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MapperStateError {}
+
+// the registers save_state encodes with bincode, the variant tells which mapper they belong to
+#[derive(Serialize, Deserialize)]
+enum MapperState {
+    Mmc1 {
+        shift_register: u8,
+        write_count: u8,
+        control: u8,
+        chr_bank_0: u8,
+        chr_bank_1: u8,
+        prg_bank: u8,
+    },
+    Uxrom {
+        prg_bank: u8,
+    },
+    Cnrom {
+        chr_bank: u8,
+    },
+    Gxrom {
+        prg_bank: u8,
+        chr_bank: u8,
+    },
+    Mmc3 {
+        bank_select: u8,
+        registers: [u8; 8],
+        irq_latch: u8,
+        irq_counter: u8,
+        irq_reload: bool,
+        irq_enabled: bool,
+        irq_pending: bool,
+        mirroring: Option<Mirroring>,
+    },
+    Mmc5 {
+        prg_mode: u8,
+        chr_mode: u8,
+        prg_banks: [u8; 4],
+        chr_banks: [u8; 8],
+    },
+    Axrom {
+        prg_bank: u8,
+        upper_nametable: bool,
+    },
+}
+
+impl MapperState {
+    fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("mapper state is plain data")
+    }
+
+    fn decode(data: &[u8]) -> Result<MapperState, MapperStateError> {
+        bincode::deserialize(data).map_err(|_| MapperStateError::Malformed)
+    }
+}
+
+fn check_range(register: &'static str, value: u8, limit: usize) -> Result<(), MapperStateError> {
+    if (value as usize) < limit {
+        Ok(())
+    } else {
+        Err(MapperStateError::OutOfRange {
+            register,
+            value: value as usize,
+        })
+    }
+}
+
+pub trait Mapper {
+    // the implementing type, e.g. rustnes::mapper::Mmc1
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    fn prg_read(&self, addr: u16) -> u8;
+    fn prg_write(&mut self, addr: u16, data: u8);
+
+    // number of banks in the smallest unit the mapper switches, for ROM info
+    fn prg_bank_count(&self) -> usize;
+    fn chr_bank_count(&self) -> usize;
+
+    // mapper registers in the expansion area $5000-$5FFF
+    fn expansion_read(&self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn expansion_write(&mut self, _addr: u16, _data: u8) {}
+
+    // maps a PPU pattern table address $0000-$1FFF to an offset in CHR ROM
+    fn chr_addr(&self, addr: u16) -> usize {
+        addr as usize
+    }
+
+    // nametable mirroring for mappers that switch it, None keeps the cartridge's
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    // called at the end of each rendered scanline, for mappers counting scanlines
+    fn on_scanline(&mut self) {}
+
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // called when the CPU enters the IRQ handler
+    fn irq_acknowledge(&mut self) {}
+
+    // bank registers and latches for save states, a bincode-encoded MapperState
+    // mappers without registers save nothing
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), MapperStateError> {
+        if data.is_empty() {
+            Ok(())
+        } else {
+            Err(MapperStateError::WrongMapper)
+        }
+    }
+
+    // back to the power-on banking
+    fn reset(&mut self) {}
+}
+
+// Mapper 0
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+}
+
+impl Nrom {
+    pub fn new(prg_rom: Vec<u8>) -> Self {
+        Nrom { prg_rom }
+    }
+}
+
+impl Mapper for Nrom {
+    fn prg_read(&self, addr: u16) -> u8 {
+        let mut addr = addr - 0x8000;
+        // 16KB PRG ROM is mirrored at $C000
+        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
+            addr %= 0x4000;
+        }
+        self.prg_rom[addr as usize]
+    }
+
+    fn prg_write(&mut self, _addr: u16, _data: u8) {
+        panic!("Attempt to write to Cartridge ROM space")
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE_16K
+    }
+
+    fn chr_bank_count(&self) -> usize {
+        1
+    }
+}
+
+// Mapper 1
+// https://www.nesdev.org/wiki/MMC1
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    chr_rom_size: usize,
+    // bits arrive LSB first, a register is written on the 5th write
+    shift_register: u8,
+    write_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom_size: usize) -> Self {
+        Mmc1 {
+            prg_rom,
+            // CHR RAM carts report no CHR ROM but have 8KB of RAM
+            chr_rom_size: chr_rom_size.max(CHR_BANK_SIZE_8K),
+            shift_register: 0,
+            write_count: 0,
+            // PRG mode 3 (last bank fixed at $C000) on power up
+            control: 0x0c,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn prg_mode(&self) -> u8 {
+        (self.control >> 2) & 0b11
+    }
+
+    // 16KB bank number for $8000-$BFFF or $C000-$FFFF
+    fn prg_bank(&self, addr: u16) -> usize {
+        let bank = (self.prg_bank & 0x0f) as usize;
+        let last = self.prg_bank_count() - 1;
+        let bank = match (self.prg_mode(), addr) {
+            // 32KB mode ignores the low bit of the bank number
+            (0 | 1, 0x8000..=0xbfff) => bank & !1,
+            (0 | 1, _) => bank | 1,
+            (2, 0x8000..=0xbfff) => 0,
+            (2, _) => bank,
+            (_, 0x8000..=0xbfff) => bank,
+            (_, _) => last,
+        };
+        bank % self.prg_bank_count()
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn prg_read(&self, addr: u16) -> u8 {
+        let offset = (addr as usize) % PRG_BANK_SIZE_16K;
+        self.prg_rom[self.prg_bank(addr) * PRG_BANK_SIZE_16K + offset]
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) {
+        if data & 0x80 != 0 {
+            self.shift_register = 0;
+            self.write_count = 0;
+            self.control |= 0x0c;
+            return;
+        }
+
+        self.shift_register |= (data & 1) << self.write_count;
+        self.write_count += 1;
+        if self.write_count < 5 {
+            return;
+        }
+        let value = self.shift_register;
+        self.shift_register = 0;
+        self.write_count = 0;
+        match addr {
+            0x8000..=0x9fff => self.control = value,
+            0xa000..=0xbfff => self.chr_bank_0 = value,
+            0xc000..=0xdfff => self.chr_bank_1 = value,
+            _ => self.prg_bank = value,
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_SIZE_16K).max(1)
+    }
+
+    fn chr_bank_count(&self) -> usize {
+        self.chr_rom_size / CHR_BANK_SIZE_4K
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let addr = addr as usize;
+        let bank = if self.control & 0x10 == 0 {
+            // 8KB mode ignores the low bit of the bank number
+            (self.chr_bank_0 & !1) as usize + addr / CHR_BANK_SIZE_4K
+        } else if addr < CHR_BANK_SIZE_4K {
+            self.chr_bank_0 as usize
+        } else {
+            self.chr_bank_1 as usize
+        };
+        (bank * CHR_BANK_SIZE_4K + addr % CHR_BANK_SIZE_4K) % self.chr_rom_size
+    }
+
+    // bits 1-0 of the control register
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        })
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        MapperState::Mmc1 {
+            shift_register: self.shift_register,
+            write_count: self.write_count,
+            control: self.control,
+            chr_bank_0: self.chr_bank_0,
+            chr_bank_1: self.chr_bank_1,
+            prg_bank: self.prg_bank,
+        }
+        .encode()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), MapperStateError> {
+        let MapperState::Mmc1 {
+            shift_register,
+            write_count,
+            control,
+            chr_bank_0,
+            chr_bank_1,
+            prg_bank,
+        } = MapperState::decode(data)?
+        else {
+            return Err(MapperStateError::WrongMapper);
+        };
+        // the banks wrap on read, only the shift position has to be valid
+        check_range("write_count", write_count, 5)?;
+        self.shift_register = shift_register;
+        self.write_count = write_count;
+        self.control = control;
+        self.chr_bank_0 = chr_bank_0;
+        self.chr_bank_1 = chr_bank_1;
+        self.prg_bank = prg_bank;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        *self = Mmc1::new(std::mem::take(&mut self.prg_rom), self.chr_rom_size);
+    }
+}
+
+// Mapper 2
+// https://www.nesdev.org/wiki/UxROM
+pub struct Uxrom {
+    prg_rom: Vec<u8>,
+    prg_bank: u8,
+}
+
+impl Uxrom {
+    pub fn new(prg_rom: Vec<u8>) -> Self {
+        Uxrom {
+            prg_rom,
+            prg_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    // switchable bank at $8000, last bank fixed at $C000
+    fn prg_read(&self, addr: u16) -> u8 {
+        let bank = match addr {
+            0x8000..=0xbfff => self.prg_bank as usize,
+            _ => self.prg_bank_count() - 1,
+        };
+        self.prg_rom[bank * PRG_BANK_SIZE_16K + (addr as usize) % PRG_BANK_SIZE_16K]
+    }
+
+    // bank numbers wrap around the PRG ROM size
+    fn prg_write(&mut self, _addr: u16, data: u8) {
+        self.prg_bank = (data as usize % self.prg_bank_count()) as u8;
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_SIZE_16K).max(1)
+    }
+
+    fn chr_bank_count(&self) -> usize {
+        1
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        MapperState::Uxrom {
+            prg_bank: self.prg_bank,
+        }
+        .encode()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), MapperStateError> {
+        let MapperState::Uxrom { prg_bank } = MapperState::decode(data)? else {
+            return Err(MapperStateError::WrongMapper);
+        };
+        check_range("prg_bank", prg_bank, self.prg_bank_count())?;
+        self.prg_bank = prg_bank;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank = 0;
+    }
+}
+
+// Mapper 3
+// https://www.nesdev.org/wiki/CNROM
+pub struct Cnrom {
+    nrom: Nrom,
+    chr_bank_count: usize,
+    chr_bank: u8,
+}
+
+impl Cnrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom_size: usize) -> Self {
+        Cnrom {
+            nrom: Nrom::new(prg_rom),
+            chr_bank_count: (chr_rom_size / CHR_BANK_SIZE_8K).max(1),
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn prg_read(&self, addr: u16) -> u8 {
+        self.nrom.prg_read(addr)
+    }
+
+    // bank numbers wrap around the CHR ROM size
+    fn prg_write(&mut self, _addr: u16, data: u8) {
+        self.chr_bank = (data as usize % self.chr_bank_count) as u8;
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.nrom.prg_bank_count()
+    }
+
+    fn chr_bank_count(&self) -> usize {
+        self.chr_bank_count
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        self.chr_bank as usize * CHR_BANK_SIZE_8K + addr as usize
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        MapperState::Cnrom {
+            chr_bank: self.chr_bank,
+        }
+        .encode()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), MapperStateError> {
+        let MapperState::Cnrom { chr_bank } = MapperState::decode(data)? else {
+            return Err(MapperStateError::WrongMapper);
+        };
+        check_range("chr_bank", chr_bank, self.chr_bank_count)?;
+        self.chr_bank = chr_bank;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.chr_bank = 0;
+    }
+}
+
+// Mapper 7
+// https://www.nesdev.org/wiki/AxROM
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    prg_bank: u8,
+    // bit 4 of the bank register, which 1KB of VRAM all nametables show
+    upper_nametable: bool,
+}
+
+impl Axrom {
+    pub fn new(prg_rom: Vec<u8>) -> Self {
+        Axrom {
+            prg_rom,
+            prg_bank: 0,
+            upper_nametable: false,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn prg_read(&self, addr: u16) -> u8 {
+        let offset = (addr - 0x8000) as usize % self.prg_rom.len().min(PRG_BANK_SIZE_32K);
+        self.prg_rom[self.prg_bank as usize * PRG_BANK_SIZE_32K + offset]
+    }
+
+    // 32KB PRG bank in bits 2-0, wrapped around the ROM size
+    fn prg_write(&mut self, _addr: u16, data: u8) {
+        self.prg_bank = ((data & 0b111) as usize % self.prg_bank_count()) as u8;
+        self.upper_nametable = data & 0b1_0000 != 0;
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_SIZE_32K).max(1)
+    }
+
+    // AxROM boards carry 8KB of CHR RAM
+    fn chr_bank_count(&self) -> usize {
+        1
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(if self.upper_nametable {
+            Mirroring::SingleScreenUpper
+        } else {
+            Mirroring::SingleScreenLower
+        })
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        MapperState::Axrom {
+            prg_bank: self.prg_bank,
+            upper_nametable: self.upper_nametable,
+        }
+        .encode()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), MapperStateError> {
+        let MapperState::Axrom {
+            prg_bank,
+            upper_nametable,
+        } = MapperState::decode(data)?
+        else {
+            return Err(MapperStateError::WrongMapper);
+        };
+        check_range("prg_bank", prg_bank, self.prg_bank_count())?;
+        self.prg_bank = prg_bank;
+        self.upper_nametable = upper_nametable;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank = 0;
+        self.upper_nametable = false;
+    }
+}
+
+// Mapper 66
+// https://www.nesdev.org/wiki/GxROM
+pub struct Gxrom {
+    prg_rom: Vec<u8>,
+    chr_bank_count: usize,
+    prg_bank: u8,
+    chr_bank: u8,
+}
+
+impl Gxrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom_size: usize) -> Self {
+        Gxrom {
+            prg_rom,
+            chr_bank_count: (chr_rom_size / CHR_BANK_SIZE_8K).max(1),
+            prg_bank: 0,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Gxrom {
+    fn prg_read(&self, addr: u16) -> u8 {
+        let offset = (addr - 0x8000) as usize % self.prg_rom.len().min(PRG_BANK_SIZE_32K);
+        self.prg_rom[self.prg_bank as usize * PRG_BANK_SIZE_32K + offset]
+    }
+
+    // PRG bank in bits 5-4, CHR bank in bits 1-0, wrapped around the ROM sizes
+    fn prg_write(&mut self, _addr: u16, data: u8) {
+        self.prg_bank = (((data >> 4) & 0b11) as usize % self.prg_bank_count()) as u8;
+        self.chr_bank = ((data & 0b11) as usize % self.chr_bank_count) as u8;
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_SIZE_32K).max(1)
+    }
+
+    fn chr_bank_count(&self) -> usize {
+        self.chr_bank_count
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        self.chr_bank as usize * CHR_BANK_SIZE_8K + addr as usize
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        MapperState::Gxrom {
+            prg_bank: self.prg_bank,
+            chr_bank: self.chr_bank,
+        }
+        .encode()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), MapperStateError> {
+        let MapperState::Gxrom { prg_bank, chr_bank } = MapperState::decode(data)? else {
+            return Err(MapperStateError::WrongMapper);
+        };
+        check_range("prg_bank", prg_bank, self.prg_bank_count())?;
+        check_range("chr_bank", chr_bank, self.chr_bank_count)?;
+        self.prg_bank = prg_bank;
+        self.chr_bank = chr_bank;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank = 0;
+        self.chr_bank = 0;
+    }
+}
+
+// Mapper 4
+// https://www.nesdev.org/wiki/MMC3
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    chr_rom_size: usize,
+    bank_select: u8,
+    registers: [u8; 8],
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    // set by $A000, the cartridge's mirroring applies until the first write
+    mirroring: Option<Mirroring>,
+}
+
+impl Mmc3 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom_size: usize) -> Self {
+        Mmc3 {
+            prg_rom,
+            chr_rom_size: chr_rom_size.max(CHR_BANK_SIZE_8K),
+            bank_select: 0,
+            registers: [0; 8],
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            mirroring: None,
+        }
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        let second_last = self.prg_bank_count() - 2;
+        let swap_mode = self.bank_select & 0b0100_0000 != 0;
+        let bank = match (addr, swap_mode) {
+            (0x8000..=0x9fff, false) => self.registers[6] as usize,
+            (0x8000..=0x9fff, true) => second_last,
+            (0xa000..=0xbfff, _) => self.registers[7] as usize,
+            (0xc000..=0xdfff, false) => second_last,
+            (0xc000..=0xdfff, true) => self.registers[6] as usize,
+            _ => self.prg_bank_count() - 1,
+        };
+        bank % self.prg_bank_count()
+    }
+
+    fn chr_bank(&self, addr: u16) -> usize {
+        // CHR mode 1 swaps the 2KB banks of R0/R1 to $1000 and the 1KB banks of R2-R5 to $0000
+        let chr_mode = self.bank_select & 0b1000_0000 != 0;
+        let slot = (addr as usize ^ if chr_mode { 0x1000 } else { 0 }) / CHR_BANK_SIZE_1K;
+        match slot {
+            // R0 and R1 ignore the lowest bit and select a 2KB pair
+            0..=3 => (self.registers[slot / 2] & !1) as usize + slot % 2,
+            _ => self.registers[slot - 2] as usize,
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn prg_read(&self, addr: u16) -> u8 {
+        let offset = (addr as usize) % PRG_BANK_SIZE_8K;
+        self.prg_rom[self.prg_bank(addr) * PRG_BANK_SIZE_8K + offset]
+    }
+
+    fn prg_write(&mut self, addr: u16, data: u8) {
+        let even = addr.is_multiple_of(2);
+        match (addr, even) {
+            (0x8000..=0x9fff, true) => self.bank_select = data,
+            (0x8000..=0x9fff, false) => {
+                self.registers[(self.bank_select & 0b111) as usize] = data;
+            }
+            (0xa000..=0xbfff, true) => {
+                self.mirroring = Some(if data & 1 == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                });
+            }
+            (0xa000..=0xbfff, false) => {
+                // TODO PRG RAM protect
+            }
+            (0xc000..=0xdfff, true) => self.irq_latch = data,
+            (0xc000..=0xdfff, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xe000..=0xffff, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (0xe000..=0xffff, false) => self.irq_enabled = true,
+            _ => panic!("unexpected MMC3 write at {:04x}", addr),
+        }
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let offset = addr as usize % CHR_BANK_SIZE_1K;
+        (self.chr_bank(addr) * CHR_BANK_SIZE_1K + offset) % self.chr_rom_size
+    }
+
+    fn on_scanline(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE_8K
+    }
+
+    fn chr_bank_count(&self) -> usize {
+        self.chr_rom_size / CHR_BANK_SIZE_1K
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn irq_acknowledge(&mut self) {
+        self.irq_pending = false;
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        self.mirroring.clone()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        MapperState::Mmc3 {
+            bank_select: self.bank_select,
+            registers: self.registers,
+            irq_latch: self.irq_latch,
+            irq_counter: self.irq_counter,
+            irq_reload: self.irq_reload,
+            irq_enabled: self.irq_enabled,
+            irq_pending: self.irq_pending,
+            mirroring: self.mirroring.clone(),
+        }
+        .encode()
+    }
+
+    // every register value is valid, the banks wrap on read
+    fn load_state(&mut self, data: &[u8]) -> Result<(), MapperStateError> {
+        let MapperState::Mmc3 {
+            bank_select,
+            registers,
+            irq_latch,
+            irq_counter,
+            irq_reload,
+            irq_enabled,
+            irq_pending,
+            mirroring,
+        } = MapperState::decode(data)?
+        else {
+            return Err(MapperStateError::WrongMapper);
+        };
+        self.bank_select = bank_select;
+        self.registers = registers;
+        self.irq_latch = irq_latch;
+        self.irq_counter = irq_counter;
+        self.irq_reload = irq_reload;
+        self.irq_enabled = irq_enabled;
+        self.irq_pending = irq_pending;
+        self.mirroring = mirroring;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        *self = Mmc3::new(std::mem::take(&mut self.prg_rom), self.chr_rom_size);
+    }
+}
+
+// Mapper 5, PRG/CHR banking only: no ExRAM, split screen, IRQ or PCM audio yet
+// https://www.nesdev.org/wiki/MMC5
+pub struct Mmc5 {
+    prg_rom: Vec<u8>,
+    chr_rom_size: usize,
+    prg_mode: u8,
+    chr_mode: u8,
+    // $5114-$5117
+    prg_banks: [u8; 4],
+    // $5120-$5127
+    chr_banks: [u8; 8],
+}
+
+impl Mmc5 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom_size: usize) -> Self {
+        Mmc5 {
+            prg_rom,
+            chr_rom_size: chr_rom_size.max(CHR_BANK_SIZE_8K),
+            // games expect PRG mode 3 with the last bank at $E000 on power up
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_banks: [0xff; 4],
+            chr_banks: [0; 8],
+        }
+    }
+
+    // 8KB bank number for $8000-$FFFF, bigger banks ignore the low bits of their register
+    fn prg_bank(&self, addr: u16) -> usize {
+        let slot = ((addr - 0x8000) as usize) / PRG_BANK_SIZE_8K;
+        let (register, banks) = match (self.prg_mode, slot) {
+            (0, _) => (3, 4),
+            (1, 0..=1) => (1, 2),
+            (1, _) => (3, 2),
+            (2, 0..=1) => (1, 2),
+            (_, _) => (slot, 1),
+        };
+        // bit 7 selects ROM or RAM, PRG RAM is not supported so it is ignored
+        let bank = (self.prg_banks[register] & 0x7f) as usize;
+        let bank = bank / banks * banks + slot % banks;
+        bank % self.prg_bank_count()
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn prg_read(&self, addr: u16) -> u8 {
+        let offset = (addr as usize) % PRG_BANK_SIZE_8K;
+        self.prg_rom[self.prg_bank(addr) * PRG_BANK_SIZE_8K + offset]
+    }
+
+    fn prg_write(&mut self, _addr: u16, _data: u8) {
+        // PRG RAM mapped into $8000-$DFFF is not supported
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE_8K
+    }
+
+    fn chr_bank_count(&self) -> usize {
+        self.chr_rom_size / CHR_BANK_SIZE_1K
+    }
+
+    fn expansion_read(&self, _addr: u16) -> u8 {
+        // ExRAM ($5C00-$5FFF) and the status registers are stubbed
+        0
+    }
+
+    fn expansion_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5100 => self.prg_mode = data & 0b11,
+            0x5101 => self.chr_mode = data & 0b11,
+            0x5114..=0x5117 => self.prg_banks[(addr - 0x5114) as usize] = data,
+            0x5120..=0x5127 => self.chr_banks[(addr - 0x5120) as usize] = data,
+            _ => {
+                // ExRAM, nametable mapping, split screen, IRQ and audio are ignored
+            }
+        }
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        // the bank size is 8KB, 4KB, 2KB or 1KB, and the last register of each bank is used
+        let bank_size = CHR_BANK_SIZE_8K >> self.chr_mode;
+        let slot = addr as usize / bank_size;
+        let register = (slot + 1) * (8 >> self.chr_mode) - 1;
+        let bank = self.chr_banks[register] as usize;
+        (bank * bank_size + addr as usize % bank_size) % self.chr_rom_size
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        MapperState::Mmc5 {
+            prg_mode: self.prg_mode,
+            chr_mode: self.chr_mode,
+            prg_banks: self.prg_banks,
+            chr_banks: self.chr_banks,
+        }
+        .encode()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), MapperStateError> {
+        let MapperState::Mmc5 {
+            prg_mode,
+            chr_mode,
+            prg_banks,
+            chr_banks,
+        } = MapperState::decode(data)?
+        else {
+            return Err(MapperStateError::WrongMapper);
+        };
+        check_range("prg_mode", prg_mode, 4)?;
+        check_range("chr_mode", chr_mode, 4)?;
+        self.prg_mode = prg_mode;
+        self.chr_mode = chr_mode;
+        self.prg_banks = prg_banks;
+        self.chr_banks = chr_banks;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        *self = Mmc5::new(std::mem::take(&mut self.prg_rom), self.chr_rom_size);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn prg_rom_8k_banks(count: u8) -> Vec<u8> {
+        (0..count)
+            .flat_map(|bank| vec![bank; PRG_BANK_SIZE_8K])
+            .collect()
+    }
+
+    #[test]
+    fn test_nrom_16k_mirroring() {
+        let mut prg_rom = vec![0; PRG_BANK_SIZE_16K];
+        prg_rom[0] = 0x11;
+        prg_rom[0x3ffc] = 0x22;
+        let mapper = Nrom::new(prg_rom);
+
+        assert_eq!(mapper.prg_read(0x8000), 0x11);
+        assert_eq!(mapper.prg_read(0xc000), 0x11);
+        assert_eq!(mapper.prg_read(0xbffc), 0x22);
+        assert_eq!(mapper.prg_read(0xfffc), 0x22);
+        assert_eq!(mapper.prg_bank_count(), 1);
+    }
+
+    #[test]
+    fn test_nrom_32k_not_mirrored() {
+        let mapper = Nrom::new(prg_rom_8k_banks(4));
+        assert_eq!(mapper.prg_read(0x8000), 0);
+        assert_eq!(mapper.prg_read(0xc000), 2);
+        assert_eq!(mapper.prg_read(0xffff), 3);
+    }
+
+    #[test]
+    fn test_reset_restores_power_on_banks() {
+        let mut mappers: Vec<Box<dyn Mapper>> = vec![
+            Box::new(Mmc1::new(prg_rom_8k_banks(8), 4 * CHR_BANK_SIZE_8K)),
+            Box::new(Uxrom::new(prg_rom_8k_banks(8))),
+            Box::new(Cnrom::new(prg_rom_8k_banks(4), 4 * CHR_BANK_SIZE_8K)),
+            Box::new(Gxrom::new(prg_rom_8k_banks(8), 4 * CHR_BANK_SIZE_8K)),
+            Box::new(Mmc3::new(prg_rom_8k_banks(8), 4 * CHR_BANK_SIZE_8K)),
+            Box::new(Mmc5::new(prg_rom_8k_banks(8), 4 * CHR_BANK_SIZE_8K)),
+            Box::new(Axrom::new(prg_rom_8k_banks(8))),
+        ];
+        for (i, mapper) in mappers.iter_mut().enumerate() {
+            let power_on = mapper.save_state();
+            mapper.prg_write(0x8000, 0x11);
+            mapper.prg_write(0x8001, 0x01);
+            mapper.expansion_write(0x5100, 0);
+            mapper.expansion_write(0x5120, 3);
+            assert_ne!(mapper.save_state(), power_on, "mapper {}", i);
+
+            mapper.reset();
+            assert_eq!(mapper.save_state(), power_on, "mapper {}", i);
+        }
+    }
+
+    fn mmc1_write(mapper: &mut Mmc1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.prg_write(addr, (value >> bit) & 1);
+        }
+    }
+
+    fn prg_rom_16k_banks(count: u8) -> Vec<u8> {
+        (0..count)
+            .flat_map(|bank| vec![bank; PRG_BANK_SIZE_16K])
+            .collect()
+    }
+
+    #[test]
+    fn test_mmc1_shift_register() {
+        let mut mapper = Mmc1::new(prg_rom_16k_banks(8), 8 * CHR_BANK_SIZE_4K);
+        mmc1_write(&mut mapper, 0xa000, 0b10101);
+        assert_eq!(mapper.chr_bank_0, 0b10101);
+        mmc1_write(&mut mapper, 0xc000, 0b00011);
+        assert_eq!(mapper.chr_bank_1, 0b00011);
+        mmc1_write(&mut mapper, 0xe000, 0b00110);
+        assert_eq!(mapper.prg_bank, 0b00110);
+        mmc1_write(&mut mapper, 0x8000, 0b10010);
+        assert_eq!(mapper.control, 0b10010);
+
+        // only the 5th write selects the register, by its address
+        mapper.prg_write(0x8000, 1);
+        mapper.prg_write(0x8000, 1);
+        mapper.prg_write(0x8000, 1);
+        mapper.prg_write(0x8000, 1);
+        mapper.prg_write(0xe000, 0);
+        assert_eq!(mapper.prg_bank, 0b01111);
+        assert_eq!(mapper.control, 0b10010);
+    }
+
+    #[test]
+    fn test_mmc1_mirroring() {
+        let mut mapper = Mmc1::new(prg_rom_16k_banks(2), 8 * CHR_BANK_SIZE_4K);
+        let modes = [
+            Mirroring::SingleScreenLower,
+            Mirroring::SingleScreenUpper,
+            Mirroring::Vertical,
+            Mirroring::Horizontal,
+        ];
+        for (bits, mirroring) in modes.into_iter().enumerate() {
+            mmc1_write(&mut mapper, 0x8000, 0b01100 | bits as u8);
+            assert_eq!(mapper.mirroring(), Some(mirroring));
+        }
+        // the reset bit keeps the mirroring
+        mapper.prg_write(0x8000, 0x80);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+    }
+
+    #[test]
+    fn test_mmc1_save_state() {
+        let mut mapper = Mmc1::new(prg_rom_16k_banks(8), 8 * CHR_BANK_SIZE_4K);
+        mapper.prg_write(0x8000, 1);
+        mapper.prg_write(0x8000, 0);
+        mapper.prg_write(0x8000, 1);
+        let state = mapper.save_state();
+
+        mapper.prg_write(0x8000, 0x80);
+        assert_eq!(mapper.shift_register, 0);
+        mapper.load_state(&state).unwrap();
+
+        assert_eq!(mapper.shift_register, 0b101);
+        assert_eq!(mapper.write_count, 3);
+    }
+
+    #[test]
+    fn test_load_state_errors() {
+        let mut mapper = Uxrom::new(prg_rom_16k_banks(4));
+        mapper.prg_write(0x8000, 3);
+
+        assert_eq!(mapper.load_state(&[]), Err(MapperStateError::Malformed));
+        let mmc1_state = Mmc1::new(prg_rom_16k_banks(4), CHR_BANK_SIZE_8K).save_state();
+        assert_eq!(
+            mapper.load_state(&mmc1_state),
+            Err(MapperStateError::WrongMapper)
+        );
+        // saved from a bigger ROM
+        let mut bigger = Uxrom::new(prg_rom_16k_banks(8));
+        bigger.prg_write(0x8000, 6);
+        assert_eq!(
+            mapper.load_state(&bigger.save_state()),
+            Err(MapperStateError::OutOfRange {
+                register: "prg_bank",
+                value: 6
+            })
+        );
+        // a failed load leaves the banks alone
+        assert_eq!(mapper.prg_read(0x8000), 3);
+    }
+
+    #[test]
+    fn test_mmc1_reset_bit() {
+        let mut mapper = Mmc1::new(prg_rom_16k_banks(8), 8 * CHR_BANK_SIZE_4K);
+        mmc1_write(&mut mapper, 0x8000, 0);
+        mapper.prg_write(0xe000, 1);
+        mapper.prg_write(0xe000, 1);
+        mapper.prg_write(0xe000, 0x80);
+        assert_eq!(mapper.control, 0x0c);
+
+        mmc1_write(&mut mapper, 0xe000, 2);
+        assert_eq!(mapper.prg_bank, 2);
+    }
+
+    #[test]
+    fn test_mmc1_prg_modes() {
+        let mut mapper = Mmc1::new(prg_rom_16k_banks(8), 8 * CHR_BANK_SIZE_4K);
+        mmc1_write(&mut mapper, 0xe000, 5);
+
+        // mode 3 on power up: switch $8000, fix the last bank at $C000
+        assert_eq!(mapper.prg_read(0x8000), 5);
+        assert_eq!(mapper.prg_read(0xffff), 7);
+
+        mmc1_write(&mut mapper, 0x8000, 0b01000);
+        assert_eq!(mapper.prg_read(0x8000), 0);
+        assert_eq!(mapper.prg_read(0xc000), 5);
+
+        // 32KB modes ignore the low bit
+        mmc1_write(&mut mapper, 0x8000, 0b00000);
+        assert_eq!(mapper.prg_read(0x8000), 4);
+        assert_eq!(mapper.prg_read(0xc000), 5);
+        mmc1_write(&mut mapper, 0x8000, 0b00100);
+        assert_eq!(mapper.prg_read(0xbfff), 4);
+        assert_eq!(mapper.prg_read(0xc000), 5);
+    }
+
+    #[test]
+    fn test_mmc1_chr_modes() {
+        let mut mapper = Mmc1::new(prg_rom_16k_banks(2), 8 * CHR_BANK_SIZE_4K);
+        mmc1_write(&mut mapper, 0xa000, 3);
+        mmc1_write(&mut mapper, 0xc000, 6);
+
+        // 8KB mode uses chr_bank_0 without its low bit
+        assert_eq!(mapper.chr_addr(0x0000), 2 * CHR_BANK_SIZE_4K);
+        assert_eq!(mapper.chr_addr(0x1001), 3 * CHR_BANK_SIZE_4K + 1);
+
+        mmc1_write(&mut mapper, 0x8000, 0b11100);
+        assert_eq!(mapper.chr_addr(0x0005), 3 * CHR_BANK_SIZE_4K + 5);
+        assert_eq!(mapper.chr_addr(0x1005), 6 * CHR_BANK_SIZE_4K + 5);
+
+        // CHR RAM carts have a single 8KB bank
+        let mut mapper = Mmc1::new(prg_rom_16k_banks(2), 0);
+        mmc1_write(&mut mapper, 0x8000, 0b11100);
+        mmc1_write(&mut mapper, 0xc000, 1);
+        assert_eq!(mapper.chr_addr(0x1000), CHR_BANK_SIZE_4K);
+        assert_eq!(mapper.chr_bank_count(), 2);
+    }
+
+    #[test]
+    fn test_uxrom_prg_banks() {
+        let mut mapper = Uxrom::new(prg_rom_16k_banks(4));
+        assert_eq!(mapper.prg_read(0x8000), 0);
+        assert_eq!(mapper.prg_read(0xc000), 3);
+
+        mapper.prg_write(0x8000, 2);
+        assert_eq!(mapper.prg_read(0x8000), 2);
+        assert_eq!(mapper.prg_read(0xbfff), 2);
+        assert_eq!(mapper.prg_read(0xffff), 3);
+
+        // any address in $8000-$FFFF selects the bank, wrapping around the ROM size
+        mapper.prg_write(0xd123, 5);
+        assert_eq!(mapper.prg_read(0x8000), 1);
+    }
+
+    #[test]
+    fn test_cnrom_chr_banks() {
+        let mut mapper = Cnrom::new(prg_rom_8k_banks(2), 4 * CHR_BANK_SIZE_8K);
+        assert_eq!(mapper.chr_addr(0x1000), 0x1000);
+
+        mapper.prg_write(0x8000, 2);
+        assert_eq!(mapper.chr_addr(0x0000), 2 * CHR_BANK_SIZE_8K);
+        assert_eq!(mapper.chr_addr(0x1fff), 3 * CHR_BANK_SIZE_8K - 1);
+
+        // bank numbers wrap around the CHR ROM size
+        mapper.prg_write(0x8000, 5);
+        assert_eq!(mapper.chr_addr(0x0000), CHR_BANK_SIZE_8K);
+        assert_eq!(mapper.prg_read(0x8000), 0);
+        assert_eq!(mapper.prg_read(0xc000), 0);
+    }
+
+    #[test]
+    fn test_bank_counts() {
+        let nrom = Nrom::new(prg_rom_8k_banks(2));
+        assert_eq!((nrom.prg_bank_count(), nrom.chr_bank_count()), (1, 1));
+        let cnrom = Cnrom::new(prg_rom_8k_banks(4), 4 * CHR_BANK_SIZE_8K);
+        assert_eq!((cnrom.prg_bank_count(), cnrom.chr_bank_count()), (2, 4));
+        let gxrom = Gxrom::new(prg_rom_8k_banks(16), 2 * CHR_BANK_SIZE_8K);
+        assert_eq!((gxrom.prg_bank_count(), gxrom.chr_bank_count()), (4, 2));
+        let mmc3 = Mmc3::new(prg_rom_8k_banks(8), CHR_BANK_SIZE_8K);
+        assert_eq!((mmc3.prg_bank_count(), mmc3.chr_bank_count()), (8, 8));
+        let mmc5 = Mmc5::new(prg_rom_8k_banks(16), 2 * CHR_BANK_SIZE_8K);
+        assert_eq!((mmc5.prg_bank_count(), mmc5.chr_bank_count()), (16, 16));
+    }
+
+    #[test]
+    fn test_bank_number_wraps() {
+        let mut mapper = Cnrom::new(prg_rom_8k_banks(2), 4 * CHR_BANK_SIZE_8K);
+        mapper.prg_write(0x8000, 15);
+        assert_eq!(mapper.chr_addr(0x0000), 3 * CHR_BANK_SIZE_8K);
+
+        let mut mapper = Mmc3::new(prg_rom_8k_banks(4), CHR_BANK_SIZE_8K);
+        mapper.prg_write(0x8000, 6);
+        mapper.prg_write(0x8001, 15);
+        assert_eq!(mapper.prg_read(0x8000), 3);
+    }
+
+    #[test]
+    fn test_gxrom_banks() {
+        let mut mapper = Gxrom::new(prg_rom_8k_banks(16), 4 * CHR_BANK_SIZE_8K);
+        for prg in 0..4u8 {
+            for chr in 0..4u8 {
+                mapper.prg_write(0x8000 + (prg as u16) * 0x1000, prg << 4 | chr);
+
+                assert_eq!(mapper.prg_read(0x8000), prg * 4);
+                assert_eq!(mapper.prg_read(0xffff), prg * 4 + 3);
+                assert_eq!(mapper.chr_addr(0x0000), chr as usize * CHR_BANK_SIZE_8K);
+                assert_eq!(
+                    mapper.chr_addr(0x1fff),
+                    (chr as usize + 1) * CHR_BANK_SIZE_8K - 1
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_axrom_banks() {
+        let mut mapper = Axrom::new(prg_rom_8k_banks(32));
+        assert_eq!(mapper.prg_read(0x8000), 0);
+        assert_eq!(mapper.prg_read(0xffff), 3);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::SingleScreenLower));
+
+        mapper.prg_write(0x8000, 0b1_0101);
+        assert_eq!(mapper.prg_read(0x8000), 20);
+        assert_eq!(mapper.prg_read(0xe000), 23);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::SingleScreenUpper));
+
+        // 2 banks of 32KB, bank 7 wraps to 1
+        let mut mapper = Axrom::new(prg_rom_8k_banks(8));
+        mapper.prg_write(0x8000, 0b0111);
+        assert_eq!(mapper.prg_read(0x8000), 4);
+    }
+
+    #[test]
+    fn test_mmc3_prg_banks() {
+        let mut mapper = Mmc3::new(prg_rom_8k_banks(8), CHR_BANK_SIZE_8K);
+        mapper.prg_write(0x8000, 6);
+        mapper.prg_write(0x8001, 2);
+        mapper.prg_write(0x8000, 7);
+        mapper.prg_write(0x8001, 3);
+
+        assert_eq!(mapper.prg_read(0x8000), 2);
+        assert_eq!(mapper.prg_read(0xa000), 3);
+        assert_eq!(mapper.prg_read(0xc000), 6);
+        assert_eq!(mapper.prg_read(0xe000), 7);
+
+        // PRG mode 1 swaps $8000 and $C000
+        mapper.prg_write(0x8000, 0b0100_0000);
+        assert_eq!(mapper.prg_read(0x8000), 6);
+        assert_eq!(mapper.prg_read(0xc000), 2);
+    }
+
+    #[test]
+    fn test_mmc3_chr_banks() {
+        let mut mapper = Mmc3::new(prg_rom_8k_banks(4), 32 * CHR_BANK_SIZE_1K);
+        for (register, bank) in [(0, 4), (1, 9), (2, 20), (3, 21), (4, 30), (5, 31)] {
+            mapper.prg_write(0x8000, register);
+            mapper.prg_write(0x8001, bank);
+        }
+
+        // (PPU address, 1KB bank) for each slot
+        let mode0 = [
+            (0x0000, 4),
+            (0x0400, 5),
+            (0x0800, 8),
+            (0x0c00, 9),
+            (0x1000, 20),
+            (0x1400, 21),
+            (0x1800, 30),
+            (0x1c00, 31),
+        ];
+        for (addr, bank) in mode0 {
+            assert_eq!(
+                mapper.chr_addr(addr + 0x3ff),
+                bank * CHR_BANK_SIZE_1K + 0x3ff
+            );
+        }
+
+        // CHR mode 1 swaps the pattern table halves
+        mapper.prg_write(0x8000, 0b1000_0000);
+        for (addr, bank) in mode0 {
+            assert_eq!(mapper.chr_addr(addr ^ 0x1000), bank * CHR_BANK_SIZE_1K);
+        }
+    }
+
+    #[test]
+    fn test_mmc3_prg_banks_r6_r7() {
+        let mut mapper = Mmc3::new(prg_rom_8k_banks(8), CHR_BANK_SIZE_8K);
+        for bank in 0..8u8 {
+            mapper.prg_write(0x8000, 6);
+            mapper.prg_write(0x8001, bank);
+            mapper.prg_write(0x8000, 7);
+            mapper.prg_write(0x8001, 7 - bank);
+
+            assert_eq!(mapper.prg_read(0x8000), bank);
+            assert_eq!(mapper.prg_read(0xbfff), 7 - bank);
+            // R0-R5 are CHR only
+            mapper.prg_write(0x8000, 5);
+            mapper.prg_write(0x8001, 0xff);
+            assert_eq!(mapper.prg_read(0x8000), bank);
+        }
+    }
+
+    #[test]
+    fn test_mmc3_mirroring() {
+        let mut mapper = Mmc3::new(prg_rom_8k_banks(8), CHR_BANK_SIZE_8K);
+        assert_eq!(mapper.mirroring(), None);
+
+        mapper.prg_write(0xa000, 1);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+        // only bit 0 counts, and the odd register is PRG RAM protect
+        mapper.prg_write(0xbffe, 0xfe);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Vertical));
+        mapper.prg_write(0xa001, 0x81);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Vertical));
+    }
+
+    #[test]
+    fn test_mmc3_save_state() {
+        let mut mapper = Mmc3::new(prg_rom_8k_banks(8), CHR_BANK_SIZE_8K);
+        mapper.prg_write(0x8000, 6);
+        mapper.prg_write(0x8001, 2);
+        mapper.prg_write(0xc000, 4);
+        mapper.prg_write(0xe001, 0);
+        mapper.prg_write(0xa000, 1);
+        let state = mapper.save_state();
+
+        mapper.prg_write(0x8001, 5);
+        mapper.prg_write(0xc000, 9);
+        mapper.prg_write(0xe000, 0);
+        mapper.prg_write(0xa000, 0);
+        mapper.load_state(&state).unwrap();
+
+        assert_eq!(mapper.prg_read(0x8000), 2);
+        assert_eq!(mapper.irq_latch, 4);
+        assert!(mapper.irq_enabled);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+        assert_eq!(mapper.save_state(), state);
+    }
+
+    #[test]
+    fn test_mmc5_save_state() {
+        let mut mapper = Mmc5::new(prg_rom_8k_banks(16), 4 * CHR_BANK_SIZE_8K);
+        mapper.expansion_write(0x5101, 3);
+        mapper.expansion_write(0x5114, 0x80 | 3);
+        mapper.expansion_write(0x5121, 9);
+        let state = mapper.save_state();
+
+        let mut restored = Mmc5::new(prg_rom_8k_banks(16), 4 * CHR_BANK_SIZE_8K);
+        restored.load_state(&state).unwrap();
+
+        assert_eq!(restored.prg_read(0x8000), 3);
+        assert_eq!(restored.chr_addr(0x0400), 9 * 0x400);
+    }
+
+    #[test]
+    fn test_mmc5_prg_mode_3() {
+        let mut mapper = Mmc5::new(prg_rom_8k_banks(16), CHR_BANK_SIZE_8K);
+        assert_eq!(mapper.prg_read(0xe000), 15);
+
+        mapper.expansion_write(0x5100, 3);
+        mapper.expansion_write(0x5114, 0x80 | 3);
+        mapper.expansion_write(0x5115, 0x80 | 7);
+        mapper.expansion_write(0x5116, 0x80 | 10);
+        mapper.expansion_write(0x5117, 12);
+
+        assert_eq!(mapper.prg_read(0x8000), 3);
+        assert_eq!(mapper.prg_read(0x9fff), 3);
+        assert_eq!(mapper.prg_read(0xa000), 7);
+        assert_eq!(mapper.prg_read(0xc000), 10);
+        assert_eq!(mapper.prg_read(0xe000), 12);
+        assert_eq!(mapper.prg_read(0xffff), 12);
+    }
+
+    #[test]
+    fn test_mmc5_prg_modes_0_to_2() {
+        let mut mapper = Mmc5::new(prg_rom_8k_banks(16), CHR_BANK_SIZE_8K);
+        mapper.expansion_write(0x5115, 0x80 | 5);
+        mapper.expansion_write(0x5116, 0x80 | 9);
+        mapper.expansion_write(0x5117, 6);
+
+        // one 32KB bank selected by $5117
+        mapper.expansion_write(0x5100, 0);
+        assert_eq!(mapper.prg_read(0x8000), 4);
+        assert_eq!(mapper.prg_read(0xe000), 7);
+
+        // two 16KB banks selected by $5115 and $5117
+        mapper.expansion_write(0x5100, 1);
+        assert_eq!(mapper.prg_read(0x8000), 4);
+        assert_eq!(mapper.prg_read(0xa000), 5);
+        assert_eq!(mapper.prg_read(0xc000), 6);
+        assert_eq!(mapper.prg_read(0xe000), 7);
+
+        // 16KB at $8000, 8KB at $C000 and $E000
+        mapper.expansion_write(0x5100, 2);
+        assert_eq!(mapper.prg_read(0x8000), 4);
+        assert_eq!(mapper.prg_read(0xc000), 9);
+        assert_eq!(mapper.prg_read(0xe000), 6);
+    }
+
+    #[test]
+    fn test_mmc5_chr_modes() {
+        let mut mapper = Mmc5::new(prg_rom_8k_banks(4), 32 * CHR_BANK_SIZE_8K);
+        for i in 0..8 {
+            mapper.expansion_write(0x5120 + i, 8 + i as u8);
+        }
+
+        mapper.expansion_write(0x5101, 0);
+        assert_eq!(mapper.chr_addr(0x1400), 15 * 0x2000 + 0x1400);
+
+        mapper.expansion_write(0x5101, 1);
+        assert_eq!(mapper.chr_addr(0x0000), 11 * 0x1000);
+        assert_eq!(mapper.chr_addr(0x1000), 15 * 0x1000);
+
+        mapper.expansion_write(0x5101, 2);
+        assert_eq!(mapper.chr_addr(0x0800), 11 * 0x800);
+
+        mapper.expansion_write(0x5101, 3);
+        assert_eq!(mapper.chr_addr(0x0400), 9 * 0x400);
+        assert_eq!(mapper.chr_addr(0x1c01), 15 * 0x400 + 1);
+    }
+
+    #[test]
+    fn test_mmc3_scanline_irq() {
+        let mut mapper = Mmc3::new(prg_rom_8k_banks(4), CHR_BANK_SIZE_8K);
+        mapper.prg_write(0xc000, 4);
+        mapper.prg_write(0xc001, 0);
+        mapper.prg_write(0xe001, 0);
+
+        // the first clock reloads the counter, then it counts 4 scanlines down to 0
+        for _ in 0..4 {
+            mapper.on_scanline();
+            assert!(!mapper.irq_pending());
+        }
+        mapper.on_scanline();
+        assert!(mapper.irq_pending());
+
+        mapper.prg_write(0xe000, 0);
+        assert!(!mapper.irq_pending());
+    }
+
+    #[test]
+    fn test_mmc3_irq_acknowledge() {
+        let mut mapper = Mmc3::new(prg_rom_8k_banks(4), CHR_BANK_SIZE_8K);
+        mapper.prg_write(0xc000, 0);
+        mapper.prg_write(0xe001, 0);
+        mapper.on_scanline();
+        assert!(mapper.irq_pending());
+
+        mapper.irq_acknowledge();
+        assert!(!mapper.irq_pending());
+        assert!(mapper.irq_enabled);
+    }
+}
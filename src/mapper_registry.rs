@@ -0,0 +1,110 @@
+use crate::mapper::{Axrom, Cnrom, Gxrom, Mapper, Mmc1, Mmc3, Mmc5, Nrom, Uxrom};
+
+// TODO mapper 9 (MMC2): its CHR latches switch on the PPU's pattern fetches,
+// which the frame-at-once renderer doesn't report to the mapper
+const SUPPORTED_MAPPERS: [u8; 8] = [0, 1, 2, 3, 4, 5, 7, 66];
+
+// chr_rom stays with the PPU, mappers only need its size to wrap bank numbers
+pub fn create_mapper(number: u8, prg_rom: Vec<u8>, chr_rom: &[u8]) -> Option<Box<dyn Mapper>> {
+    let mapper: Box<dyn Mapper> = match number {
+        0 => Box::new(Nrom::new(prg_rom)),
+        1 => Box::new(Mmc1::new(prg_rom, chr_rom.len())),
+        2 => Box::new(Uxrom::new(prg_rom)),
+        3 => Box::new(Cnrom::new(prg_rom, chr_rom.len())),
+        4 => Box::new(Mmc3::new(prg_rom, chr_rom.len())),
+        5 => Box::new(Mmc5::new(prg_rom, chr_rom.len())),
+        7 => Box::new(Axrom::new(prg_rom)),
+        66 => Box::new(Gxrom::new(prg_rom, chr_rom.len())),
+        _ => return None,
+    };
+    Some(mapper)
+}
+
+pub fn supported_mappers() -> &'static [u8] {
+    &SUPPORTED_MAPPERS
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // mapper number, the register writes, then (PRG address, 8KB bank there, chr_addr(0))
+    type MapperCase = (u8, Vec<(u16, u8)>, u16, u8, usize);
+
+    // MMC1 takes its registers serially, one bit per write
+    fn mmc1_writes(addr: u16, value: u8) -> Vec<(u16, u8)> {
+        (0..5).map(|bit| (addr, (value >> bit) & 1)).collect()
+    }
+
+    #[test]
+    fn test_create_supported_mappers() {
+        // 16 PRG banks of 8KB and 32KB of CHR, each bank filled with its number
+        let prg_rom: Vec<u8> = (0..16u8).flat_map(|bank| vec![bank; 0x2000]).collect();
+        let chr_rom = [0; 0x8000];
+        let cases: Vec<MapperCase> = vec![
+            (0, vec![], 0xc000, 2, 0),
+            (
+                1,
+                [mmc1_writes(0xe000, 2), mmc1_writes(0xa000, 3)].concat(),
+                0x8000,
+                4,
+                0x2000,
+            ),
+            (2, vec![(0x8000, 3)], 0xc000, 14, 0),
+            (3, vec![(0x8000, 2)], 0xc000, 2, 0x4000),
+            (
+                4,
+                vec![(0x8000, 6), (0x8001, 5), (0x8000, 0), (0x8001, 4)],
+                0x8000,
+                5,
+                0x1000,
+            ),
+            (5, vec![(0x5117, 9), (0x5127, 3)], 0xe000, 9, 0x6000),
+            (7, vec![(0x8000, 1)], 0xe000, 7, 0),
+            (66, vec![(0x8000, 0x11)], 0x8000, 4, 0x2000),
+        ];
+        for (number, writes, addr, bank, chr_addr) in &cases {
+            let mut mapper = create_mapper(*number, prg_rom.clone(), &chr_rom).unwrap();
+            for &(write_addr, data) in writes {
+                if write_addr < 0x8000 {
+                    mapper.expansion_write(write_addr, data);
+                } else {
+                    mapper.prg_write(write_addr, data);
+                }
+            }
+            assert_eq!(mapper.prg_read(*addr), *bank, "mapper {}", number);
+            assert_eq!(mapper.chr_addr(0), *chr_addr, "mapper {}", number);
+        }
+        let numbers: Vec<u8> = cases.iter().map(|case| case.0).collect();
+        assert_eq!(supported_mappers(), numbers.as_slice());
+    }
+
+    #[test]
+    fn test_create_mapper_types() {
+        let cases = [
+            (0, std::any::type_name::<Nrom>()),
+            (1, std::any::type_name::<Mmc1>()),
+            (2, std::any::type_name::<Uxrom>()),
+            (3, std::any::type_name::<Cnrom>()),
+            (4, std::any::type_name::<Mmc3>()),
+            (5, std::any::type_name::<Mmc5>()),
+            (7, std::any::type_name::<Axrom>()),
+            (66, std::any::type_name::<Gxrom>()),
+        ];
+        for (number, type_name) in cases {
+            let mapper = create_mapper(number, vec![0; 0x8000], &[0; 0x2000]).unwrap();
+            assert_eq!(mapper.name(), type_name, "mapper {}", number);
+        }
+        assert_eq!(supported_mappers().len(), cases.len());
+    }
+
+    #[test]
+    fn test_create_unsupported_mapper() {
+        assert!(create_mapper(9, vec![0; 0x8000], &[0; 0x2000]).is_none());
+    }
+
+    #[test]
+    fn test_supported_mappers_sorted() {
+        assert!(supported_mappers().windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
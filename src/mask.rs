@@ -1,10 +1,12 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 bitflags! {
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct MaskRegister:u8 {
         const GREYSCALE = 0b0000_0001;
         const LEFTMOST_8PXL_BACKGROUND = 0b0000_0010;
-        const LEFTMOST_8PXL_STRITE = 0b0000_0100;
+        const LEFTMOST_8PXL_SPRITE = 0b0000_0100;
         const SHOW_BACKGROUND = 0b0000_1000;
         const SHOW_SPRITES = 0b0001_0000;
         const EMPHASIZE_RED = 0b0010_0000;
@@ -13,6 +15,12 @@ bitflags! {
     }
 }
 
+impl Default for MaskRegister {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MaskRegister {
     pub fn new() -> Self {
         MaskRegister::from_bits_truncate(0)
@@ -21,4 +29,70 @@ impl MaskRegister {
     pub fn update(&mut self, data: u8) {
         *self = MaskRegister::from_bits_truncate(data);
     }
+
+    pub fn show_background(&self) -> bool {
+        self.contains(MaskRegister::SHOW_BACKGROUND)
+    }
+
+    pub fn show_sprites(&self) -> bool {
+        self.contains(MaskRegister::SHOW_SPRITES)
+    }
+
+    pub fn show_left_background(&self) -> bool {
+        self.contains(MaskRegister::LEFTMOST_8PXL_BACKGROUND)
+    }
+
+    pub fn show_left_sprites(&self) -> bool {
+        self.contains(MaskRegister::LEFTMOST_8PXL_SPRITE)
+    }
+
+    pub fn is_grayscale(&self) -> bool {
+        self.contains(MaskRegister::GREYSCALE)
+    }
+
+    pub fn is_rendering_enabled(&self) -> bool {
+        self.show_background() || self.show_sprites()
+    }
+
+    // red, green and blue emphasis in bits 0-2
+    pub fn color_emphasis(&self) -> u8 {
+        self.bits() >> 5
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_accessors() {
+        let mut mask = MaskRegister::new();
+        assert!(!mask.show_background());
+        assert!(!mask.show_sprites());
+        assert!(!mask.show_left_background());
+        assert!(!mask.show_left_sprites());
+        assert!(!mask.is_grayscale());
+
+        mask.update(0b0001_1111);
+        assert!(mask.show_background());
+        assert!(mask.show_sprites());
+        assert!(mask.show_left_background());
+        assert!(mask.show_left_sprites());
+        assert!(mask.is_grayscale());
+
+        mask.update(0b0000_0100);
+        assert!(mask.show_left_sprites());
+        assert!(!mask.show_left_background());
+    }
+
+    #[test]
+    fn test_update_stores_every_value() {
+        let mut mask = MaskRegister::new();
+        for data in 0..=255u8 {
+            mask.update(data);
+            assert_eq!(mask.bits(), data);
+            assert_eq!(mask.color_emphasis(), data >> 5);
+            assert_eq!(mask.is_rendering_enabled(), data & 0b0001_1000 != 0);
+        }
+    }
 }
@@ -0,0 +1,80 @@
+use crate::frame::Frame;
+
+// Counts the frames from a button press until the screen first changes.
+#[derive(Default)]
+pub struct InputLatencyTracker {
+    frame_count: u64,
+    pressed_at: Option<u64>,
+    last_frame: Option<Frame>,
+}
+
+impl InputLatencyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // number of frames passed to on_frame_rendered so far, i.e. the number of the next frame
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn on_button_press(&mut self, frame: u64) {
+        self.pressed_at = Some(frame);
+    }
+
+    pub fn on_frame_rendered(&mut self, frame: &Frame) -> Option<u64> {
+        let frame_number = self.frame_count;
+        self.frame_count += 1;
+
+        let changed = self
+            .last_frame
+            .as_ref()
+            .is_some_and(|last| frame.diff(last) > 0);
+        self.last_frame = Some(frame.clone());
+
+        match self.pressed_at {
+            Some(pressed_at) if changed && frame_number >= pressed_at => {
+                self.pressed_at = None;
+                Some(frame_number - pressed_at)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_latency_in_frames() {
+        let still = Frame::new();
+        let mut changed = Frame::new();
+        changed.set_pixcel(0, 0, (0xff, 0xff, 0xff));
+
+        let mut tracker = InputLatencyTracker::new();
+        tracker.on_button_press(0);
+        assert_eq!(tracker.on_frame_rendered(&still), None);
+        assert_eq!(tracker.on_frame_rendered(&still), None);
+        assert_eq!(tracker.on_frame_rendered(&still), None);
+        assert_eq!(tracker.on_frame_rendered(&changed), Some(3));
+
+        // reported once per press
+        assert_eq!(tracker.on_frame_rendered(&still), None);
+    }
+
+    #[test]
+    fn test_changes_before_press_are_ignored() {
+        let still = Frame::new();
+        let mut changed = Frame::new();
+        changed.set_pixcel(0, 0, (0xff, 0xff, 0xff));
+
+        let mut tracker = InputLatencyTracker::new();
+        assert_eq!(tracker.on_frame_rendered(&still), None);
+        assert_eq!(tracker.on_frame_rendered(&changed), None);
+
+        tracker.on_button_press(tracker.frame_count());
+        assert_eq!(tracker.on_frame_rendered(&changed), None);
+        assert_eq!(tracker.on_frame_rendered(&still), Some(1));
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+
+use crate::cpu::{CpuBus, Memory, CPU};
+
+// Flat 64KB memory without ROM, PPU or APU, for testing CPU instructions alone.
+pub struct MockBus {
+    pub ram: [u8; 65536],
+    pub tick_count: usize,
+}
+
+impl MockBus {
+    pub fn new(ram: [u8; 65536]) -> Self {
+        MockBus { ram, tick_count: 0 }
+    }
+}
+
+impl fmt::Debug for MockBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockBus")
+            .field("ram", &format_args!("[{} bytes]", self.ram.len()))
+            .field("tick_count", &self.tick_count)
+            .finish()
+    }
+}
+
+impl Memory for MockBus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.ram[addr as usize] = data;
+    }
+
+    fn mem_peek(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+}
+
+impl CpuBus for MockBus {
+    fn tick(&mut self, cycles: usize) {
+        self.tick_count += cycles;
+    }
+
+    fn take_nmi(&mut self) -> bool {
+        false
+    }
+
+    fn irq_status(&self) -> bool {
+        false
+    }
+
+    fn mapper_irq_acknowledge(&mut self) {}
+
+    fn ppu_position(&self) -> (u16, usize) {
+        (0, 0)
+    }
+
+    fn take_dma_stall(&mut self) -> usize {
+        0
+    }
+}
+
+impl CPU<MockBus> {
+    pub fn new_with_mock(ram: [u8; 65536]) -> CPU<MockBus> {
+        CPU::new(MockBus::new(ram))
+    }
+}
+
+// Runs program loaded at $0000 until the next instruction is BRK and returns the final CPU state.
+pub fn run_program(program: &[u8]) -> CPU<MockBus> {
+    run_program_with_memory(program, &[])
+}
+
+// Same as run_program, with memory_patches written before the CPU starts.
+pub fn run_program_with_memory(program: &[u8], memory_patches: &[(u16, u8)]) -> CPU<MockBus> {
+    let mut ram = [0; 65536];
+    ram[..program.len()].copy_from_slice(program);
+    for &(addr, data) in memory_patches {
+        ram[addr as usize] = data;
+    }
+    // reset vector -> $0000
+    ram[0xfffc] = 0x00;
+    ram[0xfffd] = 0x00;
+
+    let mut cpu = CPU::new_with_mock(ram);
+    cpu.reset();
+    while cpu.mem_peek(cpu.program_counter) != 0x00 {
+        cpu.step();
+    }
+    cpu
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::CpuFlags;
+
+    #[test]
+    fn test_mock_bus_read_write() {
+        let mut bus = MockBus::new([0; 65536]);
+        bus.mem_write(0xfffc, 0x34);
+        bus.mem_write(0xfffd, 0x12);
+
+        assert_eq!(bus.mem_read_u16(0xfffc), 0x1234);
+    }
+
+    #[test]
+    fn test_cpu_with_mock_bus_reset() {
+        let mut ram = [0; 65536];
+        ram[0xfffc] = 0x00;
+        ram[0xfffd] = 0x86;
+        let mut cpu = CPU::new_with_mock(ram);
+        cpu.reset();
+
+        assert_eq!(cpu.program_counter, 0x8600);
+        assert_eq!(cpu.bus.tick_count, 0);
+    }
+
+    #[test]
+    fn test_run_with_trace() {
+        let mut ram = [0; 65536];
+        // LDA #$01, TAX, INX, then a breakpoint pauses the run
+        ram[0x8000..0x8004].copy_from_slice(&[0xa9, 0x01, 0xaa, 0xe8]);
+        let mut cpu = CPU::new_with_mock(ram);
+        cpu.add_breakpoint(0x8004);
+
+        let mut output = Vec::new();
+        cpu.run_with_trace(&mut output);
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("8000  A9 01     LDA #$01"));
+        assert!(lines[1].starts_with("8002  AA        TAX"));
+        assert!(lines[2].starts_with("8003  E8        INX"));
+    }
+
+    #[test]
+    fn test_run_program_lda_immediate() {
+        let cpu = run_program(&[0xa9, 0x42, 0x00]);
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.program_counter, 0x0002);
+    }
+
+    #[test]
+    fn test_run_program_lda_zero_flag() {
+        let cpu = run_program(&[0xa9, 0x00, 0x00]);
+        assert!(cpu.status.contains(CpuFlags::ZERO));
+        assert!(!cpu.status.contains(CpuFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_run_program_tax_inx() {
+        let cpu = run_program(&[0xa9, 0xc0, 0xaa, 0xe8, 0x00]);
+        assert_eq!(cpu.register_x, 0xc1);
+        assert!(cpu.status.contains(CpuFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_run_program_inx_overflow() {
+        let cpu = run_program(&[0xa2, 0xff, 0xe8, 0xe8, 0x00]);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_run_program_lda_from_memory() {
+        let cpu = run_program_with_memory(&[0xa5, 0x10, 0x00], &[(0x10, 0x55)]);
+        assert_eq!(cpu.register_a, 0x55);
+    }
+
+    #[test]
+    fn test_run_program_sta_absolute() {
+        let cpu = run_program(&[0xa9, 0x37, 0x8d, 0x00, 0x02, 0x00]);
+        assert_eq!(cpu.bus.ram[0x0200], 0x37);
+    }
+
+    #[test]
+    fn test_run_program_adc_carry() {
+        // CLC, LDA #$f0, ADC #$20
+        let cpu = run_program(&[0x18, 0xa9, 0xf0, 0x69, 0x20, 0x00]);
+        assert_eq!(cpu.register_a, 0x10);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_run_program_sbc() {
+        // SEC, LDA #$10, SBC #$01
+        let cpu = run_program(&[0x38, 0xa9, 0x10, 0xe9, 0x01, 0x00]);
+        assert_eq!(cpu.register_a, 0x0f);
+        assert!(cpu.status.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_run_program_loop() {
+        // LDX #$05, loop: INY, DEX, BNE loop
+        let cpu = run_program(&[0xa2, 0x05, 0xc8, 0xca, 0xd0, 0xfc, 0x00]);
+        assert_eq!(cpu.register_x, 0);
+        assert_eq!(cpu.register_y, 5);
+    }
+
+    #[test]
+    fn test_run_program_jsr_rts() {
+        // JSR $0006, LDX #$01, BRK, sub: LDA #$02, RTS
+        let cpu = run_program(&[0x20, 0x06, 0x00, 0xa2, 0x01, 0x00, 0xa9, 0x02, 0x60]);
+        assert_eq!(cpu.register_a, 0x02);
+        assert_eq!(cpu.register_x, 0x01);
+        assert_eq!(cpu.stack_pointer, 0xfd);
+    }
+
+    #[test]
+    fn test_run_program_stack() {
+        // LDA #$11, PHA, LDA #$22, PLA
+        let cpu = run_program(&[0xa9, 0x11, 0x48, 0xa9, 0x22, 0x68, 0x00]);
+        assert_eq!(cpu.register_a, 0x11);
+        assert_eq!(cpu.bus.ram[0x01fd], 0x11);
+    }
+}
@@ -10,6 +10,7 @@ pub struct OpCode {
     pub len: u8,
     pub cycle: u8,
     pub mode: AddressingMode,
+    page_cross_penalty: bool,
 }
 
 impl OpCode {
@@ -20,8 +21,89 @@ impl OpCode {
             len,
             cycle,
             mode,
+            page_cross_penalty: false,
         }
     }
+
+    // for the indexed reads that take 1 more cycle when the address is on another page
+    pub fn new_page_crossing(
+        code: u8,
+        mnemonic: &'static str,
+        len: u8,
+        cycle: u8,
+        mode: AddressingMode,
+    ) -> Self {
+        OpCode::builder()
+            .code(code)
+            .mnemonic(mnemonic)
+            .len(len)
+            .cycles(cycle)
+            .mode(mode)
+            .page_cross_penalty()
+            .build()
+    }
+
+    pub fn builder() -> OpCodeBuilder {
+        OpCodeBuilder {
+            opcode: OpCode {
+                code: 0,
+                mnemonic: "",
+                len: 1,
+                cycle: 2,
+                mode: AddressingMode::NoneAddressing,
+                page_cross_penalty: false,
+            },
+        }
+    }
+
+    // BPL, BMI, BVC, BVS, BCC, BCS, BNE and BEQ are all xxx10000
+    pub fn is_branch(&self) -> bool {
+        self.code & 0x1f == 0x10
+    }
+
+    pub fn has_page_cross_penalty(&self) -> bool {
+        self.page_cross_penalty
+    }
+}
+
+pub struct OpCodeBuilder {
+    opcode: OpCode,
+}
+
+impl OpCodeBuilder {
+    pub fn code(mut self, code: u8) -> Self {
+        self.opcode.code = code;
+        self
+    }
+
+    pub fn mnemonic(mut self, mnemonic: &'static str) -> Self {
+        self.opcode.mnemonic = mnemonic;
+        self
+    }
+
+    pub fn len(mut self, len: u8) -> Self {
+        self.opcode.len = len;
+        self
+    }
+
+    pub fn cycles(mut self, cycles: u8) -> Self {
+        self.opcode.cycle = cycles;
+        self
+    }
+
+    pub fn mode(mut self, mode: AddressingMode) -> Self {
+        self.opcode.mode = mode;
+        self
+    }
+
+    pub fn page_cross_penalty(mut self) -> Self {
+        self.opcode.page_cross_penalty = true;
+        self
+    }
+
+    pub fn build(self) -> OpCode {
+        self.opcode
+    }
 }
 
 lazy_static! {
@@ -31,21 +113,21 @@ lazy_static! {
         OpCode::new(0x65, "ADC", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(0x75, "ADC", 2, 4, AddressingMode::ZeroPage_X),
         OpCode::new(0x6d, "ADC", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x7d, "ADC", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
-        OpCode::new(0x79, "ADC", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
+        OpCode::new_page_crossing(0x7d, "ADC", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
+        OpCode::new_page_crossing(0x79, "ADC", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
         OpCode::new(0x61, "ADC", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x71, "ADC", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
+        OpCode::new_page_crossing(0x71, "ADC", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
         // AND
         OpCode::new(0x29, "AND", 2, 2, AddressingMode::Immediate),
         OpCode::new(0x25, "AND", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(0x35, "AND", 2, 4, AddressingMode::ZeroPage_X),
         OpCode::new(0x2d, "AND", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x3d, "AND", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
-        OpCode::new(0x39, "AND", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
+        OpCode::new_page_crossing(0x3d, "AND", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
+        OpCode::new_page_crossing(0x39, "AND", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
         OpCode::new(0x21, "AND", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x31, "AND", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
+        OpCode::new_page_crossing(0x31, "AND", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
         // ASL
-        OpCode::new(0x0a, "ASL", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x0a, "ASL", 1, 2, AddressingMode::Accumulator),
         OpCode::new(0x06, "ASL", 2, 5, AddressingMode::ZeroPage),
         OpCode::new(0x16, "ASL", 2, 6, AddressingMode::ZeroPage_X),
         OpCode::new(0x0e, "ASL", 3, 6, AddressingMode::Absolute),
@@ -84,10 +166,10 @@ lazy_static! {
         OpCode::new(0xc5, "CMP", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(0xd5, "CMP", 2, 4, AddressingMode::ZeroPage_X),
         OpCode::new(0xcd, "CMP", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xdd, "CMP", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
-        OpCode::new(0xd9, "CMP", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
+        OpCode::new_page_crossing(0xdd, "CMP", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
+        OpCode::new_page_crossing(0xd9, "CMP", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
         OpCode::new(0xc1, "CMP", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0xd1, "CMP", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
+        OpCode::new_page_crossing(0xd1, "CMP", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
         // CPX
         OpCode::new(0xe0, "CPX", 2, 2, AddressingMode::Immediate),
         OpCode::new(0xe4, "CPX", 2, 3, AddressingMode::ZeroPage),
@@ -110,10 +192,10 @@ lazy_static! {
         OpCode::new(0x45, "EOR", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(0x55, "EOR", 2, 4, AddressingMode::ZeroPage_X),
         OpCode::new(0x4d, "EOR", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x5d, "EOR", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
-        OpCode::new(0x59, "EOR", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
+        OpCode::new_page_crossing(0x5d, "EOR", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
+        OpCode::new_page_crossing(0x59, "EOR", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
         OpCode::new(0x41, "EOR", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x51, "EOR", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
+        OpCode::new_page_crossing(0x51, "EOR", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
         // INC
         OpCode::new(0xe6, "INC", 2, 5, AddressingMode::ZeroPage),
         OpCode::new(0xf6, "INC", 2, 6, AddressingMode::ZeroPage_X),
@@ -133,24 +215,24 @@ lazy_static! {
         OpCode::new(0xa5, "LDA", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(0xb5, "LDA", 2, 4, AddressingMode::ZeroPage_X),
         OpCode::new(0xad, "LDA", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xbd, "LDA", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
-        OpCode::new(0xb9, "LDA", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
+        OpCode::new_page_crossing(0xbd, "LDA", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
+        OpCode::new_page_crossing(0xb9, "LDA", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
         OpCode::new(0xa1, "LDA", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0xb1, "LDA", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
+        OpCode::new_page_crossing(0xb1, "LDA", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
         // LDX
         OpCode::new(0xa2, "LDX", 2, 2, AddressingMode::Immediate),
         OpCode::new(0xa6, "LDX", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(0xb6, "LDX", 2, 4, AddressingMode::ZeroPage_Y),
         OpCode::new(0xae, "LDX", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xbe, "LDX", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
+        OpCode::new_page_crossing(0xbe, "LDX", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
         // LDY
         OpCode::new(0xa0, "LDY", 2, 2, AddressingMode::Immediate),
         OpCode::new(0xa4, "LDY", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(0xb4, "LDY", 2, 4, AddressingMode::ZeroPage_X),
         OpCode::new(0xac, "LDY", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xbc, "LDY", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
+        OpCode::new_page_crossing(0xbc, "LDY", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
         // LSR
-        OpCode::new(0x4a, "LSR", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x4a, "LSR", 1, 2, AddressingMode::Accumulator),
         OpCode::new(0x46, "LSR", 2, 5, AddressingMode::ZeroPage),
         OpCode::new(0x56, "LSR", 2, 6, AddressingMode::ZeroPage_X),
         OpCode::new(0x4e, "LSR", 3, 6, AddressingMode::Absolute),
@@ -162,10 +244,10 @@ lazy_static! {
         OpCode::new(0x05, "ORA", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(0x15, "ORA", 2, 4, AddressingMode::ZeroPage_X),
         OpCode::new(0x0d, "ORA", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0x1d, "ORA", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
-        OpCode::new(0x19, "ORA", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
+        OpCode::new_page_crossing(0x1d, "ORA", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
+        OpCode::new_page_crossing(0x19, "ORA", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
         OpCode::new(0x01, "ORA", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0x11, "ORA", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
+        OpCode::new_page_crossing(0x11, "ORA", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
         // PHA
         OpCode::new(0x48, "PHA", 1, 3, AddressingMode::NoneAddressing),
         // PHP
@@ -175,13 +257,13 @@ lazy_static! {
         // PLP
         OpCode::new(0x28, "PLP", 1, 4, AddressingMode::NoneAddressing),
         // ROL
-        OpCode::new(0x2a, "ROL", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x2a, "ROL", 1, 2, AddressingMode::Accumulator),
         OpCode::new(0x26, "ROL", 2, 5, AddressingMode::ZeroPage),
         OpCode::new(0x36, "ROL", 2, 6, AddressingMode::ZeroPage_X),
         OpCode::new(0x2e, "ROL", 3, 6, AddressingMode::Absolute),
         OpCode::new(0x3e, "ROL", 3, 7, AddressingMode::Absolute_X),
         // ROR
-        OpCode::new(0x6a, "ROR", 1, 2, AddressingMode::NoneAddressing),
+        OpCode::new(0x6a, "ROR", 1, 2, AddressingMode::Accumulator),
         OpCode::new(0x66, "ROR", 2, 5, AddressingMode::ZeroPage),
         OpCode::new(0x76, "ROR", 2, 6, AddressingMode::ZeroPage_X),
         OpCode::new(0x6e, "ROR", 3, 6, AddressingMode::Absolute),
@@ -195,10 +277,10 @@ lazy_static! {
         OpCode::new(0xe5, "SBC", 2, 3, AddressingMode::ZeroPage),
         OpCode::new(0xf5, "SBC", 2, 4, AddressingMode::ZeroPage_X),
         OpCode::new(0xed, "SBC", 3, 4, AddressingMode::Absolute),
-        OpCode::new(0xfd, "SBC", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
-        OpCode::new(0xf9, "SBC", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
+        OpCode::new_page_crossing(0xfd, "SBC", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_X),
+        OpCode::new_page_crossing(0xf9, "SBC", 3, 4 /*(+1 if page crossed)*/, AddressingMode::Absolute_Y),
         OpCode::new(0xe1, "SBC", 2, 6, AddressingMode::Indirect_X),
-        OpCode::new(0xf1, "SBC", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
+        OpCode::new_page_crossing(0xf1, "SBC", 2, 5 /*(+1 if page crossed)*/, AddressingMode::Indirect_Y),
         // SEC
         OpCode::new(0x38, "SEC", 1, 2, AddressingMode::NoneAddressing),
         // SED
@@ -334,11 +416,23 @@ lazy_static! {
         OpCode::new(0x73, "*RRA", 2, 8, AddressingMode::Indirect_Y),
     ];
 
-    pub static ref OPECODE_MAP: HashMap<u8, &'static OpCode>={
+    pub static ref OPCODE_MAP: HashMap<u8, &'static OpCode>={
         let mut map = HashMap::new();
         for cpuop in &*CPU_OPS_CODES{
             map.insert(cpuop.code, cpuop);
         }
         map
     };
+
+    // indexed by opcode, for the lookup on every instruction without hashing
+    pub static ref OPCODE_TABLE: [Option<&'static OpCode>; 256] = {
+        let mut table = [None; 256];
+        for cpuop in &*CPU_OPS_CODES {
+            table[cpuop.code as usize] = Some(cpuop);
+        }
+        table
+    };
 }
+
+#[deprecated(note = "use OPCODE_MAP")]
+pub static OPECODE_MAP: &OPCODE_MAP = &OPCODE_MAP;
@@ -0,0 +1,90 @@
+use crate::ppu::NesPPU;
+
+// Cycles palette_table entries through lists of system colors, one step per frame,
+// the way games animate water or lava without touching the nametables.
+pub struct PaletteAnimator<'a> {
+    ppu: &'a mut NesPPU,
+    // (palette_table index, colors it cycles through)
+    entries: Vec<(usize, Vec<u8>)>,
+    frame: u64,
+}
+
+impl<'a> PaletteAnimator<'a> {
+    pub fn new(ppu: &'a mut NesPPU, entries: Vec<(usize, Vec<u8>)>) -> Self {
+        let mut animator = PaletteAnimator {
+            ppu,
+            entries,
+            frame: 0,
+        };
+        animator.apply();
+        animator
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn ppu(&self) -> &NesPPU {
+        self.ppu
+    }
+
+    pub fn advance_frame(&mut self) {
+        self.frame += 1;
+        self.apply();
+    }
+
+    fn apply(&mut self) {
+        for (index, colors) in &self.entries {
+            if !colors.is_empty() {
+                self.ppu.palette_table[*index] =
+                    colors[(self.frame % colors.len() as u64) as usize];
+            }
+        }
+    }
+}
+
+// The four background palette 0 entries, each cycling through all 64 system colors
+// a quarter of the way apart.
+pub fn all_colors_demo() -> Vec<(usize, Vec<u8>)> {
+    (0..4)
+        .map(|index| {
+            let colors = (0..64)
+                .map(|color| ((color + index * 16) % 64) as u8)
+                .collect();
+            (index, colors)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartoridge::Mirroring;
+
+    #[test]
+    fn test_cycle_returns_to_initial_state() {
+        let mut ppu = NesPPU::new(vec![0; 8 * 1024], Mirroring::Horizontal);
+        let mut animator = PaletteAnimator::new(&mut ppu, vec![(1, vec![0x0f, 0x16, 0x27, 0x30])]);
+        let initial = animator.ppu().palette_table;
+        assert_eq!(initial[1], 0x0f);
+
+        animator.advance_frame();
+        assert_eq!(animator.ppu().palette_table[1], 0x16);
+        for _ in 1..4 {
+            animator.advance_frame();
+        }
+
+        assert_eq!(animator.frame(), 4);
+        assert_eq!(animator.ppu().palette_table, initial);
+    }
+
+    #[test]
+    fn test_all_colors_demo() {
+        let entries = all_colors_demo();
+        assert_eq!(entries.len(), 4);
+        for (index, colors) in &entries {
+            assert_eq!(colors.len(), 64);
+            assert_eq!(colors[0], (*index * 16) as u8);
+        }
+    }
+}
@@ -1,11 +1,53 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
 use crate::cartoridge::Mirroring;
 use crate::control::ControlRegister;
+use crate::frame::Frame;
 use crate::mask::MaskRegister;
+use crate::render;
 use crate::scroll::ScrollRegister;
 use crate::status::StatusRegister;
 
+const CHR_RAM_SIZE: usize = 8 * 1024;
+const CHR_PAGE_SIZE: usize = 4 * 1024;
+const CHR_BANK_SIZE: usize = 1024;
+
+// $3F20-$3FFF mirrors $3F00-$3F1F, and $3F10/$3F14/$3F18/$3F1C mirror $3F00/$3F04/$3F08/$3F0C
+// https://www.nesdev.org/wiki/PPU_palettes#Memory_Map
+fn palette_addr_to_index(addr: u16) -> usize {
+    let index = (addr & 0x1f) as usize;
+    match index {
+        0x10 | 0x14 | 0x18 | 0x1c => index - 0x10,
+        _ => index,
+    }
+}
+
+// Names of the regions in the 16KB PPU address space, for debuggers
+// https://www.nesdev.org/wiki/PPU_memory_map
+pub struct PpuMemoryMap;
+
+impl PpuMemoryMap {
+    pub fn region_name(addr: u16) -> &'static str {
+        match addr & 0x3fff {
+            0x0000..=0x0fff => "Pattern Table 0",
+            0x1000..=0x1fff => "Pattern Table 1",
+            // $3000-$3EFF mirrors $2000-$2EFF
+            0x2000..=0x3eff => match (addr & 0x0fff) / 0x400 {
+                0 => "Nametable 0",
+                1 => "Nametable 1",
+                2 => "Nametable 2",
+                _ => "Nametable 3",
+            },
+            _ => "Palette RAM",
+        }
+    }
+}
+
 pub trait PPU {
-    fn write_to_ctrl(&mut self, value: u8);
+    // true when the write enables NMI during vblank, which raises it right away
+    fn write_to_ctrl(&mut self, value: u8) -> bool;
     fn write_to_mask(&mut self, value: u8);
     fn read_status(&mut self) -> u8;
     fn write_to_oam_addr(&mut self, value: u8);
@@ -17,35 +59,69 @@ pub trait PPU {
     fn read_data(&mut self) -> u8;
     fn write_oam_dma(&mut self, value: &[u8; 256]);
 }
+// what happened during one NesPPU::tick call
+#[derive(Debug, Default, PartialEq)]
+pub struct PpuTickResult {
+    pub new_frame: bool,
+    pub nmi_triggered: bool,
+    // the scanline that just ended, if the tick crossed a scanline boundary
+    pub scanline_completed: Option<u16>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct NesPPU {
     pub chr_rom: Vec<u8>,
+    pub is_chr_ram: bool,
+    // chr_rom offsets of the eight 1KB banks at $0000-$1FFF, chosen by the mapper
+    chr_banks: [usize; 8],
     pub palette_table: [u8; 32],
+    #[serde(with = "crate::save_state::byte_array")]
     pub vram: [u8; 2048],
     pub oam_addr: u8,
+    #[serde(with = "crate::save_state::byte_array")]
     pub oam_data: [u8; 256],
+    pub secondary_oam: [u8; 32],
     pub mask: MaskRegister,
     pub scroll: ScrollRegister,
     pub status: StatusRegister,
 
     pub mirroring: Mirroring,
+    // v, the current VRAM address
     addr: AddrRegister,
+    // t, the scroll position written through $2000/$2005/$2006, copied to v while rendering
+    temp_addr: u16,
+    // w, the write toggle $2005 and $2006 share, true after the first write of a pair
+    w: bool,
     pub ctrl: ControlRegister,
     internal_data_buf: u8,
     scanline: u16,
     cycle: usize,
-    pub nmi_interrupt: Option<u8>,
+    // dots since power on, unlike cycle it is not reset each scanline
+    pub absolute_cycle_count: u64,
 }
 
 impl NesPPU {
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        // cartridges without CHR ROM carry 8KB of writable CHR RAM instead
+        let is_chr_ram = chr_rom.is_empty();
+        let chr_rom = if is_chr_ram {
+            vec![0; CHR_RAM_SIZE]
+        } else {
+            chr_rom
+        };
         NesPPU {
-            chr_rom: chr_rom,
-            mirroring: mirroring,
+            chr_rom,
+            is_chr_ram,
+            chr_banks: [0, 1, 2, 3, 4, 5, 6, 7].map(|bank| bank * CHR_BANK_SIZE),
+            mirroring,
             vram: [0; 2048],
             oam_addr: 0,
             oam_data: [0; 64 * 4],
+            secondary_oam: [0xff; 8 * 4],
             palette_table: [0; 32],
             addr: AddrRegister::new(),
+            temp_addr: 0,
+            w: false,
             ctrl: ControlRegister::new(),
             mask: MaskRegister::new(),
             scroll: ScrollRegister::new(),
@@ -53,47 +129,315 @@ impl NesPPU {
             internal_data_buf: 0,
             scanline: 0,
             cycle: 0,
-            nmi_interrupt: None,
+            absolute_cycle_count: 0,
         }
     }
 
     pub fn show_ppu(&self) {
         println!(
-            "ppu cycle:{}, scanline:{}, ctrl:{:08b}, nmi:{:?}",
-            self.cycle, self.scanline, self.ctrl, self.nmi_interrupt
+            "ppu cycle:{}, scanline:{}, ctrl:{:08b}",
+            self.cycle, self.scanline, self.ctrl
         ); // TODO
         if self.cycle == 340 && self.scanline == 240 {
             println!("here");
         }
     }
 
-    pub fn tick(&mut self, cycle: usize) -> bool {
+    pub fn tick(&mut self, cycle: usize) -> PpuTickResult {
+        let mut result = PpuTickResult::default();
+        let start = self.cycle;
         self.cycle += cycle;
+        self.absolute_cycle_count += cycle as u64;
         // println!("ppu.tick cycle:{}", self.cycle);
+        for dot in start + 1..=self.cycle.min(340) {
+            self.run_dot(dot);
+        }
         if self.cycle >= 341 {
-            self.cycle = self.cycle - 341;
+            self.cycle -= 341;
+            // dot 0 is idle, so the new scanline starts at dot 1
+            let next_scanline_dots = 1..=self.cycle;
+            result.scanline_completed = Some(self.scanline);
             self.scanline += 1;
             if self.scanline == 241 {
+                // the flag is set even with NMI disabled, for games polling PPUSTATUS
+                self.status.set_start_vblank();
                 if self.ctrl.generate_vblank_status() {
-                    self.status.set_start_vblank();
-                    // todo!("Should trigger NMI interrupt")
                     // println!("nmi interrupt!!!");
-                    self.nmi_interrupt = Some(1);
+                    result.nmi_triggered = true;
                 }
             }
 
+            if self.scanline == 261 {
+                self.status.set_sprite_zero_hit(false);
+            }
+
             if self.scanline >= 262 {
                 self.scanline = 0;
                 self.status.set_end_vblank();
-                self.nmi_interrupt = None;
+                self.status.set_sprite_overflow(false);
                 // println!("reset vbrank");
-                return true;
+                result.new_frame = true;
+            }
+
+            // sprite evaluation is part of rendering and stops with it
+            if self.scanline < 240 && self.is_rendering_enabled() {
+                self.clear_secondary_oam();
+                self.evaluate_sprites();
+            }
+            for dot in next_scanline_dots {
+                self.run_dot(dot);
             }
         }
-        return false;
+        result
     }
 
-    fn mirror_vram_addr(&self, addr: u16) -> u16 {
+    fn run_dot(&mut self, dot: usize) {
+        self.check_sprite_zero_hit(dot);
+        self.advance_vram_addr(dot);
+    }
+
+    // Dots 1-256 output the pixels 0-255 of the scanline. The hit is set on the first pixel
+    // where sprite 0 and the background are both opaque, never at x=255 or in a clipped
+    // leftmost column.
+    // https://www.nesdev.org/wiki/PPU_OAM#Sprite_zero_hits
+    fn check_sprite_zero_hit(&mut self, dot: usize) {
+        if self.status.is_sprite_zero_hit()
+            || !(self.mask.show_background() && self.mask.show_sprites())
+            || self.scanline >= 240
+            || !(1..=255).contains(&dot)
+        {
+            return;
+        }
+        let x = dot - 1;
+        let y = self.scanline as usize;
+        if x < 8 && !(self.mask.show_left_background() && self.mask.show_left_sprites()) {
+            return;
+        }
+        if self.sprite_zero_opaque(x, y) && self.background_opaque(x, y) {
+            self.status.set_sprite_zero_hit(true);
+        }
+    }
+
+    // same placement as render::render_sprites
+    fn sprite_zero_opaque(&self, x: usize, y: usize) -> bool {
+        let (sprite_y, tile, attributes, sprite_x) = (
+            self.oam_data[0] as usize,
+            self.oam_data[1],
+            self.oam_data[2],
+            self.oam_data[3] as usize,
+        );
+        let height = self.ctrl.sprite_height() as usize;
+        if !(sprite_y..sprite_y + height).contains(&y) || !(sprite_x..sprite_x + 8).contains(&x) {
+            return false;
+        }
+        let mut column = x - sprite_x;
+        if attributes & 0b0100_0000 != 0 {
+            column = 7 - column;
+        }
+        let flip_vertical = attributes & 0b1000_0000 != 0;
+        let row_addr = self.sprite_row_addr(tile, flip_vertical, y - sprite_y);
+        self.pattern_pixel_opaque(row_addr, 0, column)
+    }
+
+    // Pattern address of one row of a sprite, row 0 being the top row on screen. 8x16 sprites
+    // take the pattern table from bit 0 of the tile index and stack tiles N and N+1.
+    // https://www.nesdev.org/wiki/PPU_OAM#Byte_1
+    pub fn sprite_row_addr(&self, tile: u8, flip_vertical: bool, row: usize) -> u16 {
+        let height = self.ctrl.sprite_height() as usize;
+        let row = if flip_vertical { height - 1 - row } else { row };
+        let (bank, tile) = if height == 16 {
+            (
+                (tile as u16 & 1) * 0x1000,
+                (tile & 0xfe) as u16 + (row / 8) as u16,
+            )
+        } else {
+            (self.ctrl.sprt_pattern_addr(), tile as u16)
+        };
+        bank + tile * 16 + (row % 8) as u16
+    }
+
+    // same placement as render::render_background
+    fn background_opaque(&self, x: usize, y: usize) -> bool {
+        let scrolled_x = x + self.scroll.x() as usize;
+        let scrolled_y = y + self.scroll.y() as usize;
+        let mut nametable = self.ctrl.nametable_index();
+        if scrolled_x >= 256 {
+            nametable ^= 1;
+        }
+        if scrolled_y >= 240 {
+            nametable ^= 2;
+        }
+        let (scrolled_x, scrolled_y) = (scrolled_x % 256, scrolled_y % 240);
+        let nametable_start = self.mirror_vram_addr(0x2000 + nametable as u16 * 0x400) as usize;
+        let tile = self.vram[nametable_start + (scrolled_y / 8) * 32 + scrolled_x / 8] as u16;
+        self.pattern_pixel_opaque(
+            self.ctrl.bknd_pattern_addr() + tile * 16,
+            scrolled_y % 8,
+            scrolled_x % 8,
+        )
+    }
+
+    // column 0 is the leftmost pixel, the highest bit of both planes
+    fn pattern_pixel_opaque(&self, tile_addr: u16, row: usize, column: usize) -> bool {
+        let bit = 0x80 >> column;
+        let plane_0 = self.read_chr(tile_addr + row as u16);
+        let plane_1 = self.read_chr(tile_addr + row as u16 + 8);
+        (plane_0 | plane_1) & bit != 0
+    }
+
+    // While rendering, the VRAM address moves to the next tile every 8 dots and to the
+    // next pixel row at dot 256, on the visible and pre-render scanlines.
+    // https://www.nesdev.org/wiki/PPU_scrolling#During_dots_1_to_256
+    fn advance_vram_addr(&mut self, dot: usize) {
+        if !self.is_rendering_enabled() || (self.scanline >= 240 && self.scanline != 261) {
+            return;
+        }
+        if dot.is_multiple_of(8) && (dot <= 256 || dot >= 328) {
+            self.increment_coarse_x();
+        }
+        if dot == 256 {
+            self.increment_fine_y();
+        }
+        if dot == 257 {
+            self.copy_horizontal_from_t_to_v();
+        }
+        if self.scanline == 261 && (280..=304).contains(&dot) {
+            self.copy_vertical_from_t_to_v();
+        }
+    }
+
+    // coarse X and the horizontal nametable bit
+    pub fn copy_horizontal_from_t_to_v(&mut self) {
+        let addr = (self.addr.get() & !0x041f) | (self.temp_addr & 0x041f);
+        self.addr.set(addr);
+    }
+
+    // fine Y, the vertical nametable bit and coarse Y
+    pub fn copy_vertical_from_t_to_v(&mut self) {
+        let addr = (self.addr.get() & !0x7be0) | (self.temp_addr & 0x7be0);
+        self.addr.set(addr);
+    }
+
+    // the address is laid out as yyy NN YYYYY XXXXX (fine Y, nametable, coarse Y, coarse X)
+    pub fn increment_coarse_x(&mut self) {
+        let mut addr = self.addr.get();
+        if addr & 0x001f == 31 {
+            addr &= !0x001f;
+            addr ^= 0x0400;
+        } else {
+            addr += 1;
+        }
+        self.addr.set(addr);
+    }
+
+    pub fn increment_fine_y(&mut self) {
+        let mut addr = self.addr.get();
+        if addr & 0x7000 != 0x7000 {
+            addr += 0x1000;
+        } else {
+            addr &= !0x7000;
+            let coarse_y = match (addr & 0x03e0) >> 5 {
+                // row 29 is the last row of tiles, so the next one is in the other nametable
+                29 => {
+                    addr ^= 0x0800;
+                    0
+                }
+                // rows 30 and 31 are the attribute table, wrapping without switching
+                31 => 0,
+                coarse_y => coarse_y + 1,
+            };
+            addr = (addr & !0x03e0) | (coarse_y << 5);
+        }
+        self.addr.set(addr);
+    }
+
+    pub fn generate_frame(&self, frame: &mut Frame) {
+        render::render(self, frame);
+    }
+
+    pub fn generate_frame_to_vec(&self) -> Vec<u8> {
+        let mut frame = Frame::new();
+        self.generate_frame(&mut frame);
+        frame.data
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn cycle(&self) -> usize {
+        self.cycle
+    }
+
+    pub fn is_rendering_enabled(&self) -> bool {
+        self.mask.is_rendering_enabled()
+    }
+
+    // Secondary OAM is filled with $FF before sprite evaluation, $FF meaning "no sprite".
+    pub fn clear_secondary_oam(&mut self) {
+        self.secondary_oam = [0xff; 8 * 4];
+    }
+
+    // Copy up to 8 sprites on the current scanline from OAM into secondary OAM, in OAM order.
+    // A 9th sprite on the scanline sets the sprite overflow flag.
+    pub fn evaluate_sprites(&mut self) {
+        let sprite_height = self.ctrl.sprite_height() as u16;
+
+        let mut count = 0;
+        for i in (0..self.oam_data.len()).step_by(4) {
+            let sprite_y = self.oam_data[i] as u16;
+            if self.scanline < sprite_y || self.scanline >= sprite_y + sprite_height {
+                continue;
+            }
+            if count == 8 {
+                self.status.set_sprite_overflow(true);
+                break;
+            }
+            self.secondary_oam[count * 4..count * 4 + 4].copy_from_slice(&self.oam_data[i..i + 4]);
+            count += 1;
+        }
+    }
+
+    pub fn set_chr_banks(&mut self, banks: [usize; 8]) {
+        self.chr_banks = banks;
+    }
+
+    // $0000-$1FFF -> offset in chr_rom through the mapped banks
+    pub fn chr_addr(&self, addr: u16) -> usize {
+        let addr = addr as usize;
+        self.chr_banks[addr / CHR_BANK_SIZE] + addr % CHR_BANK_SIZE
+    }
+
+    pub fn read_chr(&self, addr: u16) -> u8 {
+        self.chr_rom[self.chr_addr(addr)]
+    }
+
+    // CHR ROM is read-only, so writes are only stored for CHR RAM
+    pub fn write_chr(&mut self, addr: u16, data: u8) {
+        if self.is_chr_ram {
+            let chr_addr = self.chr_addr(addr);
+            self.chr_rom[chr_addr] = data;
+        } else {
+            tracing::trace!("ignored write of {:02x} to CHR ROM at {:04x}", data, addr);
+        }
+    }
+
+    // The banks of a page need not be contiguous in chr_rom, so the page is copied out.
+    fn chr_page(&self, start: u16) -> Vec<u8> {
+        (start..start + CHR_PAGE_SIZE as u16)
+            .map(|addr| self.vram_peek(addr))
+            .collect()
+    }
+
+    pub fn chr_page_0(&self) -> Vec<u8> {
+        self.chr_page(0x0000)
+    }
+
+    pub fn chr_page_1(&self) -> Vec<u8> {
+        self.chr_page(0x1000)
+    }
+
+    pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
         let mirrored_vram = addr & 0b10_1111_1111_1111;
         let vram_index = mirrored_vram - 0x2000;
         let name_table = vram_index / 0x400;
@@ -103,23 +447,73 @@ impl NesPPU {
             (Mirroring::Horizontal, 2) => vram_index - 0x400,
             (Mirroring::Horizontal, 1) => vram_index - 0x400,
             (Mirroring::Horizontal, 3) => vram_index - 0x800,
+            (Mirroring::SingleScreenLower, _) => vram_index % 0x400,
+            (Mirroring::SingleScreenUpper, _) => vram_index % 0x400 + 0x400,
             _ => vram_index,
         }
     }
 
+    // Read the PPU address space without touching the data buffer or the address register
+    pub fn vram_peek(&self, addr: u16) -> u8 {
+        let addr = addr & 0x3fff;
+        match addr {
+            0..=0x1fff => self.read_chr(addr),
+            0x2000..=0x3eff => self.vram[self.mirror_vram_addr(addr) as usize],
+            _ => self.palette_table[palette_addr_to_index(addr)],
+        }
+    }
+
+    #[deprecated(note = "use vram_peek")]
+    pub fn peek_addr(&self, addr: u16) -> u8 {
+        self.vram_peek(addr)
+    }
+
+    // attribute table at the end of each nametable, one byte per 4x4 tiles
+    pub fn attribute_byte(&self, nametable: u8, tile_x: u8, tile_y: u8) -> u8 {
+        let attribute_table = 0x23c0 + (nametable as u16 & 0b11) * 0x400;
+        let index = (tile_y as u16 / 4) * 8 + (tile_x as u16 / 4);
+        self.vram[self.mirror_vram_addr(attribute_table + index) as usize]
+    }
+
+    // each 2x2 tile quadrant of the attribute byte holds 2 bits, top left in the low bits
+    pub fn palette_index_for_tile(&self, nametable: u8, tile_x: u8, tile_y: u8) -> u8 {
+        let attribute = self.attribute_byte(nametable, tile_x, tile_y);
+        let shift = (tile_y % 4 / 2) * 4 + (tile_x % 4 / 2) * 2;
+        (attribute >> shift) & 0b11
+    }
+
     fn increment_vrar_addr(&mut self) {
         self.addr.increment(self.ctrl.vram_addr_increment());
     }
 }
 
+// chr_rom, vram and OAM are summarized, dumping them makes assertion messages unreadable
+impl fmt::Debug for NesPPU {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NesPPU")
+            .field("chr_rom", &format_args!("[{} bytes]", self.chr_rom.len()))
+            .field("is_chr_ram", &self.is_chr_ram)
+            .field("chr_banks", &self.chr_banks)
+            .field("mirroring", &self.mirroring)
+            .field("ctrl", &self.ctrl)
+            .field("mask", &self.mask)
+            .field("status", &self.status)
+            .field("scroll", &self.scroll)
+            .field("addr", &self.addr)
+            .field("oam_addr", &self.oam_addr)
+            .field("scanline", &self.scanline)
+            .field("cycle", &self.cycle)
+            .finish_non_exhaustive()
+    }
+}
+
 impl PPU for NesPPU {
-    fn write_to_ctrl(&mut self, value: u8) {
+    fn write_to_ctrl(&mut self, value: u8) -> bool {
         // println!("write_to_ctrl:{}", value); // TODO
         let before_nmi_status = self.ctrl.generate_vblank_status();
         self.ctrl.update(value);
-        if !before_nmi_status && self.ctrl.generate_vblank_status() && self.status.is_in_vbrank() {
-            self.nmi_interrupt = Some(1);
-        }
+        self.temp_addr = (self.temp_addr & !0x0c00) | ((value as u16 & 0b11) << 10);
+        !before_nmi_status && self.ctrl.generate_vblank_status() && self.status.is_in_vbrank()
     }
 
     fn write_to_mask(&mut self, value: u8) {
@@ -128,9 +522,9 @@ impl PPU for NesPPU {
 
     fn read_status(&mut self) -> u8 {
         let data = self.status.bits();
-        // self.status.set_end_vblank(); // TODO not graphic progress when activate, but not pass test when deactivate
-        // self.addr.reset_latch(); // TODO same
-        // self.scroll.reset_latch(); // TODO same
+        // reading clears vblank, the NMI already went out through the tick result
+        self.status.set_end_vblank();
+        self.w = false;
 
         // println!("read_status:{:04x}", data); // TODO
         data
@@ -150,40 +544,55 @@ impl PPU for NesPPU {
     }
 
     fn write_to_scroll(&mut self, value: u8) {
-        self.scroll.write(value);
+        let value16 = value as u16;
+        if !self.w {
+            self.temp_addr = (self.temp_addr & !0x001f) | (value16 >> 3);
+            self.scroll.write_x(value);
+        } else {
+            self.temp_addr =
+                (self.temp_addr & !0x73e0) | ((value16 & 0b111) << 12) | ((value16 >> 3) << 5);
+            self.scroll.write_y(value);
+        }
+        self.w = !self.w;
     }
 
     fn write_to_ppu_addr(&mut self, value: u8) {
-        self.addr.update(value);
+        let value16 = value as u16;
+        if !self.w {
+            self.temp_addr = (self.temp_addr & 0x00ff) | ((value16 & 0x3f) << 8);
+            self.addr.update_hi(value);
+        } else {
+            self.temp_addr = (self.temp_addr & 0x7f00) | value16;
+            self.addr.update_lo(value);
+        }
+        self.w = !self.w;
     }
 
     fn write_to_data(&mut self, value: u8) {
-        let addr = self.addr.get();
+        // bit 14 holds fine Y while rendering, the PPU bus is 14 bits wide
+        let addr = self.addr.get() & 0x3fff;
         self.increment_vrar_addr();
 
         match addr {
-            0..=0x1fff => {
-                // panic!("ppu addr 0..0x1fff is not used {}", addr);
-            }
+            0..=0x1fff => self.write_chr(addr, value),
             0x2000..=0x3eff => {
                 self.vram[self.mirror_vram_addr(addr) as usize] = value;
             }
             0x3f00..=0x3fff => {
-                let index = (addr - 0x3f00) as usize;
-                self.palette_table[index] = value;
+                self.palette_table[palette_addr_to_index(addr)] = value;
             }
             _ => panic!("unexpected access to mirrored space {}", addr),
         }
     }
 
     fn read_data(&mut self) -> u8 {
-        let addr = self.addr.get();
+        let addr = self.addr.get() & 0x3fff;
         self.increment_vrar_addr();
 
         match addr {
             0..=0x1fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.internal_data_buf = self.read_chr(addr);
                 result
             }
             0x2000..=0x3eff => {
@@ -191,12 +600,15 @@ impl PPU for NesPPU {
                 self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
                 result
             }
-            0x3f00..=0x3fff => self.palette_table[(addr - 0x3f00) as usize],
+            0x3f00..=0x3fff => self.palette_table[palette_addr_to_index(addr)],
             _ => panic!("unexpected access to mirrored space {}", addr),
         }
     }
 
     fn write_oam_dma(&mut self, value: &[u8; 256]) {
+        // OAM DMA always fills the whole OAM starting from OAM[0], whatever oam_addr was set to.
+        // https://www.nesdev.org/wiki/PPU_registers#OAMDMA
+        self.oam_addr = 0;
         for x in value {
             self.oam_data[self.oam_addr as usize] = *x;
             self.oam_addr = self.oam_addr.wrapping_add(1);
@@ -204,17 +616,20 @@ impl PPU for NesPPU {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AddrRegister {
     value: (u8, u8),
-    hi_ptr: bool,
+}
+
+impl Default for AddrRegister {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AddrRegister {
     pub fn new() -> Self {
-        AddrRegister {
-            value: (0, 0),
-            hi_ptr: true,
-        }
+        AddrRegister { value: (0, 0) }
     }
 
     fn set(&mut self, data: u16) {
@@ -223,18 +638,13 @@ impl AddrRegister {
         self.value.1 = (data & 0xff) as u8;
     }
 
-    pub fn update(&mut self, data: u8) {
-        // println!("update {}", data); TODO
-        if self.hi_ptr {
-            self.value.0 = data
-        } else {
-            self.value.1 = data;
-        }
+    // the first $2006 write, which half comes next is tracked by NesPPU::w
+    pub fn update_hi(&mut self, data: u8) {
+        self.value.0 = data & 0x3f;
+    }
 
-        if self.get() > 0x3fff {
-            self.set(self.get() & 0x3fff);
-        }
-        self.hi_ptr = !self.hi_ptr;
+    pub fn update_lo(&mut self, data: u8) {
+        self.value.1 = data;
     }
 
     pub fn increment(&mut self, inc: u8) {
@@ -249,10 +659,6 @@ impl AddrRegister {
         }
     }
 
-    pub fn reset_latch(&mut self) {
-        self.hi_ptr = true;
-    }
-
     pub fn get(&self) -> u16 {
         // println!("get"); TODO
         ((self.value.0 as u16) << 8) | (self.value.1 as u16)
@@ -262,6 +668,7 @@ impl AddrRegister {
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::palette;
 
     #[test]
     fn test_ppu_vram_writes() {
@@ -379,6 +786,41 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x77); //read from B
     }
 
+    #[test]
+    fn test_ppu_chr_ram_writes() {
+        let mut ppu = NesPPU::new(vec![], Mirroring::Horizontal);
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_data(0x66);
+
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_ppu_addr(0x00);
+
+        ppu.read_data(); //load_into_buffer
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    fn test_write_chr() {
+        let mut ppu = NesPPU::new(vec![], Mirroring::Horizontal);
+        ppu.write_chr(0x0010, 0x42);
+        assert_eq!(ppu.read_chr(0x0010), 0x42);
+
+        let mut ppu = NesPPU::new(vec![0; 8 * 1024], Mirroring::Horizontal);
+        ppu.write_chr(0x0010, 0x42);
+        assert_eq!(ppu.read_chr(0x0010), 0x00);
+    }
+
+    #[test]
+    fn test_ppu_chr_rom_writes_ignored() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_data(0x66);
+
+        assert_eq!(ppu.chr_rom[0], 0x00);
+    }
+
     #[test]
     fn test_read_status_resets_latch() {
         let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
@@ -400,6 +842,44 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x66);
     }
 
+    #[test]
+    fn test_read_status_resets_scroll_latch() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.write_to_scroll(0x12);
+        ppu.read_status();
+        ppu.write_to_scroll(0x34);
+
+        assert_eq!(ppu.scroll.x(), 0x34);
+        assert_eq!(ppu.scroll.y(), 0x00);
+    }
+
+    #[test]
+    fn test_third_scroll_write_goes_to_x() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.write_to_scroll(0x12);
+        ppu.write_to_scroll(0x34);
+        ppu.write_to_scroll(0x56);
+
+        assert_eq!(ppu.scroll.x(), 0x56);
+        assert_eq!(ppu.scroll.y(), 0x34);
+    }
+
+    #[test]
+    fn test_scroll_and_addr_share_latch() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        // the $2005 write flips w, so the $2006 write is the low byte
+        ppu.write_to_scroll(0x00);
+        ppu.write_to_ppu_addr(0x45);
+        assert_eq!(ppu.temp_addr & 0x00ff, 0x45);
+        assert_eq!(ppu.addr.get(), 0x0045);
+
+        // and a $2006 high byte write makes the next $2005 write the Y scroll
+        ppu.write_to_ppu_addr(0x21);
+        ppu.write_to_scroll(0x7d);
+        assert_eq!(ppu.scroll.y(), 0x7d);
+        assert_eq!(ppu.scroll.x(), 0x00);
+    }
+
     #[test]
     fn test_ppu_vram_mirroring() {
         let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
@@ -414,6 +894,145 @@ pub mod test {
         // assert_eq!(ppu.addr.read(), 0x0306)
     }
 
+    #[test]
+    fn test_palette_addr_to_index() {
+        assert_eq!(palette_addr_to_index(0x3f00), 0x00);
+        assert_eq!(palette_addr_to_index(0x3f0f), 0x0f);
+        assert_eq!(palette_addr_to_index(0x3f11), 0x11);
+        for (mirror, index) in [
+            (0x3f10, 0x00),
+            (0x3f14, 0x04),
+            (0x3f18, 0x08),
+            (0x3f1c, 0x0c),
+        ] {
+            assert_eq!(palette_addr_to_index(mirror), index);
+            assert_eq!(palette_addr_to_index(mirror + 0x20), index);
+        }
+        assert_eq!(palette_addr_to_index(0x3f25), 0x05);
+        assert_eq!(palette_addr_to_index(0x3fff), 0x1f);
+    }
+
+    #[test]
+    fn test_vram_single_screen_mirroring() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::SingleScreenLower);
+        for nametable in 0..4 {
+            assert_eq!(ppu.mirror_vram_addr(0x2005 + nametable * 0x400), 0x0005);
+        }
+        ppu.mirroring = Mirroring::SingleScreenUpper;
+        for nametable in 0..4 {
+            assert_eq!(ppu.mirror_vram_addr(0x23ff + nametable * 0x400), 0x07ff);
+        }
+    }
+
+    #[test]
+    fn test_palette_mirroring() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.write_to_ctrl(0);
+
+        // $3F10 is shared with the backdrop color $3F00
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x10);
+        ppu.write_to_data(0x2a);
+        assert_eq!(ppu.palette_table[0x00], 0x2a);
+
+        // $3F2C -> $3F0C, palette reads bypass the buffer
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x2c);
+        ppu.write_to_data(0x15);
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x1c);
+        assert_eq!(ppu.read_data(), 0x15);
+
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0xe0);
+        assert_eq!(ppu.read_data(), 0x2a);
+    }
+
+    #[test]
+    fn test_region_name() {
+        assert_eq!(PpuMemoryMap::region_name(0x0000), "Pattern Table 0");
+        assert_eq!(PpuMemoryMap::region_name(0x0fff), "Pattern Table 0");
+        assert_eq!(PpuMemoryMap::region_name(0x1000), "Pattern Table 1");
+        assert_eq!(PpuMemoryMap::region_name(0x1fff), "Pattern Table 1");
+        assert_eq!(PpuMemoryMap::region_name(0x2000), "Nametable 0");
+        assert_eq!(PpuMemoryMap::region_name(0x23ff), "Nametable 0");
+        assert_eq!(PpuMemoryMap::region_name(0x2400), "Nametable 1");
+        assert_eq!(PpuMemoryMap::region_name(0x27ff), "Nametable 1");
+        assert_eq!(PpuMemoryMap::region_name(0x2800), "Nametable 2");
+        assert_eq!(PpuMemoryMap::region_name(0x2bff), "Nametable 2");
+        assert_eq!(PpuMemoryMap::region_name(0x2c00), "Nametable 3");
+        assert_eq!(PpuMemoryMap::region_name(0x2fff), "Nametable 3");
+        assert_eq!(PpuMemoryMap::region_name(0x3000), "Nametable 0");
+        assert_eq!(PpuMemoryMap::region_name(0x3eff), "Nametable 3");
+        assert_eq!(PpuMemoryMap::region_name(0x3f00), "Palette RAM");
+        assert_eq!(PpuMemoryMap::region_name(0x3fff), "Palette RAM");
+        assert_eq!(PpuMemoryMap::region_name(0x4000), "Pattern Table 0");
+    }
+
+    #[test]
+    fn test_vram_peek() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        chr_rom[0x1fff] = 0x11;
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.vram[0x0305] = 0x66;
+        ppu.palette_table[0x04] = 0x2a;
+
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+
+        assert_eq!(ppu.vram_peek(0x1fff), 0x11);
+        assert_eq!(ppu.vram_peek(0x2305), 0x66);
+        assert_eq!(ppu.vram_peek(0x2705), 0x66);
+        assert_eq!(ppu.vram_peek(0x3f14), 0x2a);
+
+        // neither the address nor the read buffer moved
+        assert_eq!(ppu.addr.get(), 0x2305);
+        assert_eq!(ppu.read_data(), 0x00);
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_peek_addr_alias() {
+        let mut ppu = NesPPU::new(vec![0; 8 * 1024], Mirroring::Horizontal);
+        ppu.vram[0x0305] = 0x66;
+        assert_eq!(ppu.peek_addr(0x2305), 0x66);
+    }
+
+    #[test]
+    fn test_vram_peek_after_register_writes() {
+        let mut ppu = NesPPU::new(vec![0; 8 * 1024], Mirroring::Vertical);
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_ppu_addr(0x10);
+        ppu.write_to_data(0x66);
+        ppu.write_to_data(0x77);
+        ppu.write_to_ppu_addr(0x3f);
+        ppu.write_to_ppu_addr(0x10);
+        ppu.write_to_data(0x2a);
+
+        // no dummy read needed, and vertical mirroring maps $2C10 to $2410
+        assert_eq!(ppu.vram_peek(0x2410), 0x66);
+        assert_eq!(ppu.vram_peek(0x2411), 0x77);
+        assert_eq!(ppu.vram_peek(0x2c10), 0x66);
+        assert_eq!(ppu.vram_peek(0x3f00), 0x2a);
+    }
+
+    #[test]
+    fn test_palette_index_for_tile() {
+        let mut ppu = NesPPU::new(vec![0; 8 * 1024], Mirroring::Vertical);
+        // nametable 1, tiles (4..8, 8..12): top left 0, top right 1, bottom left 2, bottom right 3
+        ppu.vram[0x0400 + 0x3c0 + 2 * 8 + 1] = 0b11_10_01_00;
+
+        assert_eq!(ppu.attribute_byte(1, 5, 9), 0b11_10_01_00);
+        assert_eq!(ppu.palette_index_for_tile(1, 4, 8), 0);
+        assert_eq!(ppu.palette_index_for_tile(1, 7, 9), 1);
+        assert_eq!(ppu.palette_index_for_tile(1, 5, 10), 2);
+        assert_eq!(ppu.palette_index_for_tile(1, 6, 11), 3);
+        // nametable 3 mirrors nametable 1
+        assert_eq!(ppu.palette_index_for_tile(3, 6, 11), 3);
+        assert_eq!(ppu.palette_index_for_tile(0, 6, 11), 0);
+    }
+
     #[test]
     fn test_read_status_resets_vblank() {
         let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
@@ -450,7 +1069,10 @@ pub mod test {
         ppu.write_to_oam_addr(0x10);
         ppu.write_oam_dma(&data);
 
-        ppu.write_to_oam_addr(0xf); //wrap around
+        ppu.write_to_oam_addr(0x00);
+        assert_eq!(ppu.read_oam_data(), 0x77);
+
+        ppu.write_to_oam_addr(0xff);
         assert_eq!(ppu.read_oam_data(), 0x88);
 
         ppu.write_to_oam_addr(0x10);
@@ -458,4 +1080,385 @@ pub mod test {
         ppu.write_to_oam_addr(0x11);
         ppu.write_to_oam_addr(0x66);
     }
+
+    #[test]
+    fn test_oam_dma_ignores_oam_addr() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+
+        let mut data = [0x66; 256];
+        data[0] = 0x77;
+
+        ppu.write_to_oam_addr(0x80);
+        ppu.write_oam_dma(&data);
+
+        assert_eq!(ppu.oam_data[0], 0x77);
+        assert_eq!(ppu.oam_data[0x80], 0x66);
+        assert_eq!(ppu.oam_addr, 0);
+    }
+
+    #[test]
+    fn test_nmi_interrupt_on_vblank() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.write_to_ctrl(0b1000_0000);
+
+        for _ in 0..240 {
+            assert!(!ppu.tick(341).nmi_triggered);
+        }
+
+        assert!(ppu.tick(341).nmi_triggered);
+        assert!(ppu.status.is_in_vbrank());
+    }
+
+    #[test]
+    fn test_nmi_follows_ctrl_writes() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        assert!(!ppu.write_to_ctrl(0x00));
+
+        for _ in 0..241 {
+            assert!(!ppu.tick(341).nmi_triggered);
+        }
+        assert!(ppu.status.is_in_vbrank());
+        assert!(!ppu.ctrl.generate_vblank_status());
+
+        // enabling NMI during vblank fires it right away, but only on the enabling write
+        assert!(ppu.write_to_ctrl(0x80));
+        assert!(!ppu.write_to_ctrl(0x80));
+        assert!(ppu.ctrl.generate_vblank_status());
+    }
+
+    #[test]
+    fn test_increment_coarse_x() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.addr.set(0x2005);
+        ppu.increment_coarse_x();
+        assert_eq!(ppu.addr.get(), 0x2006);
+
+        ppu.addr.set(0x201f);
+        ppu.increment_coarse_x();
+        assert_eq!(ppu.addr.get(), 0x2400);
+        ppu.addr.set(0x241f);
+        ppu.increment_coarse_x();
+        assert_eq!(ppu.addr.get(), 0x2000);
+    }
+
+    #[test]
+    fn test_increment_fine_y() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.addr.set(0x2000);
+        ppu.increment_fine_y();
+        assert_eq!(ppu.addr.get(), 0x3000);
+
+        // fine Y 7 moves to the next coarse Y
+        ppu.addr.set(0x7000 | 3 << 5 | 0x05);
+        ppu.increment_fine_y();
+        assert_eq!(ppu.addr.get(), 4 << 5 | 0x05);
+
+        // coarse Y 29 switches to the nametable below
+        ppu.addr.set(0x7000 | 29 << 5);
+        ppu.increment_fine_y();
+        assert_eq!(ppu.addr.get(), 0x0800);
+
+        // coarse Y 31 wraps in the same nametable
+        ppu.addr.set(0x7000 | 0x0800 | 31 << 5);
+        ppu.increment_fine_y();
+        assert_eq!(ppu.addr.get(), 0x0800);
+    }
+
+    #[test]
+    fn test_tick_advances_vram_addr_while_rendering() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.tick(341);
+        assert_eq!(ppu.addr.get(), 0);
+
+        ppu.write_to_mask(0b0000_1000);
+        for _ in 0..341 {
+            ppu.tick(1);
+        }
+        // t is 0, so coarse X restarts at dot 257 before the 2 prefetched tiles
+        assert_eq!(ppu.addr.get(), 0x1000 | 2);
+
+        // ticks spanning several dots and a scanline boundary advance the same way
+        let mut other = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        other.write_to_mask(0b0000_1000);
+        other.tick(341);
+        other.tick(341);
+        for _ in 0..113 {
+            ppu.tick(3);
+        }
+        ppu.tick(2);
+        assert_eq!(ppu.addr.get(), other.addr.get());
+
+        // nothing changes during vblank
+        while ppu.scanline() != 241 {
+            ppu.tick(341);
+        }
+        let addr = ppu.addr.get();
+        ppu.tick(341);
+        assert_eq!(ppu.addr.get(), addr);
+    }
+
+    #[test]
+    fn test_scroll_writes_fill_t() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.write_to_ctrl(0b0000_0011);
+        // X = 0x7d: coarse X 15, fine X 5; Y = 0x5e: coarse Y 11, fine Y 6
+        ppu.write_to_scroll(0x7d);
+        ppu.write_to_scroll(0x5e);
+        assert_eq!(ppu.temp_addr, 0x6000 | 0x0c00 | 11 << 5 | 15);
+        assert_eq!(ppu.scroll.x(), 0x7d);
+
+        ppu.write_to_ppu_addr(0x21);
+        ppu.write_to_ppu_addr(0x08);
+        assert_eq!(ppu.temp_addr, 0x2108);
+        assert_eq!(ppu.addr.get(), 0x2108);
+    }
+
+    #[test]
+    fn test_copy_t_to_v() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.write_to_ctrl(0b0000_0011);
+        ppu.write_to_scroll(0x7d);
+        ppu.write_to_scroll(0x5e);
+        ppu.addr.set(0x0000);
+
+        ppu.copy_horizontal_from_t_to_v();
+        assert_eq!(ppu.addr.get(), 0x0400 | 15);
+
+        ppu.copy_vertical_from_t_to_v();
+        assert_eq!(ppu.addr.get(), 0x6000 | 0x0c00 | 11 << 5 | 15);
+
+        // the copies keep the other bits of v
+        ppu.addr.set(0x7bff);
+        ppu.write_to_ctrl(0);
+        ppu.write_to_scroll(0);
+        ppu.write_to_scroll(0);
+        ppu.copy_horizontal_from_t_to_v();
+        assert_eq!(ppu.addr.get(), 0x7be0);
+    }
+
+    #[test]
+    fn test_pre_render_scanline_reloads_v() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        while ppu.scanline() != 261 {
+            ppu.tick(341);
+        }
+        ppu.write_to_mask(0b0000_1000);
+        ppu.write_to_ctrl(0b0000_0010);
+        ppu.write_to_scroll(0x10);
+        ppu.write_to_scroll(0x22);
+        ppu.tick(341);
+
+        // the vertical copy at dot 304, then 2 coarse X increments at dots 328 and 336
+        assert_eq!(ppu.addr.get(), 0x2000 | 0x0800 | (4 << 5) | (2 + 2));
+    }
+
+    #[test]
+    fn test_tick_result_events() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        assert_eq!(ppu.tick(340), PpuTickResult::default());
+        assert_eq!(
+            ppu.tick(1),
+            PpuTickResult {
+                scanline_completed: Some(0),
+                ..PpuTickResult::default()
+            }
+        );
+
+        // vblank without NMI enabled
+        for _ in 1..240 {
+            ppu.tick(341);
+        }
+        assert!(!ppu.tick(341).nmi_triggered);
+
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.write_to_ctrl(0b1000_0000);
+        for _ in 0..240 {
+            assert!(!ppu.tick(341).nmi_triggered);
+        }
+        let result = ppu.tick(341);
+        assert!(result.nmi_triggered);
+        assert_eq!(result.scanline_completed, Some(240));
+
+        for _ in 241..261 {
+            assert!(!ppu.tick(341).new_frame);
+        }
+        let result = ppu.tick(341);
+        assert!(result.new_frame);
+        assert_eq!(result.scanline_completed, Some(261));
+    }
+
+    #[test]
+    fn test_secondary_oam_cleared_without_sprites() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.write_to_mask(0b0001_1000);
+        ppu.oam_data = [0xff; 256];
+        ppu.secondary_oam = [0; 32];
+
+        ppu.tick(341);
+
+        assert_eq!(ppu.scanline, 1);
+        assert_eq!(ppu.secondary_oam, [0xff; 32]);
+    }
+
+    #[test]
+    fn test_sprite_evaluation_fills_secondary_oam() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.write_to_mask(0b0001_1000);
+        ppu.oam_data = [0xff; 256];
+        ppu.oam_data[20..24].copy_from_slice(&[0x02, 0x11, 0x22, 0x33]);
+
+        ppu.tick(341);
+        ppu.tick(341);
+
+        assert_eq!(ppu.scanline, 2);
+        assert_eq!(ppu.secondary_oam[0..4], [0x02, 0x11, 0x22, 0x33]);
+        assert_eq!(ppu.secondary_oam[4..], [0xff; 28]);
+
+        for _ in 0..8 {
+            ppu.tick(341);
+        }
+
+        assert_eq!(ppu.scanline, 10);
+        assert_eq!(ppu.secondary_oam, [0xff; 32]);
+    }
+
+    #[test]
+    fn test_no_sprite_evaluation_while_rendering_disabled() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.oam_data = [0; 256];
+        ppu.secondary_oam = [0x12; 32];
+
+        ppu.tick(341);
+        assert_eq!(ppu.secondary_oam, [0x12; 32]);
+        assert_eq!(ppu.status.snapshot() & 0b0010_0000, 0);
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let mut ppu = NesPPU::new(vec![0; 2048], Mirroring::Horizontal);
+        ppu.write_to_mask(0b0001_1000);
+        ppu.oam_data = [0xff; 256];
+        for n in 0..8 {
+            ppu.oam_data[n * 4..n * 4 + 4].copy_from_slice(&[0, 1, 0, (n * 8) as u8]);
+        }
+
+        ppu.tick(341);
+        assert_eq!(ppu.status.snapshot() & 0b0010_0000, 0);
+
+        ppu.oam_data[32..40].copy_from_slice(&[0, 1, 0, 64, 0, 1, 0, 72]);
+        ppu.tick(341);
+        assert_eq!(ppu.status.snapshot() & 0b0010_0000, 0b0010_0000);
+        assert_eq!(ppu.secondary_oam[28..32], [0, 1, 0, 56]);
+
+        // the flag is kept until the pre-render scanline
+        ppu.oam_data = [0xff; 256];
+        ppu.tick(341);
+        assert_eq!(ppu.status.snapshot() & 0b0010_0000, 0b0010_0000);
+        for _ in 3..262 {
+            ppu.tick(341);
+        }
+        assert_eq!(ppu.status.snapshot() & 0b0010_0000, 0);
+    }
+
+    fn sprite_zero_hit_ppu() -> NesPPU {
+        let mut chr_rom = vec![0; 8 * 1024];
+        // tile 1 is a solid block
+        chr_rom[16..32].fill(0xff);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.oam_data = [0xff; 256];
+        ppu.write_to_mask(0b0001_1000);
+        ppu
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        let mut ppu = sprite_zero_hit_ppu();
+        // background tile at x 16-23, sprite 0 at x 20-27 and y 4-11
+        ppu.vram[2] = 1;
+        ppu.oam_data[0..4].copy_from_slice(&[4, 1, 0, 20]);
+
+        for _ in 0..4 {
+            ppu.tick(341);
+        }
+        ppu.tick(20);
+        assert!(!ppu.status.is_sprite_zero_hit());
+        // dot 21 outputs pixel 20
+        ppu.tick(1);
+        assert!(ppu.status.is_sprite_zero_hit());
+
+        // kept through vblank, cleared on the pre-render scanline
+        ppu.tick(320);
+        for _ in 5..260 {
+            ppu.tick(341);
+        }
+        assert_eq!(ppu.scanline, 260);
+        assert!(ppu.status.is_sprite_zero_hit());
+        ppu.tick(341);
+        assert!(!ppu.status.is_sprite_zero_hit());
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_needs_opaque_pixels() {
+        let mut ppu = sprite_zero_hit_ppu();
+        // sprite 0 is transparent, sprite 1 overlaps the background
+        ppu.vram[2] = 1;
+        ppu.oam_data[0..8].copy_from_slice(&[0, 0, 0, 16, 0, 1, 0, 16]);
+        for _ in 0..8 {
+            ppu.tick(341);
+        }
+        assert!(!ppu.status.is_sprite_zero_hit());
+
+        // an opaque sprite 0 over a transparent background
+        ppu.vram[2] = 0;
+        ppu.oam_data[1] = 1;
+        for _ in 0..254 {
+            ppu.tick(341);
+        }
+        assert_eq!(ppu.scanline, 0);
+        for _ in 0..8 {
+            ppu.tick(341);
+        }
+        assert!(!ppu.status.is_sprite_zero_hit());
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_left_clipping() {
+        let mut ppu = sprite_zero_hit_ppu();
+        ppu.vram[0] = 1;
+        ppu.oam_data[0..4].copy_from_slice(&[0, 1, 0, 0]);
+        ppu.tick(341);
+        assert!(!ppu.status.is_sprite_zero_hit());
+
+        // show both in the leftmost 8 pixels
+        ppu.write_to_mask(0b0001_1110);
+        for _ in 1..262 {
+            ppu.tick(341);
+        }
+        ppu.tick(1);
+        assert!(ppu.status.is_sprite_zero_hit());
+    }
+
+    #[test]
+    fn test_generate_frame_to_vec() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        // tile 1: left half color 3, right half color 0
+        chr_rom[16..32].fill(0xf0);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.write_to_mask(0b0000_1010);
+        ppu.vram[0] = 1;
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[3] = 0x30;
+
+        let data = ppu.generate_frame_to_vec();
+
+        let pixel = |x: usize, y: usize| {
+            let base = (y * 256 + x) * 3;
+            (data[base], data[base + 1], data[base + 2])
+        };
+        assert_eq!(data.len(), 256 * 240 * 3);
+        assert_eq!(pixel(0, 0), palette::SYSTEM_PALLETE[0x30]);
+        assert_eq!(pixel(3, 7), palette::SYSTEM_PALLETE[0x30]);
+        assert_eq!(pixel(4, 0), palette::SYSTEM_PALLETE[0x0f]);
+        assert_eq!(pixel(8, 0), palette::SYSTEM_PALLETE[0x0f]);
+    }
 }
@@ -1,97 +1,216 @@
 use crate::{frame::Frame, palette, ppu::NesPPU};
 
+// The PPU can only show 8 sprites (the first ones in OAM order) on a scanline.
+const MAX_SPRITES_PER_SCANLINE: u8 = 8;
+
+const OVERLAY_ALPHA: f32 = 0.5;
+const SPRITE_BOX_COLOR: (u8, u8, u8) = (0xff, 0x00, 0xff);
+
+#[derive(Debug, Default, Clone)]
+pub struct DebugConfig {
+    pub show_sprites: bool,
+}
+
 pub fn render(ppu: &NesPPU, frame: &mut Frame) {
+    // start from the universal background color so nothing of the previous frame is left
+    let backdrop = apply_greyscale(ppu.palette_table[0], ppu.mask.is_grayscale());
+    frame.clear(palette::SYSTEM_PALLETE[backdrop as usize]);
+    // games turn rendering off while they load, leaving only the backdrop on screen
+    if ppu.mask.show_background() {
+        render_background(ppu, frame);
+    }
+    if ppu.mask.show_sprites() {
+        render_sprites(ppu, frame);
+    }
+}
+
+fn render_background(ppu: &NesPPU, frame: &mut Frame) {
+    let scroll_x = ppu.scroll.x() as isize;
+    let scroll_y = ppu.scroll.y() as isize;
+    let nametable = ppu.ctrl.nametable_index();
+
+    // scrolling brings in the nametables to the right of and below the base one,
+    // the mirroring decides which of them share memory
+    render_nametable(ppu, frame, nametable, -scroll_x, -scroll_y);
+    if scroll_x > 0 {
+        render_nametable(ppu, frame, nametable ^ 1, 256 - scroll_x, -scroll_y);
+    }
+    if scroll_y > 0 {
+        render_nametable(ppu, frame, nametable ^ 2, -scroll_x, 240 - scroll_y);
+    }
+    if scroll_x > 0 && scroll_y > 0 {
+        render_nametable(ppu, frame, nametable ^ 3, 256 - scroll_x, 240 - scroll_y);
+    }
+}
+
+// draws a whole nametable shifted by (offset_x, offset_y) pixels, clipped to the screen
+fn render_nametable(
+    ppu: &NesPPU,
+    frame: &mut Frame,
+    nametable: u8,
+    offset_x: isize,
+    offset_y: isize,
+) {
     let bank = ppu.ctrl.bknd_pattern_addr();
+    let greyscale = ppu.mask.is_grayscale();
+    let show_left = ppu.mask.show_left_background();
+    let nametable_start = ppu.mirror_vram_addr(0x2000 + nametable as u16 * 0x400) as usize;
 
-    for i in 0..0x03cf {
-        let tile = ppu.vram[i] as u16;
+    for i in 0..0x03c0 {
         let tile_x = i % 32;
         let tile_y = i / 32;
-        let tile = &ppu.chr_rom[(bank + tile * 16) as usize..=(bank + tile * 16 + 15) as usize];
-        let palette = bg_pallette(ppu, tile_x, tile_y);
+        let tile_screen_x = (tile_x * 8) as isize + offset_x;
+        let tile_screen_y = (tile_y * 8) as isize + offset_y;
+        if tile_screen_x <= -8
+            || tile_screen_x >= 256
+            || tile_screen_y <= -8
+            || tile_screen_y >= 240
+        {
+            continue;
+        }
+        let tile = ppu.vram[nametable_start + i] as u16;
+        let tile_addr = ppu.chr_addr(bank + tile * 16);
+        let tile = &ppu.chr_rom[tile_addr..=tile_addr + 15];
+        let palette = bg_pallette(ppu, nametable, tile_x, tile_y);
 
         for y in 0..=7 {
+            let screen_y = tile_screen_y + y as isize;
+            if !(0..240).contains(&screen_y) {
+                continue;
+            }
             let mut upper = tile[y];
             let mut lower = tile[y + 8];
 
             for x in (0..=7).rev() {
-                let value = (1 & upper) << 1 | (1 & lower);
-                upper = upper >> 1;
-                lower = lower >> 1;
-
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[ppu.palette_table[0] as usize],
-                    1 => palette::SYSTEM_PALLETE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[palette[3] as usize],
+                // the first plane holds bit 0 of the color, as for sprites
+                let value = (1 & lower) << 1 | (1 & upper);
+                upper >>= 1;
+                lower >>= 1;
+
+                let screen_x = tile_screen_x + x as isize;
+                if !(0..256).contains(&screen_x) {
+                    continue;
+                }
+                // the left column clip shows the universal background color instead
+                let value = if screen_x < 8 && !show_left { 0 } else { value };
+                let color = match value {
+                    0 => ppu.palette_table[0],
+                    1 => palette[1],
+                    2 => palette[2],
+                    3 => palette[3],
                     _ => panic!("can't be"),
                 };
-                frame.set_pixcel(tile_x * 8 + x, tile_y * 8 + y, rgb)
+                let rgb = palette::SYSTEM_PALLETE[apply_greyscale(color, greyscale) as usize];
+                frame.set_pixcel(screen_x as usize, screen_y as usize, rgb)
             }
         }
     }
+}
+
+fn render_sprites(ppu: &NesPPU, frame: &mut Frame) {
+    let visible_rows = sprite_visible_rows(ppu);
+    let sprite_height = ppu.ctrl.sprite_height() as usize;
+    let greyscale = ppu.mask.is_grayscale();
+    let show_left = ppu.mask.show_left_sprites();
 
     for i in (0..ppu.oam_data.len()).step_by(4).rev() {
-        let tile_idx = ppu.oam_data[i + 1] as u16;
+        let tile_idx = ppu.oam_data[i + 1];
         let tile_x = ppu.oam_data[i + 3] as usize;
         let tile_y = ppu.oam_data[i] as usize;
 
-        let flip_vertical = if ppu.oam_data[i + 2] >> 7 & 1 == 1 {
-            true
-        } else {
-            false
-        };
-        let flip_horizontial = if ppu.oam_data[i + 2] >> 6 & 1 == 1 {
-            true
-        } else {
-            false
-        };
+        let flip_vertical = ppu.oam_data[i + 2] >> 7 & 1 == 1;
+        let flip_horizontal = ppu.oam_data[i + 2] >> 6 & 1 == 1;
 
         let palette_idx = ppu.oam_data[i + 2] & 0b11;
         let sprite_palette = sprite_palette(ppu, palette_idx);
 
-        let bank = ppu.ctrl.sprt_pattern_addr();
-
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
-
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
+        for (y, &visible) in visible_rows[i / 4][..sprite_height].iter().enumerate() {
+            if !visible {
+                continue;
+            }
+            let row_addr = ppu.sprite_row_addr(tile_idx, flip_vertical, y);
+            let mut upper = ppu.read_chr(row_addr);
+            let mut lower = ppu.read_chr(row_addr + 8);
+            if flip_horizontal {
+                upper = flip_horizontally(upper);
+                lower = flip_horizontally(lower);
+            }
 
             'loop_skip: for x in (0..=7).rev() {
                 let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
+                upper >>= 1;
+                lower >>= 1;
+                if tile_x + x < 8 && !show_left {
+                    continue 'loop_skip;
+                }
+                let color = match value {
                     0 => continue 'loop_skip,
-                    1 => palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[sprite_palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[sprite_palette[3] as usize],
+                    1 => sprite_palette[1],
+                    2 => sprite_palette[2],
+                    3 => sprite_palette[3],
                     _ => panic!("can't be"),
                 };
-                match (flip_horizontial, flip_vertical) {
-                    (false, false) => frame.set_pixcel(tile_x + x, tile_y + y, rgb),
-                    (true, false) => frame.set_pixcel(tile_x + 7 - x, tile_y + y, rgb),
-                    (false, true) => frame.set_pixcel(tile_x + x, tile_y + 7 - y, rgb),
-                    (true, true) => frame.set_pixcel(tile_x + 7 - x, tile_y + 7 - y, rgb),
-                }
+                let rgb = palette::SYSTEM_PALLETE[apply_greyscale(color, greyscale) as usize];
+                frame.set_pixcel(tile_x + x, tile_y + y, rgb);
             }
         }
     }
 }
 
-fn bg_pallette(ppu: &NesPPU, tile_column: usize, tile_row: usize) -> [u8; 4] {
-    let attr_table_idx = tile_row / 4 * 8 + tile_column / 4;
-    let attr_byte = ppu.vram[0x3c0 + attr_table_idx];
+// greyscale keeps only the brightness, the grey column $x0 of the system palette
+fn apply_greyscale(nes_color: u8, greyscale: bool) -> u8 {
+    if greyscale {
+        nes_color & 0x30
+    } else {
+        nes_color
+    }
+}
+
+// the leftmost pixel is the highest bit of a pattern row
+fn flip_horizontally(row_bits: u8) -> u8 {
+    row_bits.reverse_bits()
+}
+
+// Debug overlays drawn over the rendered frame, e.g. sprite bounding boxes.
+pub fn render_debug_overlay(ppu: &NesPPU, frame: &mut Frame, config: &DebugConfig) {
+    if !config.show_sprites {
+        return;
+    }
+    let mut overlay = frame.clone();
+    let sprite_height = ppu.ctrl.sprite_height() as usize;
+    for sprite in ppu.oam_data.chunks(4) {
+        let (y, x) = (sprite[0] as usize, sprite[3] as usize);
+        // sprites at Y >= $EF are hidden below the screen
+        if y < 0xef {
+            overlay.draw_rect(x, y, 8, sprite_height, SPRITE_BOX_COLOR);
+        }
+    }
+    frame.blend_alpha(&overlay, OVERLAY_ALPHA);
+}
+
+// For each sprite, which of its rows survive the per-scanline sprite limit.
+fn sprite_visible_rows(ppu: &NesPPU) -> [[bool; 16]; 64] {
+    let mut sprites_on_scanline = [0u8; 256];
+    let mut visible_rows = [[false; 16]; 64];
+    let sprite_height = ppu.ctrl.sprite_height() as usize;
+
+    for i in (0..ppu.oam_data.len()).step_by(4) {
+        let tile_y = ppu.oam_data[i] as usize;
+        for (row, visible) in visible_rows[i / 4][..sprite_height].iter_mut().enumerate() {
+            let scanline = tile_y + row;
+            if scanline < sprites_on_scanline.len()
+                && sprites_on_scanline[scanline] < MAX_SPRITES_PER_SCANLINE
+            {
+                sprites_on_scanline[scanline] += 1;
+                *visible = true;
+            }
+        }
+    }
+    visible_rows
+}
 
-    let palette_idx = match (tile_column % 4 / 2, tile_row % 4 / 2) {
-        (0, 0) => attr_byte & 0b11,
-        (1, 0) => (attr_byte >> 2) & 0b11,
-        (0, 1) => (attr_byte >> 4) & 0b11,
-        (1, 1) => (attr_byte >> 6) & 0b11,
-        _ => panic!("should not happen"),
-    };
+fn bg_pallette(ppu: &NesPPU, nametable: u8, tile_column: usize, tile_row: usize) -> [u8; 4] {
+    let palette_idx = ppu.palette_index_for_tile(nametable, tile_column as u8, tile_row as u8);
 
     let palette_start: usize = 1 + (palette_idx as usize) * 4;
     [
@@ -111,3 +230,411 @@ fn sprite_palette(ppu: &NesPPU, palette_idx: u8) -> [u8; 4] {
         ppu.palette_table[start + 2],
     ]
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartoridge::Mirroring;
+    use crate::ppu::PPU;
+
+    // background and sprites on, including the leftmost 8 pixels
+    const SHOW_ALL: u8 = 0b0001_1110;
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = y * 3 * 256 + x * 3;
+        (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+    }
+
+    #[test]
+    fn test_render_max_8_sprites_per_scanline() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        // tile 1 is a solid block of color 1
+        chr_rom[16..24].fill(0xff);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.mask.update(SHOW_ALL);
+        ppu.palette_table[0x11] = 0x30;
+        ppu.oam_data = [0xff; 256];
+        for n in 0..10 {
+            ppu.oam_data[n * 4..n * 4 + 4].copy_from_slice(&[0, 1, 0, (n * 8) as u8]);
+        }
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        let sprite_color = palette::SYSTEM_PALLETE[0x30];
+        let background_color = palette::SYSTEM_PALLETE[0];
+        for n in 0..8 {
+            assert_eq!(pixel(&frame, n * 8, 0), sprite_color);
+            assert_eq!(pixel(&frame, n * 8 + 7, 7), sprite_color);
+        }
+        for n in 8..10 {
+            assert_eq!(pixel(&frame, n * 8, 0), background_color);
+            assert_eq!(pixel(&frame, n * 8 + 7, 7), background_color);
+        }
+    }
+
+    #[test]
+    fn test_render_background_from_nametable_1() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        // tile 1 is a solid block of color 3
+        chr_rom[16..32].fill(0xff);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Vertical);
+        ppu.mask.update(SHOW_ALL);
+        ppu.palette_table[3] = 0x30;
+        ppu.oam_data = [0xff; 256];
+        // tile 1 at the top left of nametable 1 only
+        ppu.vram[0x400] = 1;
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), palette::SYSTEM_PALLETE[0]);
+
+        ppu.ctrl.update(0b0000_0001);
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), palette::SYSTEM_PALLETE[0x30]);
+        assert_eq!(pixel(&frame, 8, 0), palette::SYSTEM_PALLETE[0]);
+    }
+
+    #[test]
+    fn test_render_background_palettes() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        // tile 1 is color 1 (first plane only), tile 2 is color 2 (second plane only)
+        chr_rom[16..24].fill(0xff);
+        chr_rom[40..48].fill(0xff);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.mask.update(SHOW_ALL);
+        ppu.oam_data = [0xff; 256];
+        // one tile in each 16x16 quadrant of the first attribute byte
+        ppu.vram[0] = 1;
+        ppu.vram[2] = 2;
+        ppu.vram[64] = 1;
+        ppu.vram[66] = 1;
+        // top left palette 0, top right 1, bottom left 2, bottom right 3
+        ppu.vram[0x3c0] = 0b11_10_01_00;
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x01;
+        ppu.palette_table[6] = 0x16;
+        ppu.palette_table[9] = 0x29;
+        ppu.palette_table[13] = 0x30;
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        assert_eq!(pixel(&frame, 0, 0), palette::SYSTEM_PALLETE[0x01]);
+        assert_eq!(pixel(&frame, 23, 7), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 0, 16), palette::SYSTEM_PALLETE[0x29]);
+        assert_eq!(pixel(&frame, 16, 16), palette::SYSTEM_PALLETE[0x30]);
+        // color 0 is the universal background color
+        assert_eq!(pixel(&frame, 8, 0), palette::SYSTEM_PALLETE[0x0f]);
+    }
+
+    #[test]
+    fn test_render_scroll_into_adjacent_nametables() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        // tile 1 is color 1, tile 2 is color 2
+        chr_rom[16..24].fill(0xff);
+        chr_rom[40..48].fill(0xff);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Vertical);
+        ppu.mask.update(SHOW_ALL);
+        ppu.oam_data = [0xff; 256];
+        ppu.palette_table[1] = 0x01;
+        ppu.palette_table[2] = 0x16;
+        // column 16 of nametable 0 and column 0 of nametable 1
+        ppu.vram[16] = 1;
+        ppu.vram[0x400] = 2;
+
+        ppu.scroll.write_x(128);
+        ppu.scroll.write_y(0);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), palette::SYSTEM_PALLETE[0x01]);
+        assert_eq!(pixel(&frame, 128, 0), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 135, 7), palette::SYSTEM_PALLETE[0x16]);
+
+        // with nametable 1 as the base, scrolling right wraps back to nametable 0
+        ppu.vram[0] = 1;
+        ppu.ctrl.update(0b0000_0001);
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), palette::SYSTEM_PALLETE[0]);
+        assert_eq!(pixel(&frame, 128, 0), palette::SYSTEM_PALLETE[0x01]);
+    }
+
+    #[test]
+    fn test_render_vertical_scroll() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        chr_rom[16..24].fill(0xff);
+        chr_rom[40..48].fill(0xff);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.mask.update(SHOW_ALL);
+        ppu.oam_data = [0xff; 256];
+        ppu.palette_table[1] = 0x01;
+        ppu.palette_table[2] = 0x16;
+        // row 15 of nametable 0 and row 0 of nametable 2
+        ppu.vram[15 * 32] = 1;
+        ppu.vram[0x400] = 2;
+
+        ppu.scroll.write_x(0);
+        ppu.scroll.write_y(120);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), palette::SYSTEM_PALLETE[0x01]);
+        assert_eq!(pixel(&frame, 0, 119), palette::SYSTEM_PALLETE[0]);
+        assert_eq!(pixel(&frame, 0, 120), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 7, 127), palette::SYSTEM_PALLETE[0x16]);
+    }
+
+    #[test]
+    fn test_render_fine_x_scroll() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        // tile 1 has only its leftmost column set, in color 3
+        chr_rom[16..32].fill(0x80);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Vertical);
+        ppu.mask.update(SHOW_ALL);
+        ppu.palette_table[3] = 0x30;
+        ppu.oam_data = [0xff; 256];
+        ppu.vram[1] = 1;
+        // first column of nametable 1
+        ppu.vram[0x400] = 1;
+
+        let set_columns = |frame: &Frame| -> Vec<usize> {
+            (0..256)
+                .filter(|x| pixel(frame, *x, 0) == palette::SYSTEM_PALLETE[0x30])
+                .collect()
+        };
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(set_columns(&frame), vec![8]);
+
+        ppu.scroll.write_x(3);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(set_columns(&frame), vec![5, 253]);
+    }
+
+    // tile 1 has a single color 1 pixel at the top left, tile 2 fills its top row
+    fn flip_test_ppu(attributes: u8) -> NesPPU {
+        let mut chr_rom = vec![0; 8 * 1024];
+        chr_rom[16] = 0b1000_0000;
+        chr_rom[32] = 0b1100_0000;
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.mask.update(SHOW_ALL);
+        ppu.palette_table[0x11] = 0x30;
+        ppu.oam_data = [0xff; 256];
+        ppu.oam_data[0..4].copy_from_slice(&[8, 1, attributes, 16]);
+        ppu
+    }
+
+    fn sprite_pixels(frame: &Frame) -> Vec<(usize, usize)> {
+        let mut pixels = Vec::new();
+        for y in 8..16 {
+            for x in 16..24 {
+                if pixel(frame, x, y) == palette::SYSTEM_PALLETE[0x30] {
+                    pixels.push((x - 16, y - 8));
+                }
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn test_flip_horizontally() {
+        assert_eq!(flip_horizontally(0b1100_0001), 0b1000_0011);
+        assert_eq!(flip_horizontally(0xff), 0xff);
+    }
+
+    #[test]
+    fn test_sprite_flips() {
+        let cases = [
+            (0b0000_0000, (0, 0)),
+            (0b0100_0000, (7, 0)),
+            (0b1000_0000, (0, 7)),
+            (0b1100_0000, (7, 7)),
+        ];
+        for (attributes, expected) in cases {
+            let mut frame = Frame::new();
+            render(&flip_test_ppu(attributes), &mut frame);
+            assert_eq!(sprite_pixels(&frame), vec![expected]);
+        }
+
+        let mut ppu = flip_test_ppu(0b0100_0000);
+        ppu.oam_data[1] = 2;
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(sprite_pixels(&frame), vec![(6, 0), (7, 0)]);
+    }
+
+    #[test]
+    fn test_render_8x16_sprite() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        // tiles 2 and 3 of the $1000 pattern table in colors 1 and 2
+        chr_rom[0x1000 + 32..0x1000 + 40].fill(0xff);
+        chr_rom[0x1000 + 56..0x1000 + 64].fill(0xff);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.mask.update(SHOW_ALL);
+        ppu.palette_table[0x11] = 0x01;
+        ppu.palette_table[0x12] = 0x16;
+        ppu.oam_data = [0xff; 256];
+        // odd tile index: $1000 pattern table, tiles 2 (top) and 3 (bottom)
+        ppu.oam_data[0..4].copy_from_slice(&[10, 3, 0, 20]);
+        ppu.ctrl.update(0b0010_0000);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 20, 10), palette::SYSTEM_PALLETE[0x01]);
+        assert_eq!(pixel(&frame, 27, 17), palette::SYSTEM_PALLETE[0x01]);
+        assert_eq!(pixel(&frame, 20, 18), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 27, 25), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 20, 26), palette::SYSTEM_PALLETE[0]);
+
+        // vertical flip swaps the two tiles too
+        ppu.oam_data[2] = 0b1000_0000;
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 20, 10), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 20, 18), palette::SYSTEM_PALLETE[0x01]);
+    }
+
+    #[test]
+    fn test_left_column_background_clipping() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        chr_rom[16..24].fill(0xff);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.oam_data = [0xff; 256];
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x16;
+        ppu.vram[0] = 1;
+        ppu.vram[1] = 1;
+
+        // background on, left column hidden
+        ppu.mask.update(0b0001_1000);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), palette::SYSTEM_PALLETE[0x0f]);
+        assert_eq!(pixel(&frame, 7, 7), palette::SYSTEM_PALLETE[0x0f]);
+        assert_eq!(pixel(&frame, 8, 0), palette::SYSTEM_PALLETE[0x16]);
+
+        ppu.mask.update(0b0001_1010);
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), palette::SYSTEM_PALLETE[0x16]);
+    }
+
+    #[test]
+    fn test_left_column_sprite_clipping() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        chr_rom[16..24].fill(0xff);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.oam_data = [0xff; 256];
+        ppu.palette_table[0x11] = 0x30;
+        // straddles the clipped column, x 4-11
+        ppu.oam_data[0..4].copy_from_slice(&[0, 1, 0, 4]);
+
+        ppu.mask.update(0b0001_1000);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 4, 0), palette::SYSTEM_PALLETE[0]);
+        assert_eq!(pixel(&frame, 7, 0), palette::SYSTEM_PALLETE[0]);
+        assert_eq!(pixel(&frame, 8, 0), palette::SYSTEM_PALLETE[0x30]);
+
+        ppu.mask.update(0b0001_1100);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 4, 0), palette::SYSTEM_PALLETE[0x30]);
+    }
+
+    #[test]
+    fn test_render_hides_background_when_disabled() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        chr_rom[16..24].fill(0xff);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.oam_data = [0xff; 256];
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x16;
+        ppu.vram[0] = 1;
+
+        ppu.mask.update(SHOW_ALL);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), palette::SYSTEM_PALLETE[0x16]);
+
+        // sprites only
+        ppu.mask.update(0b0001_0100);
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), palette::SYSTEM_PALLETE[0x0f]);
+    }
+
+    #[test]
+    fn test_render_hides_sprites_when_disabled() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        chr_rom[16..24].fill(0xff);
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.oam_data = [0xff; 256];
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[0x11] = 0x30;
+        ppu.oam_data[0..4].copy_from_slice(&[0, 1, 0, 16]);
+
+        ppu.mask.update(SHOW_ALL);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 16, 0), palette::SYSTEM_PALLETE[0x30]);
+
+        // background only
+        ppu.mask.update(0b0000_1010);
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 16, 0), palette::SYSTEM_PALLETE[0x0f]);
+    }
+
+    #[test]
+    fn test_render_greyscale() {
+        let mut chr_rom = vec![0; 8 * 1024];
+        // tile 1 has colors 1, 2 and 3 in its first three rows
+        chr_rom[16] = 0xff;
+        chr_rom[17 + 8] = 0xff;
+        chr_rom[18] = 0xff;
+        chr_rom[18 + 8] = 0xff;
+        let mut ppu = NesPPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.mask.update(SHOW_ALL);
+        ppu.oam_data = [0xff; 256];
+        ppu.oam_data[0..4].copy_from_slice(&[8, 1, 0, 0]);
+        ppu.vram[0] = 1;
+        ppu.palette_table[..4].copy_from_slice(&[0x21, 0x16, 0x2a, 0x3c]);
+        ppu.palette_table[0x11..0x14].copy_from_slice(&[0x05, 0x19, 0x12]);
+        ppu.write_to_mask(SHOW_ALL | 0x01);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        for (x, y) in [(0, 0), (0, 1), (0, 2), (0, 3), (0, 8), (0, 9), (0, 10)] {
+            let (r, g, b) = pixel(&frame, x, y);
+            assert!(r == g && g == b, "({}, {}) is not grey", x, y);
+        }
+        assert_eq!(pixel(&frame, 0, 1), palette::SYSTEM_PALLETE[0x20]);
+
+        ppu.write_to_mask(SHOW_ALL);
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 1), palette::SYSTEM_PALLETE[0x2a]);
+    }
+
+    #[test]
+    fn test_apply_greyscale() {
+        assert_eq!(apply_greyscale(0x16, true), 0x10);
+        assert_eq!(apply_greyscale(0x3c, true), 0x30);
+        assert_eq!(apply_greyscale(0x16, false), 0x16);
+    }
+
+    #[test]
+    fn test_debug_overlay_sprite_boxes() {
+        let mut ppu = NesPPU::new(vec![0; 8 * 1024], Mirroring::Horizontal);
+        ppu.oam_data = [0xff; 256];
+        ppu.oam_data[0..4].copy_from_slice(&[16, 0, 0, 32]);
+
+        let mut frame = Frame::new();
+        render_debug_overlay(&ppu, &mut frame, &DebugConfig::default());
+        assert_eq!(pixel(&frame, 32, 16), (0, 0, 0));
+
+        let config = DebugConfig { show_sprites: true };
+        render_debug_overlay(&ppu, &mut frame, &config);
+        assert_eq!(pixel(&frame, 32, 16), (0x80, 0, 0x80));
+        assert_eq!(pixel(&frame, 39, 23), (0x80, 0, 0x80));
+        assert_eq!(pixel(&frame, 35, 20), (0, 0, 0));
+    }
+}
@@ -0,0 +1,132 @@
+use sha2::{Digest, Sha256};
+
+use crate::cartoridge::Mirroring;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    World,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomDbEntry {
+    pub title: String,
+    pub region: Region,
+    pub mapper: u8,
+    pub correct_mirroring: Mirroring,
+}
+
+// (SHA-256 of the whole .nes file, title, region, mapper, mirroring)
+const BUNDLED_DB: &[(&str, &str, Region, u8, Mirroring)] = &[
+    (
+        "f67d55fd6b3cf0bad1cc85f1df0d739c65b53e79cecb7fea8f77ec0eadab0004",
+        "nestest",
+        Region::World,
+        0,
+        Mirroring::Horizontal,
+    ),
+    (
+        "1528f53e2aa6d4a230c21558646a446cf914e436d82f91a6fd69b3199890a866",
+        "Hello World (sample)",
+        Region::World,
+        0,
+        Mirroring::Vertical,
+    ),
+    (
+        "aa9d7ee02fa6fc6a4af89f5a62ff9b7157b832b6781c62622cb62a3d08d0e002",
+        "Snake (sample)",
+        Region::World,
+        0,
+        Mirroring::Vertical,
+    ),
+];
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+pub fn hash_to_hex(hash: &[u8; 32]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_to_hash(hex: &str) -> [u8; 32] {
+    let mut hash = [0; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    hash
+}
+
+pub struct RomDatabase {
+    entries: Vec<([u8; 32], RomDbEntry)>,
+}
+
+impl Default for RomDatabase {
+    fn default() -> Self {
+        let entries = BUNDLED_DB
+            .iter()
+            .map(|(hex, title, region, mapper, mirroring)| {
+                let entry = RomDbEntry {
+                    title: title.to_string(),
+                    region: *region,
+                    mapper: *mapper,
+                    correct_mirroring: mirroring.clone(),
+                };
+                (hex_to_hash(hex), entry)
+            })
+            .collect();
+        RomDatabase { entries }
+    }
+}
+
+impl RomDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn empty() -> Self {
+        RomDatabase {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn lookup(&self, sha256: &[u8; 32]) -> Option<RomDbEntry> {
+        self.entries
+            .iter()
+            .find(|(hash, _)| hash == sha256)
+            .map(|(_, entry)| entry.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookup_nestest() {
+        let hash = hex_to_hash("f67d55fd6b3cf0bad1cc85f1df0d739c65b53e79cecb7fea8f77ec0eadab0004");
+        let entry = RomDatabase::new().lookup(&hash).unwrap();
+
+        assert_eq!(entry.title, "nestest");
+        assert_eq!(entry.region, Region::World);
+        assert_eq!(entry.mapper, 0);
+        assert_eq!(entry.correct_mirroring, Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_lookup_unknown() {
+        assert_eq!(RomDatabase::new().lookup(&[0; 32]), None);
+        let hash = hex_to_hash("f67d55fd6b3cf0bad1cc85f1df0d739c65b53e79cecb7fea8f77ec0eadab0004");
+        assert_eq!(RomDatabase::empty().lookup(&hash), None);
+    }
+
+    #[test]
+    fn test_sha256() {
+        let hash = sha256(b"abc");
+        assert_eq!(
+            hash_to_hex(&hash),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
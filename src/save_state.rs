@@ -0,0 +1,203 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bus::{Bus, BusState};
+use crate::cpu::{CpuState, CPU};
+use crate::mapper::MapperStateError;
+
+// serde only implements arrays up to 32 elements, larger byte arrays go through a Vec
+pub mod byte_array {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        data: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(data)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        let data = Vec::<u8>::deserialize(deserializer)?;
+        let len = data.len();
+        data.try_into()
+            .map_err(|_| D::Error::invalid_length(len, &"a byte array of the field's size"))
+    }
+}
+
+// The whole machine except the callbacks on the bus. Those can't be serialized,
+// load_state keeps the ones of the running emulator.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SaveState {
+    pub cpu: CpuState,
+    pub bus: BusState,
+}
+
+impl SaveState {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    pub fn write_to_file(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_json()?)
+    }
+
+    pub fn read_from_file(path: &Path) -> io::Result<Self> {
+        Ok(SaveState::from_json(&fs::read_to_string(path)?)?)
+    }
+}
+
+impl CPU<Bus<'_>> {
+    pub fn save_state(&self) -> SaveState {
+        SaveState {
+            cpu: self.cpu_state(),
+            bus: self.bus.save_state(),
+        }
+    }
+
+    // the bus goes first, so a state it rejects leaves the CPU untouched too
+    pub fn load_state(&mut self, state: SaveState) -> Result<(), MapperStateError> {
+        self.bus.load_state(state.bus)?;
+        self.load_cpu_state(state.cpu);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct EmulatorConfig {
+    // where save states go, usually a .sav file next to the ROM
+    pub save_state_path: Option<PathBuf>,
+}
+
+impl EmulatorConfig {
+    // without a save state path there is nothing to do
+    pub fn save_state(&self, cpu: &CPU<Bus<'_>>) -> io::Result<()> {
+        match &self.save_state_path {
+            Some(path) => cpu.save_state().write_to_file(path),
+            None => Ok(()),
+        }
+    }
+
+    // returns whether a state was loaded, a state for another cartridge is InvalidData
+    pub fn load_state(&self, cpu: &mut CPU<Bus<'_>>) -> io::Result<bool> {
+        match &self.save_state_path {
+            Some(path) if path.exists() => {
+                cpu.load_state(SaveState::read_from_file(path)?)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartoridge::Rom;
+    use crate::cpu::Memory;
+    use crate::joypad::Joypad;
+    use crate::ppu::NesPPU;
+
+    // INX, STX $10, INC $0200,X, JMP $8000
+    const PROGRAM: [u8; 9] = [0xe8, 0x86, 0x10, 0xfe, 0x00, 0x02, 0x4c, 0x00, 0x80];
+
+    fn test_cpu<'a>() -> CPU<Bus<'a>> {
+        let mut rom_data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        let prg_start = rom_data.len();
+        rom_data.resize(prg_start + 2 * 16 * 1024, 0);
+        rom_data[prg_start..prg_start + PROGRAM.len()].copy_from_slice(&PROGRAM);
+        // reset vector at $8000
+        let len = rom_data.len();
+        rom_data[len - 4..len - 2].copy_from_slice(&[0x00, 0x80]);
+        rom_data.resize(len + 8 * 1024, 0);
+
+        let rom = Rom::from_bytes(&rom_data).unwrap();
+        let mut cpu = CPU::new(Bus::new(
+            rom,
+            |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {},
+        ));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let mut cpu = test_cpu();
+        for _ in 0..1000 {
+            cpu.step();
+        }
+        let saved = SaveState::from_json(&cpu.save_state().to_json().unwrap()).unwrap();
+        let saved_json = saved.to_json().unwrap();
+        let x = cpu.register_x;
+        let counter = cpu.mem_read(0x0200 + x as u16);
+
+        for _ in 0..500 {
+            cpu.step();
+        }
+        cpu.register_a = 0x42;
+        cpu.mem_write(0x0010, 0xff);
+        assert_ne!(cpu.save_state().to_json().unwrap(), saved_json);
+
+        cpu.load_state(saved).unwrap();
+        assert_eq!(cpu.save_state().to_json().unwrap(), saved_json);
+        assert_eq!(cpu.register_x, x);
+        assert_eq!(cpu.mem_read(0x0010), x);
+        assert_eq!(cpu.mem_read(0x0200 + x as u16), counter);
+    }
+
+    #[test]
+    fn test_emulator_config_save_state_file() {
+        let path = std::env::temp_dir().join("rustnes_test_save_state.sav");
+        let config = EmulatorConfig {
+            save_state_path: Some(path.clone()),
+        };
+        let mut cpu = test_cpu();
+        for _ in 0..100 {
+            cpu.step();
+        }
+        config.save_state(&cpu).unwrap();
+        let saved_cycles = cpu.get_cycle_count();
+
+        for _ in 0..100 {
+            cpu.step();
+        }
+        assert!(config.load_state(&mut cpu).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(cpu.get_cycle_count(), saved_cycles);
+
+        // nothing to load
+        assert!(!config.load_state(&mut cpu).unwrap());
+        assert!(!EmulatorConfig::default().load_state(&mut cpu).unwrap());
+    }
+
+    #[test]
+    fn test_emulator_config_rejects_bad_mapper_state() {
+        let path = std::env::temp_dir().join("rustnes_test_bad_mapper_state.sav");
+        let config = EmulatorConfig {
+            save_state_path: Some(path.clone()),
+        };
+        let mut cpu = test_cpu();
+        let mut state = cpu.save_state();
+        state.bus.mapper = vec![0xff; 3];
+        state.cpu.register_a = 0x42;
+        state.write_to_file(&path).unwrap();
+
+        let err = config.load_state(&mut cpu).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(cpu.register_a, 0);
+    }
+}
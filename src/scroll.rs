@@ -1,7 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ScrollRegister {
     pub scroll_x: u8,
     pub scroll_y: u8,
-    pub latch: bool,
+}
+
+impl Default for ScrollRegister {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ScrollRegister {
@@ -9,20 +17,40 @@ impl ScrollRegister {
         ScrollRegister {
             scroll_x: 0,
             scroll_y: 0,
-            latch: false,
         }
     }
 
-    pub fn write(&mut self, data: u8) {
-        if !self.latch {
-            self.scroll_x = data
-        } else {
-            self.scroll_y = data
-        }
-        self.latch = !self.latch;
+    // $2005 writes alternate X and Y through the w toggle NesPPU shares with $2006
+    pub fn write_x(&mut self, data: u8) {
+        self.scroll_x = data;
+    }
+
+    pub fn write_y(&mut self, data: u8) {
+        self.scroll_y = data;
     }
 
-    pub fn reset_latch(&mut self) {
-        self.latch = false;
+    pub fn x(&self) -> u8 {
+        self.scroll_x
+    }
+
+    pub fn y(&self) -> u8 {
+        self.scroll_y
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_x_then_y() {
+        let mut scroll = ScrollRegister::new();
+        scroll.write_x(0x12);
+        assert_eq!(scroll.x(), 0x12);
+        assert_eq!(scroll.y(), 0x00);
+
+        scroll.write_y(0x34);
+        assert_eq!(scroll.x(), 0x12);
+        assert_eq!(scroll.y(), 0x34);
     }
 }
@@ -1,6 +1,8 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 bitflags! {
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct StatusRegister:u8{
         const SPRITE_OVERFLOW_FLAG = 0b0010_0000;
         const SPRITE_ZERO_HIT = 0b0100_0000;
@@ -8,6 +10,12 @@ bitflags! {
     }
 }
 
+impl Default for StatusRegister {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StatusRegister {
     pub fn new() -> Self {
         StatusRegister::from_bits_truncate(0)
@@ -21,6 +29,18 @@ impl StatusRegister {
         self.set(StatusRegister::VBLANK_STARTED, false);
     }
 
+    pub fn set_sprite_overflow(&mut self, status: bool) {
+        self.set(StatusRegister::SPRITE_OVERFLOW_FLAG, status);
+    }
+
+    pub fn set_sprite_zero_hit(&mut self, status: bool) {
+        self.set(StatusRegister::SPRITE_ZERO_HIT, status);
+    }
+
+    pub fn is_sprite_zero_hit(&self) -> bool {
+        self.contains(StatusRegister::SPRITE_ZERO_HIT)
+    }
+
     pub fn is_in_vbrank(&self) -> bool {
         self.contains(StatusRegister::VBLANK_STARTED)
     }
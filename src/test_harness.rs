@@ -0,0 +1,184 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::bus::Bus;
+use crate::cartoridge::Rom;
+use crate::cpu::{CpuSnapshot, Memory, CPU};
+use crate::joypad::Joypad;
+use crate::ppu::NesPPU;
+
+// blargg's test ROMs report through $6000 once these bytes are at $6001-$6003
+const BLARGG_STATUS: u16 = 0x6000;
+const BLARGG_SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+const BLARGG_RUNNING: u8 = 0x80;
+const BLARGG_PASSED: u8 = 0x00;
+
+#[derive(Debug)]
+pub struct TestResult {
+    pub success: bool,
+    pub ram_value: u8,
+    pub frames_elapsed: u64,
+    pub final_cpu: CpuSnapshot,
+}
+
+// Runs a test ROM headless until it reports its result in memory.
+pub struct TestHarness {
+    cpu: CPU<Bus<'static>>,
+    frames: Rc<Cell<u64>>,
+}
+
+impl TestHarness {
+    pub fn new(rom: Rom) -> TestHarness {
+        let frames = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&frames);
+        let bus = Bus::new(rom, move |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {
+            counter.set(counter.get() + 1);
+        });
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        TestHarness { cpu, frames }
+    }
+
+    // nestest and some other ROMs need the CPU set up before running
+    pub fn cpu_mut(&mut self) -> &mut CPU<Bus<'static>> {
+        &mut self.cpu
+    }
+
+    // Memory is checked at the end of each frame, so a value the ROM writes
+    // and overwrites within one frame is not seen. PRG RAM starts zeroed, so waiting
+    // for 0 there succeeds before the ROM has run, see run_blargg_test for that.
+    pub fn run_until_ram_equals(&mut self, addr: u16, value: u8, max_frames: u64) -> TestResult {
+        self.run_until(max_frames, |cpu| cpu.mem_peek(addr) == value);
+
+        let ram_value = self.cpu.mem_peek(addr);
+        TestResult {
+            success: ram_value == value,
+            ram_value,
+            frames_elapsed: self.frames.get(),
+            final_cpu: self.cpu.snapshot(),
+        }
+    }
+
+    // The status at $6000 only counts once the ROM has written its signature
+    // and is no longer running, 0 means passed.
+    pub fn run_blargg_test(&mut self, max_frames: u64) -> TestResult {
+        let finished = |cpu: &CPU<Bus<'static>>| {
+            let signature = [0x6001, 0x6002, 0x6003].map(|addr| cpu.mem_peek(addr));
+            signature == BLARGG_SIGNATURE && cpu.mem_peek(BLARGG_STATUS) != BLARGG_RUNNING
+        };
+        self.run_until(max_frames, finished);
+
+        let ram_value = self.cpu.mem_peek(BLARGG_STATUS);
+        TestResult {
+            success: finished(&self.cpu) && ram_value == BLARGG_PASSED,
+            ram_value,
+            frames_elapsed: self.frames.get(),
+            final_cpu: self.cpu.snapshot(),
+        }
+    }
+
+    fn run_until<F>(&mut self, max_frames: u64, done: F)
+    where
+        F: Fn(&CPU<Bus<'static>>) -> bool,
+    {
+        let mut checked_frames = self.frames.get();
+        while self.frames.get() < max_frames {
+            self.cpu.step();
+            if self.frames.get() != checked_frames {
+                checked_frames = self.frames.get();
+                if done(&self.cpu) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // NROM-256 with program at $8000 and the reset vector pointing to it
+    fn test_rom(program: &[u8]) -> Rom {
+        let mut rom_data = vec![0x4e, 0x45, 0x53, 0x1a, 0x02, 0x01, 0x00, 0x00];
+        rom_data.resize(16, 0);
+        let mut prg_rom = vec![0; 2 * 16 * 1024];
+        prg_rom[..program.len()].copy_from_slice(program);
+        prg_rom[0x7ffc] = 0x00;
+        prg_rom[0x7ffd] = 0x80;
+        rom_data.extend_from_slice(&prg_rom);
+        rom_data.resize(rom_data.len() + 8 * 1024, 0);
+        Rom::from_bytes(&rom_data).unwrap()
+    }
+
+    #[test]
+    fn test_run_until_ram_equals_success() {
+        // LDA #$42; STA $6000; JMP $8005
+        let rom = test_rom(&[0xa9, 0x42, 0x8d, 0x00, 0x60, 0x4c, 0x05, 0x80]);
+        let result = TestHarness::new(rom).run_until_ram_equals(0x6000, 0x42, 10);
+
+        assert!(result.success);
+        assert_eq!(result.ram_value, 0x42);
+        assert_eq!(result.frames_elapsed, 1);
+        assert_eq!(result.final_cpu.register_a, 0x42);
+    }
+
+    // LDA #$80; STA $6000; signature to $6001-$6003; delay loop;
+    // LDA #result; STA $6000; JMP to itself
+    fn blargg_rom(delay: u8, result: u8) -> Rom {
+        #[rustfmt::skip]
+        let program = [
+            0xa9, 0x80, 0x8d, 0x00, 0x60,
+            0xa9, 0xde, 0x8d, 0x01, 0x60,
+            0xa9, 0xb0, 0x8d, 0x02, 0x60,
+            0xa9, 0x61, 0x8d, 0x03, 0x60,
+            // delay times 256 DEY, BNE, about 1280 cycles each
+            0xa2, delay, 0xa0, 0x00, 0x88, 0xd0, 0xfd, 0xca, 0xd0, 0xf8,
+            0xa9, result, 0x8d, 0x00, 0x60,
+            0x4c, 0x23, 0x80,
+        ];
+        test_rom(&program)
+    }
+
+    #[test]
+    fn test_run_blargg_test_waits_for_result() {
+        let result = TestHarness::new(blargg_rom(160, 0x00)).run_blargg_test(20);
+
+        assert!(result.success, "{:?}", result);
+        assert!(result.frames_elapsed > 1);
+        assert_eq!(result.final_cpu.program_counter & 0xfff0, 0x8020);
+    }
+
+    #[test]
+    fn test_run_blargg_test_failure_code() {
+        let result = TestHarness::new(blargg_rom(80, 0x03)).run_blargg_test(20);
+
+        assert!(!result.success);
+        assert_eq!(result.ram_value, 0x03);
+        assert!(result.frames_elapsed < 20);
+    }
+
+    #[test]
+    fn test_run_blargg_test_without_signature() {
+        // PRG RAM reads 0 from power up but nothing was reported
+        let rom = test_rom(&[0x4c, 0x00, 0x80]);
+        let result = TestHarness::new(rom).run_blargg_test(3);
+
+        assert!(!result.success);
+        assert_eq!(result.ram_value, 0x00);
+        assert_eq!(result.frames_elapsed, 3);
+    }
+
+    #[test]
+    fn test_run_until_ram_equals_timeout() {
+        // LDA #$80; STA $6000; JMP $8005
+        let rom = test_rom(&[0xa9, 0x80, 0x8d, 0x00, 0x60, 0x4c, 0x05, 0x80]);
+        let result = TestHarness::new(rom).run_until_ram_equals(0x6000, 0x00, 3);
+
+        assert!(!result.success);
+        assert_eq!(result.ram_value, 0x80);
+        assert_eq!(result.frames_elapsed, 3);
+        assert_eq!(result.final_cpu.register_a, 0x80);
+        assert!((0x8005..=0x8007).contains(&result.final_cpu.program_counter));
+    }
+}
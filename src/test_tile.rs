@@ -48,7 +48,7 @@ fn main() {
         .unwrap();
 
     let bytes = std::fs::read("./test/sample/sample1.nes").unwrap();
-    let rom = Rom::new(&bytes).unwrap();
+    let rom = Rom::from_bytes(&bytes).unwrap();
 
     let tile_frame = show_tile_brank(&rom.chr_rom, 1);
     texture
@@ -1,118 +1,103 @@
-use std::collections::HashMap;
-
 use crate::cpu::AddressingMode;
-use crate::cpu::{Memory, CPU};
+use crate::cpu::{CpuBus, Memory, CPU};
 use crate::opcode;
 
-pub fn trace(cpu: &mut CPU) -> String {
-    let ref opcodes: HashMap<u8, &'static opcode::OpCode> = *opcode::OPECODE_MAP;
-
-    let code = cpu.mem_read(cpu.program_counter);
-    let ops = opcodes.get(&code).unwrap();
-
-    let begin = cpu.program_counter;
-    let mut hex_dump = vec![];
-    hex_dump.push(code);
-
-    let (mem_addr, stored_value) = match ops.mode {
-        AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
-        _ => {
-            let (addr, _) = cpu.get_absolute_address(&ops.mode, begin + 1);
-            (addr, cpu.mem_read(addr))
-        }
-    };
-
-    let tmp = match ops.len {
-        1 => match ops.code {
-            0x0a | 0x4a | 0x2a | 0x6a => format!("A "),
-            _ => String::from(""),
-        },
-        2 => {
-            let address: u8 = cpu.mem_read(begin + 1);
-            // let value = cpu.mem_read(address));
-            hex_dump.push(address);
-
-            match ops.mode {
-                AddressingMode::Immediate => format!("#${:02x}", address),
-                AddressingMode::ZeroPage => format!("${:02x} = {:02x}", mem_addr, stored_value),
-                AddressingMode::ZeroPage_X => format!(
-                    "${:02x},X @ {:02x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::ZeroPage_Y => format!(
-                    "${:02x},Y @ {:02x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::Indirect_X => format!(
-                    "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
-                    address,
-                    (address.wrapping_add(cpu.register_x)),
-                    mem_addr,
-                    stored_value
-                ),
-                AddressingMode::Indirect_Y => format!(
-                    "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
-                    address,
-                    (mem_addr.wrapping_sub(cpu.register_y as u16)),
-                    mem_addr,
-                    stored_value
-                ),
-                AddressingMode::NoneAddressing => {
-                    // assuming local jumps: BNE, BVS, etc....
-                    let address: usize =
-                        (begin as usize + 2).wrapping_add((address as i8) as usize);
-                    format!("${:04x}", address)
-                }
-
-                _ => panic!(
-                    "unexpected addressing mode {:?} has ops-len 2. code {:02x}",
-                    ops.mode, ops.code
-                ),
+impl AddressingMode {
+    // nestest style operand of the instruction at pc, e.g. "($40,X) @ 50 = 0310 = 00"
+    pub fn format_operand<B: CpuBus>(&self, cpu: &CPU<B>, pc: u16) -> String {
+        let (mem_addr, stored_value) = match self {
+            AddressingMode::Immediate
+            | AddressingMode::Accumulator
+            | AddressingMode::NoneAddressing => (0, 0),
+            _ => {
+                let (addr, _) = cpu.get_absolute_address(self, pc + 1);
+                (addr, cpu.mem_peek(addr))
             }
-        }
-        3 => {
-            let address_lo = cpu.mem_read(begin + 1);
-            let address_hi = cpu.mem_read(begin + 2);
-            hex_dump.push(address_lo);
-            hex_dump.push(address_hi);
-
-            let address = cpu.mem_read_u16(begin + 1);
+        };
+        let address = cpu.mem_peek(pc + 1);
+        let address_u16 = cpu.mem_peek_u16(pc + 1);
 
-            match ops.mode {
-                AddressingMode::NoneAddressing => {
-                    if ops.code == 0x6c {
+        match self {
+            AddressingMode::Accumulator => String::from("A"),
+            AddressingMode::Immediate => format!("#${:02x}", address),
+            AddressingMode::ZeroPage => format!("${:02x} = {:02x}", mem_addr, stored_value),
+            AddressingMode::ZeroPage_X => format!(
+                "${:02x},X @ {:02x} = {:02x}",
+                address, mem_addr, stored_value
+            ),
+            AddressingMode::ZeroPage_Y => format!(
+                "${:02x},Y @ {:02x} = {:02x}",
+                address, mem_addr, stored_value
+            ),
+            AddressingMode::Indirect_X => format!(
+                "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
+                address,
+                (address.wrapping_add(cpu.register_x)),
+                mem_addr,
+                stored_value
+            ),
+            AddressingMode::Indirect_Y => format!(
+                "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
+                address,
+                (mem_addr.wrapping_sub(cpu.register_y as u16)),
+                mem_addr,
+                stored_value
+            ),
+            AddressingMode::Absolute => format!("${:04x} = {:02x}", mem_addr, stored_value),
+            AddressingMode::Absolute_X => format!(
+                "${:04x},X @ {:04x} = {:02x}",
+                address_u16, mem_addr, stored_value
+            ),
+            AddressingMode::Absolute_Y => format!(
+                "${:04x},Y @ {:04x} = {:02x}",
+                address_u16, mem_addr, stored_value
+            ),
+            AddressingMode::NoneAddressing => {
+                let code = cpu.mem_peek(pc);
+                let ops = opcode::OPCODE_TABLE[code as usize];
+                let len = ops.map_or(1, |ops| ops.len);
+                match (len, code) {
+                    _ if ops.is_some_and(|ops| ops.is_branch()) => {
+                        // local jumps: BNE, BVS, etc....
+                        let address: usize =
+                            (pc as usize + 2).wrapping_add((address as i8) as usize);
+                        format!("${:04x}", address)
+                    }
+                    (3, 0x6c) => {
                         //jmp indirect
-                        let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
+                        let jmp_addr = if address_u16 & 0x00FF == 0x00FF {
+                            let lo = cpu.mem_peek(address_u16);
+                            let hi = cpu.mem_peek(address_u16 & 0xFF00);
                             (hi as u16) << 8 | (lo as u16)
                         } else {
-                            cpu.mem_read_u16(address)
+                            cpu.mem_peek_u16(address_u16)
                         };
-
-                        // let jmp_addr = cpu.mem_read_u16(address);
-                        format!("(${:04x}) = {:04x}", address, jmp_addr)
-                    } else {
-                        format!("${:04x}", address)
+                        format!("(${:04x}) = {:04x}", address_u16, jmp_addr)
                     }
+                    (3, _) => format!("${:04x}", address_u16),
+                    _ => String::from(""),
                 }
-                AddressingMode::Absolute => format!("${:04x} = {:02x}", mem_addr, stored_value),
-                AddressingMode::Absolute_X => format!(
-                    "${:04x},X @ {:04x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                AddressingMode::Absolute_Y => format!(
-                    "${:04x},Y @ {:04x} = {:02x}",
-                    address, mem_addr, stored_value
-                ),
-                _ => panic!(
-                    "unexpected addressing mode {:?} has ops-len 3. code {:02x}",
-                    ops.mode, ops.code
-                ),
             }
         }
-        _ => String::from(""),
-    };
+    }
+}
+
+// One nestest.log line, e.g.
+// "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7"
+pub fn trace<B: CpuBus>(
+    cpu: &CPU<B>,
+    cpu_cycles: u64,
+    ppu_scanline: u16,
+    ppu_cycle: usize,
+) -> String {
+    let code = cpu.mem_peek(cpu.program_counter);
+    let ops = opcode::OPCODE_TABLE[code as usize].unwrap();
+
+    let begin = cpu.program_counter;
+    let hex_dump: Vec<u8> = (0..ops.len as u16)
+        .map(|i| cpu.mem_peek(begin + i))
+        .collect();
+    let tmp = ops.mode.format_operand(cpu, begin);
 
     let hex_str = hex_dump
         .iter()
@@ -124,8 +109,272 @@ pub fn trace(cpu: &mut CPU) -> String {
         .to_string();
 
     format!(
-        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
-        asm_str, cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer,
+        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x} PPU:{:>3},{:>3} CYC:{}",
+        asm_str,
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status,
+        cpu.stack_pointer,
+        ppu_scanline,
+        ppu_cycle,
+        cpu_cycles,
     )
     .to_ascii_uppercase()
 }
+
+// operand of an instruction without the CPU state, e.g. "($40,X)"
+fn format_static_operand(ops: &opcode::OpCode, addr: u16, operand: &[u8]) -> String {
+    let address = operand.first().copied().unwrap_or(0);
+    let address_u16 = match operand {
+        [lo, hi] => (*hi as u16) << 8 | *lo as u16,
+        _ => address as u16,
+    };
+    match ops.mode {
+        AddressingMode::Accumulator => String::from("A"),
+        AddressingMode::Immediate => format!("#${:02x}", address),
+        AddressingMode::ZeroPage => format!("${:02x}", address),
+        AddressingMode::ZeroPage_X => format!("${:02x},X", address),
+        AddressingMode::ZeroPage_Y => format!("${:02x},Y", address),
+        AddressingMode::Indirect_X => format!("(${:02x},X)", address),
+        AddressingMode::Indirect_Y => format!("(${:02x}),Y", address),
+        AddressingMode::Absolute => format!("${:04x}", address_u16),
+        AddressingMode::Absolute_X => format!("${:04x},X", address_u16),
+        AddressingMode::Absolute_Y => format!("${:04x},Y", address_u16),
+        AddressingMode::NoneAddressing => match (ops.len, ops.code) {
+            (2, _) => format!(
+                "${:04x}",
+                addr.wrapping_add(2).wrapping_add((address as i8) as u16)
+            ),
+            (3, 0x6c) => format!("(${:04x})", address_u16),
+            (3, _) => format!("${:04x}", address_u16),
+            _ => String::from(""),
+        },
+    }
+}
+
+// Disassembles raw bytes in the trace layout, e.g. "C000  4C F5 C5  JMP $C5F5".
+// Unknown opcodes and instructions cut off by the end of the slice become ".DB" lines.
+pub fn disassemble(bytes: &[u8], start_addr: u16) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let addr = start_addr.wrapping_add(pos as u16);
+        let code = bytes[pos];
+        let line = match opcode::OPCODE_TABLE[code as usize] {
+            Some(ops) if pos + ops.len as usize <= bytes.len() => {
+                let len = ops.len as usize;
+                let hex_str = bytes[pos..pos + len]
+                    .iter()
+                    .map(|z| format!("{:02x}", z))
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                let operand = format_static_operand(ops, addr, &bytes[pos + 1..pos + len]);
+                pos += len;
+                format!(
+                    "{:04x}  {:8} {: >4} {}",
+                    addr, hex_str, ops.mnemonic, operand
+                )
+            }
+            _ => {
+                pos += 1;
+                format!(
+                    "{:04x}  {:8}  .DB ${:02x}",
+                    addr,
+                    format!("{:02x}", code),
+                    code
+                )
+            }
+        };
+        lines.push(line.trim().to_ascii_uppercase());
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock_bus::MockBus;
+
+    fn setup_cpu(program: &[u8]) -> CPU<MockBus> {
+        let mut ram = [0; 65536];
+        ram[0x0064..0x0064 + program.len()].copy_from_slice(program);
+        let mut cpu = CPU::new_with_mock(ram);
+        cpu.program_counter = 0x64;
+        cpu
+    }
+
+    fn format_operand(cpu: &CPU<MockBus>, mode: AddressingMode) -> String {
+        mode.format_operand(cpu, cpu.program_counter)
+    }
+
+    #[test]
+    fn test_format_immediate_and_accumulator() {
+        let cpu = setup_cpu(&[0xa9, 0x40]);
+        assert_eq!(format_operand(&cpu, AddressingMode::Immediate), "#$40");
+        assert_eq!(format_operand(&cpu, AddressingMode::Accumulator), "A");
+    }
+
+    #[test]
+    fn test_format_zero_page() {
+        let mut cpu = setup_cpu(&[0xa5, 0x40]);
+        cpu.register_x = 0x10;
+        cpu.register_y = 0x20;
+        cpu.mem_write(0x40, 0x11);
+        cpu.mem_write(0x50, 0x22);
+        cpu.mem_write(0x60, 0x33);
+
+        assert_eq!(format_operand(&cpu, AddressingMode::ZeroPage), "$40 = 11");
+        assert_eq!(
+            format_operand(&cpu, AddressingMode::ZeroPage_X),
+            "$40,X @ 50 = 22"
+        );
+        assert_eq!(
+            format_operand(&cpu, AddressingMode::ZeroPage_Y),
+            "$40,Y @ 60 = 33"
+        );
+    }
+
+    #[test]
+    fn test_format_absolute() {
+        let mut cpu = setup_cpu(&[0xad, 0x00, 0x03]);
+        cpu.register_x = 0x10;
+        cpu.register_y = 0xff;
+        cpu.mem_write(0x0300, 0x11);
+        cpu.mem_write(0x0310, 0x22);
+        cpu.mem_write(0x03ff, 0x33);
+
+        assert_eq!(format_operand(&cpu, AddressingMode::Absolute), "$0300 = 11");
+        assert_eq!(
+            format_operand(&cpu, AddressingMode::Absolute_X),
+            "$0300,X @ 0310 = 22"
+        );
+        assert_eq!(
+            format_operand(&cpu, AddressingMode::Absolute_Y),
+            "$0300,Y @ 03ff = 33"
+        );
+    }
+
+    #[test]
+    fn test_format_indirect() {
+        let mut cpu = setup_cpu(&[0xa1, 0x40]);
+        cpu.register_x = 0x10;
+        cpu.register_y = 0x05;
+        cpu.mem_write_u16(0x50, 0x0310);
+        cpu.mem_write_u16(0x40, 0x0400);
+        cpu.mem_write(0x0310, 0x11);
+        cpu.mem_write(0x0405, 0x22);
+
+        assert_eq!(
+            format_operand(&cpu, AddressingMode::Indirect_X),
+            "($40,X) @ 50 = 0310 = 11"
+        );
+        assert_eq!(
+            format_operand(&cpu, AddressingMode::Indirect_Y),
+            "($40),Y = 0400 @ 0405 = 22"
+        );
+    }
+
+    #[test]
+    fn test_format_none_addressing() {
+        // BNE -2
+        let cpu = setup_cpu(&[0xd0, 0xfe]);
+        assert_eq!(
+            format_operand(&cpu, AddressingMode::NoneAddressing),
+            "$0064"
+        );
+
+        let cpu = setup_cpu(&[0x4c, 0x34, 0x12]);
+        assert_eq!(
+            format_operand(&cpu, AddressingMode::NoneAddressing),
+            "$1234"
+        );
+
+        // JMP ($02FF) wraps inside the page
+        let mut cpu = setup_cpu(&[0x6c, 0xff, 0x02]);
+        cpu.mem_write(0x02ff, 0x34);
+        cpu.mem_write(0x0200, 0x12);
+        assert_eq!(
+            format_operand(&cpu, AddressingMode::NoneAddressing),
+            "($02ff) = 1234"
+        );
+
+        let cpu = setup_cpu(&[0xe8]);
+        assert_eq!(format_operand(&cpu, AddressingMode::NoneAddressing), "");
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut cpu = setup_cpu(&[0xa2, 0x01, 0xca, 0x88, 0x00]);
+        cpu.register_a = 1;
+        cpu.register_x = 2;
+        cpu.register_y = 3;
+
+        assert_eq!(
+            trace(&cpu, 7, 0, 21),
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD PPU:  0, 21 CYC:7"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_nestest() {
+        // first lines of nestest.log, with the CPU state stripped
+        let prg = [
+            0x4c, 0xf5, 0xc5, 0x60, 0x78, 0xd8, 0xa2, 0xff, 0x9a, 0xad, 0x02, 0x20, 0x10, 0xfb,
+        ];
+        assert_eq!(
+            disassemble(&prg, 0xc000),
+            vec![
+                "C000  4C F5 C5  JMP $C5F5",
+                "C003  60        RTS",
+                "C004  78        SEI",
+                "C005  D8        CLD",
+                "C006  A2 FF     LDX #$FF",
+                "C008  9A        TXS",
+                "C009  AD 02 20  LDA $2002",
+                "C00C  10 FB     BPL $C009",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_addressing_modes() {
+        let program = [
+            0xb5, 0x40, // LDA $40,X
+            0xb6, 0x40, // LDX $40,Y
+            0xbd, 0x00, 0x03, // LDA $0300,X
+            0xb9, 0x00, 0x03, // LDA $0300,Y
+            0xa1, 0x40, // LDA ($40,X)
+            0xb1, 0x40, // LDA ($40),Y
+            0x0a, // ASL A
+            0x6c, 0xff, 0x02, // JMP ($02FF)
+        ];
+        let lines = disassemble(&program, 0x0600);
+        let operands: Vec<&str> = lines.iter().map(|line| &line[16..]).collect();
+        assert_eq!(
+            operands,
+            vec![
+                "LDA $40,X",
+                "LDX $40,Y",
+                "LDA $0300,X",
+                "LDA $0300,Y",
+                "LDA ($40,X)",
+                "LDA ($40),Y",
+                "ASL A",
+                "JMP ($02FF)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_truncated() {
+        assert_eq!(
+            disassemble(&[0xea, 0xad, 0x02], 0x8000),
+            vec![
+                "8000  EA        NOP",
+                "8001  AD        .DB $AD",
+                "8002  02        .DB $02"
+            ]
+        );
+    }
+}
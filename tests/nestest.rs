@@ -0,0 +1,76 @@
+use std::fs;
+
+use rustnes::bus::Bus;
+use rustnes::cartoridge::Rom;
+use rustnes::cpu::{CpuBus, Memory, CPU};
+use rustnes::joypad::Joypad;
+use rustnes::ppu::NesPPU;
+use rustnes::trace::trace;
+
+const ROM_PATH: &str = "test/sample/nestest.nes";
+// https://www.qmtpro.com/~nes/misc/nestest.log, fetched by `make nestest`
+const LOG_PATH: &str = "test/sample/nestest.log";
+// automated mode starts at $C000 and needs no PPU, the last logged instruction is the RTS at $C66E
+const START_PC: u16 = 0xc000;
+const END_PC: u16 = 0xc66e;
+// nestest.log counts the 7 cycles of the reset sequence, which reset() does not spend
+const RESET_CYCLES: usize = 7;
+const CONTEXT_LINES: usize = 3;
+
+#[test]
+#[cfg_attr(
+    not(feature = "nestest"),
+    ignore = "needs test/sample/nestest.log, run `make nestest`"
+)]
+fn test_nestest_trace() {
+    let rom = Rom::from_file(ROM_PATH).unwrap();
+    let golden = fs::read_to_string(LOG_PATH).unwrap();
+    let golden: Vec<&str> = golden.lines().collect();
+
+    let mut cpu = CPU::new(Bus::new(
+        rom,
+        |_: &NesPPU, _: &mut Joypad, _: &mut Joypad| {},
+    ));
+    cpu.reset();
+    cpu.bus.tick(RESET_CYCLES);
+    cpu.program_counter = START_PC;
+    cpu.halt_on_brk = true;
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut finished = false;
+    cpu.run_with_callback(|cpu| {
+        if finished {
+            // plant a BRK where the final RTS returned to, so halt_on_brk stops the run
+            cpu.mem_write(cpu.program_counter, 0x00);
+            return;
+        }
+        let (scanline, dot) = cpu.bus.ppu_position();
+        let cycles = cpu.get_cycle_count() + RESET_CYCLES as u64;
+        lines.push(trace(cpu, cycles, scanline, dot));
+        finished = cpu.program_counter == END_PC;
+    });
+
+    if let Some(i) = lines
+        .iter()
+        .zip(&golden)
+        .position(|(line, gold)| line != gold)
+    {
+        let start = i.saturating_sub(CONTEXT_LINES);
+        for line in &lines[start..i] {
+            eprintln!("  {}", line);
+        }
+        eprintln!("- {}", golden[i]);
+        eprintln!("+ {}", lines[i]);
+        for line in lines.iter().skip(i + 1).take(CONTEXT_LINES) {
+            eprintln!("  {}", line);
+        }
+        panic!("trace diverges from nestest.log at line {}", i + 1);
+    }
+    assert_eq!(
+        lines.len(),
+        golden.len(),
+        "trace stopped after {} of {} nestest.log lines",
+        lines.len(),
+        golden.len()
+    );
+}
@@ -0,0 +1,17 @@
+use rustnes::cartoridge::Rom;
+use rustnes::test_harness::TestHarness;
+
+// blargg's test ROMs write 0x80 to $6000 while running, then the result code (0 = passed)
+const MAX_FRAMES: u64 = 60 * 60;
+
+// nestest has no completion signal in memory: it leaves its error codes in $02/$03
+// and returns into RAM when done, so it is checked by the trace test in nestest.rs.
+
+#[test]
+#[ignore = "needs test/sample/cpu_dummy_reads.nes, run `make blargg`"]
+fn test_cpu_dummy_reads() {
+    let rom = Rom::from_file("test/sample/cpu_dummy_reads.nes").unwrap();
+    let result = TestHarness::new(rom).run_blargg_test(MAX_FRAMES);
+
+    assert!(result.success, "{:?}", result);
+}